@@ -0,0 +1,14 @@
+extern crate bytes;
+extern crate libdingy;
+extern crate rand;
+extern crate rcgen;
+extern crate rustls;
+extern crate serde;
+extern crate serde_yaml;
+extern crate sha2;
+
+pub mod relay;
+pub mod scenario;
+pub mod sim;
+pub mod tls;
+pub mod weechat;
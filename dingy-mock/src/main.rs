@@ -0,0 +1,105 @@
+extern crate dingy_mock;
+
+use dingy_mock::relay::MockRelay;
+use dingy_mock::scenario::Scenario;
+use dingy_mock::sim::SimRelay;
+use dingy_mock::tls::TlsFront;
+use dingy_mock::weechat::Core;
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::thread;
+
+fn main() {
+    let mut args: Vec<String> = env::args().collect();
+    let tls = args.get(1).is_some_and(|arg| arg == "--tls");
+    if tls {
+        args.remove(1);
+    }
+    if args.len() < 2 {
+        println!("Usage: {} [--tls] <scenario.yaml> [addr:port]", args[0]);
+        println!("       {} [--tls] --sim [addr:port] [password]", args[0]);
+        return;
+    }
+    let addr = args.get(2).map_or("127.0.0.1:9001", |a| a.as_str());
+
+    // With TLS the relay itself only listens locally, behind the TLS front
+    let relay_addr = if tls { "127.0.0.1:0" } else { addr };
+    let relay = if args[1] == "--sim" {
+        start_sim(relay_addr, args.get(3).cloned())
+    } else {
+        start_scenario(&args[1], relay_addr)
+    };
+    let relay = match (relay, tls) {
+        (Some(relay), true) => start_tls(addr, relay),
+        (relay, _) => relay,
+    };
+    match relay {
+        Some(relay_addr) => println!("Listening on {}", relay_addr),
+        None => return,
+    }
+
+    loop {
+        thread::park();
+    }
+}
+
+fn start_tls(addr: &str, backend: SocketAddr) -> Option<SocketAddr> {
+    let front = match TlsFront::start(addr, backend) {
+        Ok(front) => front,
+        Err(e) => {
+            println!("Could not listen with TLS on {}: {}", addr, e);
+            return None;
+        }
+    };
+
+    println!("Certificate SHA-256 fingerprint: {}", front.fingerprint());
+    let path = env::temp_dir().join("dingy-mock-cert.pem");
+    match fs::write(&path, front.cert_pem()) {
+        Ok(()) => println!("Certificate written to {}", path.display()),
+        Err(e) => println!("Could not write certificate to {}: {}", path.display(), e),
+    }
+    Some(front.addr())
+}
+
+fn start_scenario(path: &str, addr: &str) -> Option<SocketAddr> {
+    let scenario = match Scenario::load(path) {
+        Ok(scenario) => scenario,
+        Err(e) => {
+            println!("Could not load scenario {}: {}", path, e);
+            return None;
+        }
+    };
+    match MockRelay::start(addr, scenario) {
+        Ok(relay) => Some(relay.addr()),
+        Err(e) => {
+            println!("Could not listen on {}: {}", addr, e);
+            None
+        }
+    }
+}
+
+fn start_sim(addr: &str, password: Option<String>) -> Option<SocketAddr> {
+    let mut core = Core::new();
+    core.add_buffer("irc.server.libera", "libera", "IRC: irc.libera.chat/6697");
+    core.add_buffer("irc.libera.#weechat", "#weechat", "WeeChat support channel");
+    core.set_local_variable("irc.libera.#weechat", "nick", "dingy");
+    core.add_nick_group("irc.libera.#weechat", "000|o");
+    core.add_nick_group("irc.libera.#weechat", "999|...");
+    core.add_nick("irc.libera.#weechat", Some("000|o"), "FlashCode", "@");
+    core.add_nick("irc.libera.#weechat", Some("999|..."), "dingy", " ");
+    core.print(
+        "irc.libera.#weechat",
+        "-->",
+        "dingy has joined #weechat",
+        &["irc_join", "no_highlight"],
+    );
+
+    match SimRelay::start(addr, core, password) {
+        Ok(relay) => Some(relay.addr()),
+        Err(e) => {
+            println!("Could not listen on {}: {}", addr, e);
+            None
+        }
+    }
+}
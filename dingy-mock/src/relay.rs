@@ -0,0 +1,204 @@
+use crate::scenario::{Scenario, Step};
+use libdingy::auth::Totp;
+use libdingy::command::{
+    parse_command, Command, CompressionType, ParsedCommand, PingCommand,
+};
+use libdingy::message::{Message, WeechatString, WeechatType};
+use std::io::{BufRead, BufReader, Error, ErrorKind};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// Relay stand-in that plays a scenario to every client that connects
+pub struct MockRelay {
+    addr: SocketAddr,
+}
+
+impl MockRelay {
+    // Bind to addr (use port 0 for any free port) and accept clients on a
+    // background thread, one thread per client
+    pub fn start<A: ToSocketAddrs>(
+        addr: A,
+        scenario: Scenario,
+    ) -> Result<MockRelay, Error> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let scenario = Arc::new(scenario);
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let scenario = scenario.clone();
+                        thread::spawn(move || {
+                            let result = Session::new(stream, scenario)
+                                .and_then(|mut session| session.run());
+                            if let Err(e) = result {
+                                println!("Session error: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => println!("Accept error: {:?}", e),
+                }
+            }
+        });
+
+        Ok(MockRelay { addr })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+struct Session {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    scenario: Arc<Scenario>,
+    compression: CompressionType,
+}
+
+impl Session {
+    fn new(stream: TcpStream, scenario: Arc<Scenario>) -> Result<Session, Error> {
+        let compression = scenario.compression_type()?;
+        Ok(Session {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            scenario,
+            compression,
+        })
+    }
+
+    fn run(&mut self) -> Result<(), Error> {
+        let scenario = self.scenario.clone();
+        for step in &scenario.steps {
+            match step {
+                Step::Push(push) => {
+                    thread::sleep(Duration::from_millis(push.delay_ms));
+                    self.send(&push.message.to_message("")?)?;
+                }
+                Step::Expect(expect) => loop {
+                    let (text, command) = match self.read_command()? {
+                        Some(command) => command,
+                        None => return Ok(()),
+                    };
+
+                    if let ParsedCommand::Init(_) = command {
+                        if !self.check_auth(&command)? {
+                            println!("Authentication failed, closing connection");
+                            return Ok(());
+                        }
+                    }
+
+                    if text.starts_with(&expect.command) {
+                        let id = command.get_id().unwrap_or_default();
+                        for reply in &expect.reply {
+                            self.send(&reply.to_message(&id)?)?;
+                        }
+                        break;
+                    }
+
+                    if let ParsedCommand::Quit(_) = command {
+                        return Ok(());
+                    }
+                    // Clients ping on their own, e.g. to check a login
+                    if let ParsedCommand::Ping(ping) = command {
+                        self.pong(ping)?;
+                        continue;
+                    }
+                    if scenario.strict {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            format!("Expected {} but got {}", expect.command, text),
+                        ));
+                    }
+                    println!("Ignoring unexpected command: {}", text);
+                },
+            }
+        }
+
+        // Script is done, swallow anything else until the client leaves
+        while let Some((text, command)) = self.read_command()? {
+            match command {
+                ParsedCommand::Quit(_) => break,
+                ParsedCommand::Ping(ping) => self.pong(ping)?,
+                _ => println!("Unscripted command: {}", text),
+            }
+        }
+        Ok(())
+    }
+
+    // Read the next command, along with its text without the id for matching.
+    // Returns None once the client disconnects.
+    fn read_command(&mut self) -> Result<Option<(String, ParsedCommand)>, Error> {
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match parse_command(&line) {
+                Ok(mut command) => {
+                    let id = command.get_id();
+                    command.set_id(None);
+                    let mut text = vec![];
+                    command.encode(&mut text)?;
+                    command.set_id(id);
+
+                    let text = String::from_utf8_lossy(&text).trim_end().to_owned();
+                    return Ok(Some((text, command)));
+                }
+                Err(e) if self.scenario.strict => return Err(e.into()),
+                Err(e) => println!("Ignoring bad command {:?}: {}", line, e),
+            }
+        }
+    }
+
+    fn check_auth(&self, command: &ParsedCommand) -> Result<bool, Error> {
+        let init = match command {
+            ParsedCommand::Init(init) => init,
+            _ => return Ok(true),
+        };
+
+        if let Some(password) = &self.scenario.password {
+            let plain_ok = init.password.as_ref() == Some(password);
+            let hash_ok = init
+                .password_hash
+                .as_ref()
+                .is_some_and(|hash| hash.verify(password));
+            if !plain_ok && !hash_ok {
+                return Ok(false);
+            }
+        }
+
+        if let Some(secret) = &self.scenario.totp_secret {
+            let totp = Totp::from_base32(secret).ok_or_else(|| {
+                Error::new(ErrorKind::InvalidData, "Invalid totp_secret in scenario")
+            })?;
+            // Allow one period of clock skew either way
+            if !init.totp.as_ref().is_some_and(|code| totp.verify(code, 1)) {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    // Pings aren't answered with their id, the relay always uses _pong
+    fn pong(&mut self, ping: PingCommand) -> Result<(), Error> {
+        let token = match ping.arguments {
+            Some(args) => WeechatString::from(args.join(" ")),
+            None => WeechatString::Null,
+        };
+        self.send(&Message::new("_pong".to_owned(), vec![WeechatType::String(token)]))
+    }
+
+    fn send(&mut self, message: &Message) -> Result<(), Error> {
+        message.encode(&mut self.writer, self.compression)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+use bytes::Bytes;
+use libdingy::command::CompressionType;
+use libdingy::message::{
+    Hdata, Message, Pointer, Timestamp, WeechatString, WeechatType,
+};
+use serde::Deserialize;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+// A scripted relay session. Steps run in order for every client that connects:
+// expected commands are answered with canned replies, pushes are sent unprompted.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Scenario {
+    // Checked against init's password or password_hash if set
+    #[serde(default)]
+    pub password: Option<String>,
+    // Base32 TOTP secret, checked against init's totp if set
+    #[serde(default)]
+    pub totp_secret: Option<String>,
+    // none, zlib or zstd
+    #[serde(default)]
+    pub compression: Option<String>,
+    // Close the connection on commands that don't match the next step
+    #[serde(default = "default_strict")]
+    pub strict: bool,
+    pub steps: Vec<Step>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum Step {
+    Expect(Expect),
+    Push(Push),
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Expect {
+    // Start of the expected command without its id, e.g. "hdata buffer:gui_buffers"
+    pub command: String,
+    #[serde(default)]
+    pub reply: Vec<MessageSpec>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Push {
+    #[serde(default)]
+    pub delay_ms: u64,
+    pub message: MessageSpec,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MessageSpec {
+    // Replies default to the id of the command they answer
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub data: Vec<Value>,
+}
+
+// Typed values, written as {type: value} using the relay's type names
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum Value {
+    Chr(i8),
+    Int(i32),
+    Lon(i64),
+    Str(Option<String>),
+    Buf(Option<Vec<u8>>),
+    Ptr(String),
+    Tim(u64),
+    Htb(Vec<(Value, Value)>),
+    Hda(HdataSpec),
+    Inf(Option<String>, Option<String>),
+    Inl(InfoListSpec),
+    Arr(Vec<Value>),
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HdataSpec {
+    // e.g. "buffer/lines/line/line_data"
+    pub path: String,
+    // e.g. "number:int,full_name:str"
+    pub keys: String,
+    #[serde(default)]
+    pub items: Vec<HdataItemSpec>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HdataItemSpec {
+    pub pointers: Vec<String>,
+    pub values: Vec<Value>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct InfoListSpec {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub items: Vec<Vec<(String, Value)>>,
+}
+
+fn default_strict() -> bool {
+    true
+}
+
+impl Scenario {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Scenario, Error> {
+        Scenario::from_yaml(&fs::read_to_string(path)?)
+    }
+
+    pub fn from_yaml(yaml: &str) -> Result<Scenario, Error> {
+        let scenario: Scenario = serde_yaml::from_str(yaml)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+        // Catch bad values on load rather than halfway through a session
+        scenario.compression_type()?;
+        for step in &scenario.steps {
+            match step {
+                Step::Expect(expect) => {
+                    for reply in &expect.reply {
+                        reply.to_message("")?;
+                    }
+                }
+                Step::Push(push) => {
+                    push.message.to_message("")?;
+                }
+            }
+        }
+        Ok(scenario)
+    }
+
+    pub fn compression_type(&self) -> Result<CompressionType, Error> {
+        match &self.compression {
+            Some(compression) => {
+                CompressionType::parse(compression).ok_or_else(|| {
+                    invalid(format!("Unknown compression {}", compression))
+                })
+            }
+            None => Ok(CompressionType::None),
+        }
+    }
+}
+
+impl MessageSpec {
+    pub fn to_message(&self, default_id: &str) -> Result<Message, Error> {
+        let id = self.id.clone().unwrap_or_else(|| default_id.to_owned());
+        let data =
+            self.data.iter().map(Value::to_weechat).collect::<Result<_, _>>()?;
+        Ok(Message::new(id, data))
+    }
+}
+
+impl Value {
+    pub fn to_weechat(&self) -> Result<WeechatType, Error> {
+        Ok(match self {
+            Value::Chr(c) => WeechatType::Char(*c),
+            Value::Int(i) => WeechatType::Int(*i),
+            Value::Lon(l) => WeechatType::Long(i128::from(*l)),
+            Value::Str(s) => WeechatType::String(to_weechat_string(s)),
+            Value::Buf(b) => WeechatType::Buffer(b.clone().map(Bytes::from)),
+            Value::Ptr(p) => WeechatType::Pointer(parse_pointer(p)?),
+            Value::Tim(t) => WeechatType::Time(Timestamp::from_secs(*t as i64)),
+            Value::Htb(htb) => WeechatType::HashTable(
+                htb.iter()
+                    .map(|(k, v)| Ok((k.to_weechat()?, v.to_weechat()?)))
+                    .collect::<Result<_, Error>>()?,
+            ),
+            Value::Hda(hda) => WeechatType::Hdata(hda.to_hdata()?),
+            Value::Inf(name, value) => {
+                WeechatType::Info(to_weechat_string(name), to_weechat_string(value))
+            }
+            Value::Inl(inl) => WeechatType::InfoList(
+                to_weechat_string(&inl.name),
+                inl.items
+                    .iter()
+                    .map(|item| {
+                        item.iter()
+                            .map(|(name, value)| {
+                                Ok((name.clone(), value.to_weechat()?))
+                            })
+                            .collect::<Result<_, Error>>()
+                    })
+                    .collect::<Result<_, Error>>()?,
+            ),
+            Value::Arr(arr) => WeechatType::Array(
+                arr.iter().map(Value::to_weechat).collect::<Result<_, _>>()?,
+            ),
+        })
+    }
+}
+
+impl HdataSpec {
+    pub fn to_hdata(&self) -> Result<Hdata, Error> {
+        let h_path: Vec<String> =
+            self.path.split('/').map(|s| s.to_owned()).collect();
+        let keys = self
+            .keys
+            .split(',')
+            .map(|key| match key.find(':') {
+                Some(colon) => {
+                    Ok((key[..colon].to_owned(), key[colon + 1..].to_owned()))
+                }
+                None => {
+                    Err(invalid(format!("Expected name:type for hdata key {}", key)))
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let values = self
+            .items
+            .iter()
+            .map(|item| {
+                if item.pointers.len() != h_path.len()
+                    || item.values.len() != keys.len()
+                {
+                    return Err(invalid(format!(
+                        "Hdata item needs {} pointers and {} values",
+                        h_path.len(),
+                        keys.len()
+                    )));
+                }
+                let pointers = item
+                    .pointers
+                    .iter()
+                    .map(|p| parse_pointer(p).map(WeechatType::Pointer))
+                    .collect::<Result<_, _>>()?;
+                let values = item
+                    .values
+                    .iter()
+                    .map(Value::to_weechat)
+                    .collect::<Result<_, _>>()?;
+                Ok((pointers, values))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Hdata { h_path, keys, values })
+    }
+}
+
+//
+// Helper functions
+//
+
+fn to_weechat_string(s: &Option<String>) -> WeechatString {
+    match s {
+        Some(s) => WeechatString::from(s.as_str()),
+        None => WeechatString::Null,
+    }
+}
+
+fn parse_pointer(p: &str) -> Result<Pointer, Error> {
+    p.parse().map_err(|_| invalid(format!("Invalid pointer {}", p)))
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,309 @@
+use crate::weechat::Core;
+use libdingy::command::{
+    parse_command, CompressionType, HandshakeCommand, InitCommand, ParsedCommand,
+    PasswordHashAlgo,
+};
+use libdingy::message::{Message, WeechatString, WeechatType};
+use rand::{thread_rng, Rng};
+use std::io::{BufRead, BufReader, Error};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// Iterations offered for pbkdf2, kept low so debug builds log in quickly
+const PBKDF2_ITERATIONS: u32 = 1000;
+
+// Relay backed by a simulated WeeChat core instead of a script. Commands are
+// answered from the core's state, and changes to it reach synced clients.
+pub struct SimRelay {
+    addr: SocketAddr,
+    core: Arc<Mutex<Core>>,
+    // Every client accepted so far, to drop them at will
+    clients: Arc<Mutex<Vec<TcpStream>>>,
+}
+
+impl SimRelay {
+    // Bind to addr (use port 0 for any free port). Clients must log in with
+    // password if one is given.
+    pub fn start<A: ToSocketAddrs>(
+        addr: A,
+        core: Core,
+        password: Option<String>,
+    ) -> Result<SimRelay, Error> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let core = Arc::new(Mutex::new(core));
+        let password = Arc::new(password);
+        let clients = Arc::new(Mutex::new(Vec::new()));
+
+        let listen_core = core.clone();
+        let listen_clients = clients.clone();
+        thread::spawn(move || {
+            let next_id = AtomicUsize::new(0);
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Ok(client) = stream.try_clone() {
+                            listen_clients.lock().unwrap().push(client);
+                        }
+                        let id = next_id.fetch_add(1, Ordering::SeqCst);
+                        let core = listen_core.clone();
+                        let password = password.clone();
+                        thread::spawn(move || {
+                            let result = SimSession::new(id, stream, core, password)
+                                .and_then(|mut session| session.run());
+                            if let Err(e) = result {
+                                println!("Session error: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => println!("Accept error: {:?}", e),
+                }
+            }
+        });
+
+        Ok(SimRelay { addr, core, clients })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // Shared core, for changing state while clients are connected
+    pub fn core(&self) -> Arc<Mutex<Core>> {
+        self.core.clone()
+    }
+
+    // Close every client's connection, as if the network went down.
+    // Returns how many there were.
+    pub fn drop_clients(&self) -> usize {
+        let clients: Vec<TcpStream> = self.clients.lock().unwrap().drain(..).collect();
+        for client in &clients {
+            let _ = client.shutdown(Shutdown::Both);
+        }
+        clients.len()
+    }
+}
+
+struct SimSession {
+    id: usize,
+    reader: BufReader<TcpStream>,
+    sender: Sender<Message>,
+    core: Arc<Mutex<Core>>,
+    password: Arc<Option<String>>,
+    compression: Arc<Mutex<CompressionType>>,
+    password_hash_algo: PasswordHashAlgo,
+    nonce: Vec<u8>,
+    authenticated: bool,
+}
+
+impl SimSession {
+    fn new(
+        id: usize,
+        stream: TcpStream,
+        core: Arc<Mutex<Core>>,
+        password: Arc<Option<String>>,
+    ) -> Result<SimSession, Error> {
+        let (sender, receiver) = channel();
+        let compression = Arc::new(Mutex::new(CompressionType::None));
+
+        // Replies and sync messages from the core share one writer thread
+        let writer = stream.try_clone()?;
+        let writer_compression = compression.clone();
+        thread::spawn(move || write_messages(writer, receiver, writer_compression));
+
+        Ok(SimSession {
+            id,
+            reader: BufReader::new(stream),
+            sender,
+            core,
+            password,
+            compression,
+            password_hash_algo: PasswordHashAlgo::Plain,
+            nonce: thread_rng().gen::<[u8; 16]>().to_vec(),
+            authenticated: false,
+        })
+    }
+
+    fn run(&mut self) -> Result<(), Error> {
+        self.core.lock().unwrap().add_client(self.id, self.sender.clone());
+        let result = self.run_commands();
+        self.core.lock().unwrap().remove_client(self.id);
+        result
+    }
+
+    fn run_commands(&mut self) -> Result<(), Error> {
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let command = match parse_command(&line) {
+                Ok(command) => command,
+                Err(e) => {
+                    println!("Ignoring bad command {:?}: {}", line, e);
+                    continue;
+                }
+            };
+
+            // Like the relay, only handshake and init are allowed before init
+            match (self.authenticated, command) {
+                (_, ParsedCommand::Quit(_)) => return Ok(()),
+                (_, ParsedCommand::Handshake(handshake)) => {
+                    self.handshake(&handshake)
+                }
+                (false, ParsedCommand::Init(init)) => {
+                    if !self.check_auth(&init) {
+                        println!("Authentication failed, closing connection");
+                        return Ok(());
+                    }
+                    if let Some(compression) = init.compression {
+                        *self.compression.lock().unwrap() = compression;
+                    }
+                    self.authenticated = true;
+                }
+                (false, command) => {
+                    println!("Ignoring {:?} before init", command.command_type())
+                }
+                (true, command) => self.handle(command),
+            }
+        }
+    }
+
+    fn handle(&mut self, command: ParsedCommand) {
+        let mut core = self.core.lock().unwrap();
+        match command {
+            ParsedCommand::Hdata(hdata) => {
+                let reply = WeechatType::Hdata(core.hdata(&hdata));
+                self.reply(hdata.id, vec![reply]);
+            }
+            ParsedCommand::Info(info) => {
+                let value = match core.info(&info.name) {
+                    Some(value) => WeechatString::from(value),
+                    None => WeechatString::Null,
+                };
+                let reply = WeechatType::Info(WeechatString::from(info.name), value);
+                self.reply(info.id, vec![reply]);
+            }
+            ParsedCommand::Infolist(infolist) => {
+                let reply =
+                    WeechatType::InfoList(WeechatString::from(infolist.name), vec![]);
+                self.reply(infolist.id, vec![reply]);
+            }
+            ParsedCommand::Nicklist(nicklist) => {
+                let hdata = core.nicklist_hdata(nicklist.buffer.as_deref());
+                self.reply(nicklist.id, vec![WeechatType::Hdata(hdata)]);
+            }
+            ParsedCommand::Input(input) => {
+                if !core.input(&input.buffer, &input.data) {
+                    println!("Input for unknown buffer {}", input.buffer);
+                }
+            }
+            ParsedCommand::Sync(sync) => core.sync(self.id, &sync.args),
+            ParsedCommand::Desync(desync) => core.desync(self.id, &desync.args),
+            ParsedCommand::Ping(ping) => {
+                let args = ping.arguments.map(|args| args.join(" "));
+                let reply = match args {
+                    Some(args) => WeechatString::from(args),
+                    None => WeechatString::Null,
+                };
+                self.reply(
+                    Some("_pong".to_owned()),
+                    vec![WeechatType::String(reply)],
+                );
+            }
+            command => println!("Unsupported command {:?}", command.command_type()),
+        }
+    }
+
+    // Pick the strongest algorithm and first compression the client offers
+    fn handshake(&mut self, handshake: &HandshakeCommand) {
+        let algos = handshake
+            .password_hash_algo
+            .clone()
+            .unwrap_or_else(|| vec![PasswordHashAlgo::Plain]);
+        let algo = [
+            PasswordHashAlgo::Pbkdf2Sha512,
+            PasswordHashAlgo::Pbkdf2Sha256,
+            PasswordHashAlgo::Sha512,
+            PasswordHashAlgo::Sha256,
+            PasswordHashAlgo::Plain,
+        ]
+        .iter()
+        .find(|algo| algos.contains(algo))
+        .cloned();
+        let compression = handshake
+            .compression
+            .as_ref()
+            .and_then(|c| c.first().cloned())
+            .unwrap_or(CompressionType::None);
+
+        if let Some(algo) = algo {
+            self.password_hash_algo = algo;
+        }
+        *self.compression.lock().unwrap() = compression;
+
+        let nonce: String =
+            self.nonce.iter().map(|b| format!("{:02x}", b)).collect();
+        let options = vec![
+            ("password_hash_algo", algo.map_or("", |a| a.as_str()).to_owned()),
+            ("password_hash_iterations", PBKDF2_ITERATIONS.to_string()),
+            ("totp", "off".to_owned()),
+            ("nonce", nonce),
+            ("compression", compression.as_handshake_str().to_owned()),
+            ("escape_commands", "off".to_owned()),
+        ];
+        let table = options
+            .into_iter()
+            .map(|(key, value)| {
+                (
+                    WeechatType::String(WeechatString::from(key)),
+                    WeechatType::String(WeechatString::from(value)),
+                )
+            })
+            .collect();
+        self.reply(handshake.id.clone(), vec![WeechatType::HashTable(table)]);
+    }
+
+    fn check_auth(&self, init: &InitCommand) -> bool {
+        let password = match self.password.as_ref() {
+            Some(password) => password,
+            None => return true,
+        };
+        match &init.password_hash {
+            Some(hash) => {
+                hash.algo == self.password_hash_algo
+                    && hash.salt.starts_with(&self.nonce)
+                    && hash.verify(password)
+            }
+            None => {
+                self.password_hash_algo == PasswordHashAlgo::Plain
+                    && init.password.as_ref() == Some(password)
+            }
+        }
+    }
+
+    fn reply(&self, id: Option<String>, data: Vec<WeechatType>) {
+        let _ = self.sender.send(Message::new(id.unwrap_or_default(), data));
+    }
+}
+
+fn write_messages(
+    mut writer: TcpStream,
+    receiver: Receiver<Message>,
+    compression: Arc<Mutex<CompressionType>>,
+) {
+    for message in receiver {
+        let compression = *compression.lock().unwrap();
+        if let Err(e) = message.encode(&mut writer, compression) {
+            println!("Could not send {}: {}", message.id, e);
+            return;
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use rcgen::{generate_simple_self_signed, CertifiedKey};
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use sha2::{Digest, Sha256};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// How long each side of a connection is waited on before checking the other
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// TLS in front of a plain relay, with a freshly generated self-signed
+// certificate for localhost and 127.0.0.1. Clients can trust it through
+// its fingerprint, the PEM of the certificate as a CA file, or not at all.
+pub struct TlsFront {
+    addr: SocketAddr,
+    cert_pem: String,
+    fingerprint: String,
+}
+
+impl TlsFront {
+    // Bind to addr (use port 0 for any free port) and forward every client
+    // to backend once decrypted, one thread per client
+    pub fn start<A: ToSocketAddrs>(addr: A, backend: SocketAddr) -> Result<TlsFront, Error> {
+        let CertifiedKey { cert, key_pair } =
+            generate_simple_self_signed(vec!["localhost".to_owned(), "127.0.0.1".to_owned()])
+                .map_err(Error::other)?;
+        let der = cert.der().clone();
+        let fingerprint = to_hex(&Sha256::digest(&der));
+        let key = PrivatePkcs8KeyDer::from(key_pair.serialize_der());
+        let config = Arc::new(server_config(der, key.into())?);
+
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let config = config.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = forward(stream, config, backend) {
+                                println!("TLS error: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => println!("Accept error: {:?}", e),
+                }
+            }
+        });
+
+        Ok(TlsFront { addr, cert_pem: cert.pem(), fingerprint })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn cert_pem(&self) -> &str {
+        &self.cert_pem
+    }
+
+    // SHA-256 of the certificate in hex, as clients pin it
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+}
+
+//
+// Helper functions
+//
+
+fn server_config(
+    cert: CertificateDer<'static>,
+    key: PrivateKeyDer<'static>,
+) -> Result<ServerConfig, Error> {
+    ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(vec![cert], key))
+        .map_err(Error::other)
+}
+
+// Pump both ways until either side closes. Reads time out quickly so one
+// thread can wait on both sockets.
+fn forward(
+    client: TcpStream,
+    config: Arc<ServerConfig>,
+    backend: SocketAddr,
+) -> Result<(), Error> {
+    let mut relay = TcpStream::connect(backend)?;
+    relay.set_read_timeout(Some(POLL_INTERVAL))?;
+    client.set_read_timeout(Some(POLL_INTERVAL))?;
+    let conn = ServerConnection::new(config).map_err(Error::other)?;
+    let mut tls = StreamOwned::new(conn, client);
+
+    let mut buf = [0u8; 16 * 1024];
+    let result = loop {
+        match tls.read(&mut buf) {
+            Ok(0) => break Ok(()),
+            Ok(len) => relay.write_all(&buf[..len])?,
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => break Ok(()),
+            Err(ref e) if is_timeout(e) => {}
+            Err(e) => break Err(e),
+        }
+
+        match relay.read(&mut buf) {
+            Ok(0) => {
+                tls.conn.send_close_notify();
+                break tls.flush();
+            }
+            Ok(len) => {
+                tls.write_all(&buf[..len])?;
+                tls.flush()?;
+            }
+            Err(ref e) if is_timeout(e) => {}
+            Err(e) => break Err(e),
+        }
+    };
+
+    let _ = relay.shutdown(Shutdown::Both);
+    let _ = tls.sock.shutdown(Shutdown::Both);
+    result
+}
+
+fn is_timeout(e: &Error) -> bool {
+    e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
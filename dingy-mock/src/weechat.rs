@@ -0,0 +1,984 @@
+use libdingy::command::{HdataCommand, HdataCommandLength, SyncOption};
+use libdingy::message::{
+    Hdata, Message, Pointer, Timestamp, WeechatString, WeechatType,
+};
+use std::sync::mpsc::Sender;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Variables of each hdata we simulate: name, type, and for pointers the hdata
+// they point to. Names and types match WeeChat's own hdata.
+type HdataVar = (&'static str, &'static str, Option<&'static str>);
+
+const BUFFER_VARS: &[HdataVar] = &[
+    ("number", "int", None),
+    ("full_name", "str", None),
+    ("short_name", "str", None),
+    ("name", "str", None),
+    ("type", "int", None),
+    ("nicklist", "int", None),
+    ("title", "str", None),
+    ("local_variables", "htb", None),
+    ("prev_buffer", "ptr", Some("buffer")),
+    ("next_buffer", "ptr", Some("buffer")),
+    ("own_lines", "ptr", Some("lines")),
+    ("lines", "ptr", Some("lines")),
+];
+
+const LINES_VARS: &[HdataVar] = &[
+    ("first_line", "ptr", Some("line")),
+    ("last_line", "ptr", Some("line")),
+    ("lines_count", "int", None),
+];
+
+const LINE_VARS: &[HdataVar] = &[
+    ("data", "ptr", Some("line_data")),
+    ("prev_line", "ptr", Some("line")),
+    ("next_line", "ptr", Some("line")),
+];
+
+const LINE_DATA_VARS: &[HdataVar] = &[
+    ("buffer", "ptr", Some("buffer")),
+    ("date", "tim", None),
+    ("date_printed", "tim", None),
+    ("displayed", "chr", None),
+    ("notify_level", "chr", None),
+    ("highlight", "chr", None),
+    ("tags_array", "arr", None),
+    ("prefix", "str", None),
+    ("message", "str", None),
+];
+
+const BUFFER_OPENED_KEYS: &[&str] = &[
+    "number",
+    "full_name",
+    "short_name",
+    "nicklist",
+    "title",
+    "local_variables",
+    "prev_buffer",
+    "next_buffer",
+];
+
+const LINE_ADDED_KEYS: &[&str] = &[
+    "buffer",
+    "date",
+    "date_printed",
+    "displayed",
+    "notify_level",
+    "highlight",
+    "tags_array",
+    "prefix",
+    "message",
+];
+
+const NICKLIST_KEYS: &str =
+    "group:chr,visible:chr,level:int,name:str,color:str,prefix:str,prefix_color:str";
+
+// Fake heap addresses handed out as pointers
+const FIRST_POINTER: u128 = 0x55d0_a8e8_0000;
+const POINTER_STEP: u128 = 0x40;
+
+#[derive(Debug, Clone)]
+pub struct Buffer {
+    pub pointer: u128,
+    pub lines_pointer: u128,
+    pub number: i32,
+    pub full_name: String,
+    pub short_name: String,
+    pub title: String,
+    pub local_variables: Vec<(String, String)>,
+    pub lines: Vec<Line>,
+    // Groups and nicks in display order, starting with the root group
+    pub nicklist: Vec<NicklistItem>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Line {
+    pub pointer: u128,
+    pub data_pointer: u128,
+    pub date: u64,
+    pub date_printed: u64,
+    pub displayed: bool,
+    pub notify_level: i8,
+    pub highlight: bool,
+    pub tags: Vec<String>,
+    pub prefix: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct NicklistItem {
+    pub pointer: u128,
+    pub group: bool,
+    pub visible: bool,
+    pub level: i32,
+    pub name: String,
+    pub color: String,
+    pub prefix: String,
+    pub prefix_color: String,
+    // Name of the group this item is in, None for the root group
+    pub parent: Option<String>,
+}
+
+// Something a pointer can point at, as indices into Core::buffers
+#[derive(Debug, Clone, Copy)]
+enum Object {
+    Buffer(usize),
+    Lines(usize),
+    Line(usize, usize),
+    LineData(usize, usize),
+}
+
+// A client that sent sync, and what it synced
+struct Client {
+    id: usize,
+    syncs: Vec<(String, SyncOption)>,
+    sender: Sender<Message>,
+}
+
+// In-memory stand-in for WeeChat's buffers, lines and nicklists. Changes made
+// through it are pushed to synced clients like the relay plugin would.
+pub struct Core {
+    pub buffers: Vec<Buffer>,
+    next_pointer: u128,
+    clients: Vec<Client>,
+}
+
+impl Default for Core {
+    fn default() -> Core {
+        Core::new()
+    }
+}
+
+impl Core {
+    // A core with just the core.weechat buffer, like a fresh WeeChat
+    pub fn new() -> Core {
+        let mut core =
+            Core { buffers: vec![], next_pointer: FIRST_POINTER, clients: vec![] };
+        core.add_buffer("core.weechat", "weechat", "WeeChat (dingy-mock)");
+        core
+    }
+
+    //
+    // Buffers
+    //
+
+    // Open a buffer named <plugin>.<name>, returning its pointer
+    pub fn add_buffer(
+        &mut self,
+        full_name: &str,
+        short_name: &str,
+        title: &str,
+    ) -> u128 {
+        let (plugin, name) = match full_name.find('.') {
+            Some(dot) => (&full_name[..dot], &full_name[dot + 1..]),
+            None => ("core", full_name),
+        };
+        let pointer = self.alloc_pointer();
+        let lines_pointer = self.alloc_pointer();
+        let root_pointer = self.alloc_pointer();
+
+        self.buffers.push(Buffer {
+            pointer,
+            lines_pointer,
+            number: self.buffers.len() as i32 + 1,
+            full_name: full_name.to_owned(),
+            short_name: short_name.to_owned(),
+            title: title.to_owned(),
+            local_variables: vec![
+                ("plugin".to_owned(), plugin.to_owned()),
+                ("name".to_owned(), name.to_owned()),
+            ],
+            lines: vec![],
+            nicklist: vec![NicklistItem {
+                pointer: root_pointer,
+                group: true,
+                visible: false,
+                level: 0,
+                name: "root".to_owned(),
+                color: "".to_owned(),
+                prefix: "".to_owned(),
+                prefix_color: "".to_owned(),
+                parent: None,
+            }],
+        });
+
+        let index = self.buffers.len() - 1;
+        let hdata =
+            self.object_hdata("buffer", Object::Buffer(index), BUFFER_OPENED_KEYS);
+        self.emit(index, SyncOption::Buffers, "_buffer_opened", hdata);
+        pointer
+    }
+
+    pub fn close_buffer(&mut self, buffer: &str) -> bool {
+        let index = match self.find_buffer(buffer) {
+            Some(index) => index,
+            None => return false,
+        };
+        let hdata = self.object_hdata(
+            "buffer",
+            Object::Buffer(index),
+            &["number", "full_name"],
+        );
+        self.emit(index, SyncOption::Buffers, "_buffer_closing", hdata);
+
+        self.buffers.remove(index);
+        for (i, buffer) in self.buffers.iter_mut().enumerate() {
+            buffer.number = i as i32 + 1;
+        }
+        true
+    }
+
+    pub fn set_title(&mut self, buffer: &str, title: &str) -> bool {
+        let index = match self.find_buffer(buffer) {
+            Some(index) => index,
+            None => return false,
+        };
+        self.buffers[index].title = title.to_owned();
+        let hdata = self.object_hdata(
+            "buffer",
+            Object::Buffer(index),
+            &["number", "full_name", "title"],
+        );
+        self.emit(index, SyncOption::Buffers, "_buffer_title_changed", hdata);
+        true
+    }
+
+    pub fn set_local_variable(
+        &mut self,
+        buffer: &str,
+        name: &str,
+        value: &str,
+    ) -> bool {
+        let index = match self.find_buffer(buffer) {
+            Some(index) => index,
+            None => return false,
+        };
+        let variables = &mut self.buffers[index].local_variables;
+        let id = match variables.iter_mut().find(|(k, _)| k == name) {
+            Some(variable) => {
+                variable.1 = value.to_owned();
+                "_buffer_localvar_changed"
+            }
+            None => {
+                variables.push((name.to_owned(), value.to_owned()));
+                "_buffer_localvar_added"
+            }
+        };
+        let hdata = self.object_hdata(
+            "buffer",
+            Object::Buffer(index),
+            &["number", "full_name", "local_variables"],
+        );
+        self.emit(index, SyncOption::Buffers, id, hdata);
+        true
+    }
+
+    pub fn remove_local_variable(&mut self, buffer: &str, name: &str) -> bool {
+        let index = match self.find_buffer(buffer) {
+            Some(index) => index,
+            None => return false,
+        };
+        let variables = &mut self.buffers[index].local_variables;
+        let count = variables.len();
+        variables.retain(|(k, _)| k != name);
+        if variables.len() == count {
+            return false;
+        }
+        let hdata = self.object_hdata(
+            "buffer",
+            Object::Buffer(index),
+            &["number", "full_name", "local_variables"],
+        );
+        self.emit(index, SyncOption::Buffers, "_buffer_localvar_removed", hdata);
+        true
+    }
+
+    // Find a buffer by full name or by pointer written as 0x...
+    pub fn find_buffer(&self, buffer: &str) -> Option<usize> {
+        match parse_pointer(buffer) {
+            Some(pointer) => self.buffers.iter().position(|b| b.pointer == pointer),
+            None => self.buffers.iter().position(|b| b.full_name == buffer),
+        }
+    }
+
+    //
+    // Lines
+    //
+
+    // Print a line in a buffer, returning the pointer to its line data
+    pub fn print(
+        &mut self,
+        buffer: &str,
+        prefix: &str,
+        message: &str,
+        tags: &[&str],
+    ) -> Option<u128> {
+        let index = self.find_buffer(buffer)?;
+        let now = now();
+        let pointer = self.alloc_pointer();
+        let data_pointer = self.alloc_pointer();
+        let highlight = !tags.contains(&"no_highlight")
+            && self.buffers[index]
+                .local_variables
+                .iter()
+                .find(|(k, _)| k == "nick")
+                .is_some_and(|(_, nick)| message.contains(nick.as_str()));
+
+        let lines = &mut self.buffers[index].lines;
+        lines.push(Line {
+            pointer,
+            data_pointer,
+            date: now,
+            date_printed: now,
+            displayed: true,
+            notify_level: if highlight { 3 } else { 1 },
+            highlight,
+            tags: tags.iter().map(|t| (*t).to_owned()).collect(),
+            prefix: prefix.to_owned(),
+            message: message.to_owned(),
+        });
+
+        let line = lines.len() - 1;
+        let hdata = self.object_hdata(
+            "line_data",
+            Object::LineData(index, line),
+            LINE_ADDED_KEYS,
+        );
+        self.emit(index, SyncOption::Buffer, "_buffer_line_added", hdata);
+        Some(data_pointer)
+    }
+
+    // Handle text typed in a buffer, as sent by the input command. Text is
+    // printed as coming from the buffer's nick, and a few commands are known.
+    pub fn input(&mut self, buffer: &str, data: &str) -> bool {
+        let index = match self.find_buffer(buffer) {
+            Some(index) => index,
+            None => return false,
+        };
+        let full_name = self.buffers[index].full_name.clone();
+
+        if data.starts_with('/') && !data.starts_with("//") {
+            let (command, args) = match data.find(' ') {
+                Some(space) => (&data[..space], &data[space + 1..]),
+                None => (data, ""),
+            };
+            match command {
+                "/print" => {
+                    self.print(&full_name, "", args, &[]);
+                }
+                "/title" => {
+                    self.set_title(&full_name, args);
+                }
+                "/close" => {
+                    self.close_buffer(&full_name);
+                }
+                _ => {
+                    let error = format!(
+                        "Unknown command \"{}\" (type /help for help)",
+                        command
+                    );
+                    self.print("core.weechat", "=!=", &error, &["no_filter"]);
+                }
+            }
+            return true;
+        }
+
+        let text = data.strip_prefix('/').unwrap_or(data);
+        let nick = self.buffers[index]
+            .local_variables
+            .iter()
+            .find(|(k, _)| k == "nick")
+            .map(|(_, nick)| nick.clone());
+        match nick {
+            Some(nick) => {
+                let nick_tag = format!("nick_{}", nick);
+                let tags =
+                    ["notify_none", "self_msg", "no_highlight", &nick_tag, "log1"];
+                self.print(&full_name, &nick, text, &tags);
+            }
+            None => {
+                self.print(
+                    "core.weechat",
+                    "=!=",
+                    "You can not write text in this buffer",
+                    &["no_filter"],
+                );
+            }
+        }
+        true
+    }
+
+    //
+    // Nicklist
+    //
+
+    pub fn add_nick_group(&mut self, buffer: &str, name: &str) -> bool {
+        let index = match self.find_buffer(buffer) {
+            Some(index) => index,
+            None => return false,
+        };
+        let pointer = self.alloc_pointer();
+        let item = NicklistItem {
+            pointer,
+            group: true,
+            visible: true,
+            level: 1,
+            name: name.to_owned(),
+            color: "weechat.color.nicklist_group".to_owned(),
+            prefix: "".to_owned(),
+            prefix_color: "".to_owned(),
+            parent: Some("root".to_owned()),
+        };
+        self.buffers[index].nicklist.push(item);
+        self.emit_nicklist_diff(index, "root", '+', pointer);
+        true
+    }
+
+    // Add a nick to a group, or to the root group if group is None
+    pub fn add_nick(
+        &mut self,
+        buffer: &str,
+        group: Option<&str>,
+        name: &str,
+        prefix: &str,
+    ) -> bool {
+        let index = match self.find_buffer(buffer) {
+            Some(index) => index,
+            None => return false,
+        };
+        let group = group.unwrap_or("root");
+        let nicklist = &self.buffers[index].nicklist;
+        if !nicklist.iter().any(|item| item.group && item.name == group) {
+            return false;
+        }
+
+        // Keep nicks right after their group
+        let position = nicklist
+            .iter()
+            .rposition(|item| {
+                item.name == group || item.parent.as_deref() == Some(group)
+            })
+            .map_or(nicklist.len(), |p| p + 1);
+        let prefix_color = match prefix {
+            "@" => "lightgreen",
+            "+" => "yellow",
+            _ => "",
+        };
+        let pointer = self.alloc_pointer();
+        let item = NicklistItem {
+            pointer,
+            group: false,
+            visible: true,
+            level: 0,
+            name: name.to_owned(),
+            color: "default".to_owned(),
+            prefix: prefix.to_owned(),
+            prefix_color: prefix_color.to_owned(),
+            parent: Some(group.to_owned()),
+        };
+        self.buffers[index].nicklist.insert(position, item);
+        self.emit_nicklist_diff(index, group, '+', pointer);
+        true
+    }
+
+    pub fn remove_nick(&mut self, buffer: &str, name: &str) -> bool {
+        let index = match self.find_buffer(buffer) {
+            Some(index) => index,
+            None => return false,
+        };
+        let nicklist = &self.buffers[index].nicklist;
+        let position = match nicklist.iter().position(|i| !i.group && i.name == name)
+        {
+            Some(position) => position,
+            None => return false,
+        };
+        let group = nicklist[position].parent.clone().unwrap_or_default();
+        let pointer = nicklist[position].pointer;
+
+        // Send the diff before the nick is gone so it can still be looked up
+        self.emit_nicklist_diff(index, &group, '-', pointer);
+        self.buffers[index].nicklist.remove(position);
+        true
+    }
+
+    // Nicklist of one buffer, or of all buffers, as sent for the nicklist
+    // command and the _nicklist sync message
+    pub fn nicklist_hdata(&self, buffer: Option<&str>) -> Hdata {
+        let indices: Vec<usize> = match buffer {
+            Some(buffer) => self.find_buffer(buffer).into_iter().collect(),
+            None => (0..self.buffers.len()).collect(),
+        };
+        let mut hdata = empty_hdata(&["buffer", "nicklist_item"], NICKLIST_KEYS);
+        for index in indices {
+            let buffer = &self.buffers[index];
+            for item in &buffer.nicklist {
+                hdata.values.push((
+                    vec![
+                        ptr(buffer.pointer),
+                        ptr(item.pointer),
+                    ],
+                    nicklist_values(item),
+                ));
+            }
+        }
+        hdata
+    }
+
+    //
+    // Relay side
+    //
+
+    // Evaluate an hdata command like the relay does, e.g.
+    // buffer:gui_buffers(*)/own_lines/last_line(-50)/data. Objects are listed
+    // in the order they are walked, so negative counts give newest lines first.
+    pub fn hdata(&self, command: &HdataCommand) -> Hdata {
+        let mut h_path = vec![command.hdata.clone()];
+        for (var, _) in &command.var {
+            let target = hdata_vars(h_path.last().unwrap())
+                .and_then(|vars| vars.iter().find(|(name, _, _)| name == var))
+                .and_then(|(_, _, target)| *target);
+            match target {
+                Some(target) => h_path.push(target.to_owned()),
+                None => return Hdata { h_path, keys: vec![], values: vec![] },
+            }
+        }
+
+        let vars = match hdata_vars(h_path.last().unwrap()) {
+            Some(vars) => vars,
+            None => return Hdata { h_path, keys: vec![], values: vec![] },
+        };
+        // Unknown keys are skipped like WeeChat does
+        let keys: Vec<&str> = match &command.keys {
+            Some(keys) => keys
+                .iter()
+                .filter(|key| vars.iter().any(|(name, _, _)| name == key))
+                .map(|key| key.as_str())
+                .collect(),
+            None => vars.iter().map(|(name, _, _)| *name).collect(),
+        };
+
+        let start = match parse_pointer(&command.pointer.0) {
+            Some(pointer) => self
+                .lookup(pointer)
+                .filter(|object| object_hdata_name(*object) == command.hdata),
+            None => self.list(&command.hdata, &command.pointer.0),
+        };
+
+        let mut paths: Vec<(Vec<u128>, Object)> = start
+            .map(|object| self.walk(object, &command.pointer.1))
+            .unwrap_or_default()
+            .into_iter()
+            .map(|object| (vec![self.object_pointer(object)], object))
+            .collect();
+
+        for (var, count) in &command.var {
+            paths = paths
+                .into_iter()
+                .flat_map(|(pointers, object)| {
+                    let next =
+                        self.pointer_var(object, var).and_then(|p| self.lookup(p));
+                    next.map(|next| self.walk(next, count))
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(move |object| {
+                            let mut pointers = pointers.clone();
+                            pointers.push(self.object_pointer(object));
+                            (pointers, object)
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect();
+        }
+
+        let mut hdata = self.keyed_hdata(h_path, &keys);
+        for (pointers, object) in paths {
+            hdata.values.push((
+                pointers.into_iter().map(ptr).collect(),
+                keys.iter().map(|key| self.value(object, key).unwrap()).collect(),
+            ));
+        }
+        hdata
+    }
+
+    // Answer for the info command, null for names we don't know
+    pub fn info(&self, name: &str) -> Option<String> {
+        match name {
+            "version" => Some("4.1.0".to_owned()),
+            "version_number" => Some("67174400".to_owned()),
+            "weechat_site" => Some("https://weechat.org/".to_owned()),
+            _ => None,
+        }
+    }
+
+    // Start sending events to a client
+    pub fn add_client(&mut self, id: usize, sender: Sender<Message>) {
+        self.clients.push(Client { id, syncs: vec![], sender });
+    }
+
+    pub fn remove_client(&mut self, id: usize) {
+        self.clients.retain(|client| client.id != id);
+    }
+
+    // Sync with no arguments means everything, like sync * without options
+    pub fn sync(&mut self, id: usize, args: &[(String, SyncOption)]) {
+        let all = all_sync_args();
+        let args = if args.is_empty() { &all } else { args };
+        let client = match self.clients.iter_mut().find(|client| client.id == id) {
+            Some(client) => client,
+            None => return,
+        };
+        for arg in args {
+            if !client.syncs.contains(arg) {
+                client.syncs.push(arg.clone());
+            }
+        }
+
+        // The relay sends the full nicklist right away when it gets synced
+        let sender = client.sender.clone();
+        for (buffer, option) in args {
+            if *option == SyncOption::Nicklist {
+                let buffer = Some(buffer.as_str()).filter(|b| *b != "*");
+                let hdata = self.nicklist_hdata(buffer);
+                let message = Message::new(
+                    "_nicklist".to_owned(),
+                    vec![WeechatType::Hdata(hdata)],
+                );
+                let _ = sender.send(message);
+            }
+        }
+    }
+
+    pub fn desync(&mut self, id: usize, args: &[(String, SyncOption)]) {
+        let all = all_sync_args();
+        let args = if args.is_empty() { &all } else { args };
+        if let Some(client) = self.clients.iter_mut().find(|client| client.id == id)
+        {
+            client.syncs.retain(|sync| !args.contains(sync));
+        }
+    }
+
+    //
+    // Internals
+    //
+
+    fn alloc_pointer(&mut self) -> u128 {
+        let pointer = self.next_pointer;
+        self.next_pointer += POINTER_STEP;
+        pointer
+    }
+
+    // Send a sync message to every client synced to the buffer, dropping
+    // clients that went away
+    fn emit(&mut self, index: usize, option: SyncOption, id: &str, hdata: Hdata) {
+        let full_name = &self.buffers[index].full_name;
+        let message = Message::new(id.to_owned(), vec![WeechatType::Hdata(hdata)]);
+        self.clients.retain(|client| {
+            let synced = client.syncs.iter().any(|(buffer, o)| {
+                *o == option && (buffer == "*" || buffer == full_name)
+            });
+            !synced || client.sender.send(message.clone()).is_ok()
+        });
+    }
+
+    // Diff for one item: its parent group marked ^, then the item itself
+    fn emit_nicklist_diff(
+        &mut self,
+        index: usize,
+        group: &str,
+        diff: char,
+        pointer: u128,
+    ) {
+        let buffer = &self.buffers[index];
+        let group =
+            buffer.nicklist.iter().find(|item| item.group && item.name == group);
+        let item = buffer.nicklist.iter().find(|item| item.pointer == pointer);
+
+        let mut hdata = empty_hdata(
+            &["buffer", "nicklist_item"],
+            &format!("_diff:chr,{}", NICKLIST_KEYS),
+        );
+        for (diff, item) in [('^', group), (diff, item)] {
+            if let Some(item) = item {
+                let mut values = vec![WeechatType::Char(diff as i8)];
+                values.extend(nicklist_values(item));
+                hdata.values.push((
+                    vec![
+                        ptr(buffer.pointer),
+                        ptr(item.pointer),
+                    ],
+                    values,
+                ));
+            }
+        }
+        self.emit(index, SyncOption::Nicklist, "_nicklist_diff", hdata);
+    }
+
+    // Hdata with a single object, used for sync messages
+    fn object_hdata(&self, hdata: &str, object: Object, keys: &[&str]) -> Hdata {
+        let mut result = self.keyed_hdata(vec![hdata.to_owned()], keys);
+        result.values.push((
+            vec![ptr(self.object_pointer(object))],
+            keys.iter().map(|key| self.value(object, key).unwrap()).collect(),
+        ));
+        result
+    }
+
+    fn keyed_hdata(&self, h_path: Vec<String>, keys: &[&str]) -> Hdata {
+        let vars = hdata_vars(h_path.last().unwrap()).unwrap_or(&[]);
+        let keys = keys
+            .iter()
+            .filter_map(|key| vars.iter().find(|(name, _, _)| name == key))
+            .map(|(name, _type, _)| ((*name).to_owned(), (*_type).to_owned()))
+            .collect();
+        Hdata { h_path, keys, values: vec![] }
+    }
+
+    // Start of a named list, like gui_buffers
+    fn list(&self, hdata: &str, name: &str) -> Option<Object> {
+        match (hdata, name) {
+            ("buffer", "gui_buffers") if !self.buffers.is_empty() => {
+                Some(Object::Buffer(0))
+            }
+            ("buffer", "last_gui_buffer") if !self.buffers.is_empty() => {
+                Some(Object::Buffer(self.buffers.len() - 1))
+            }
+            _ => None,
+        }
+    }
+
+    // Objects reached from start with an hdata count: none for just start,
+    // * for the whole list, positive or negative for that many next or previous
+    fn walk(
+        &self,
+        start: Object,
+        count: &Option<HdataCommandLength>,
+    ) -> Vec<Object> {
+        let (forward, limit) = match count {
+            None => return vec![start],
+            Some(HdataCommandLength::Infinite) => (true, usize::MAX),
+            Some(HdataCommandLength::Finite(n)) => {
+                (*n >= 0, n.unsigned_abs().max(1) as usize)
+            }
+        };
+        let mut objects = vec![start];
+        while objects.len() < limit {
+            let next = match (*objects.last().unwrap(), forward) {
+                (Object::Buffer(b), true) if b + 1 < self.buffers.len() => {
+                    Object::Buffer(b + 1)
+                }
+                (Object::Buffer(b), false) if b > 0 => Object::Buffer(b - 1),
+                (Object::Line(b, l), true)
+                    if l + 1 < self.buffers[b].lines.len() =>
+                {
+                    Object::Line(b, l + 1)
+                }
+                (Object::Line(b, l), false) if l > 0 => Object::Line(b, l - 1),
+                _ => break,
+            };
+            objects.push(next);
+        }
+        objects
+    }
+
+    fn lookup(&self, pointer: u128) -> Option<Object> {
+        for (b, buffer) in self.buffers.iter().enumerate() {
+            if buffer.pointer == pointer {
+                return Some(Object::Buffer(b));
+            }
+            if buffer.lines_pointer == pointer {
+                return Some(Object::Lines(b));
+            }
+            for (l, line) in buffer.lines.iter().enumerate() {
+                if line.pointer == pointer {
+                    return Some(Object::Line(b, l));
+                }
+                if line.data_pointer == pointer {
+                    return Some(Object::LineData(b, l));
+                }
+            }
+        }
+        None
+    }
+
+    fn object_pointer(&self, object: Object) -> u128 {
+        match object {
+            Object::Buffer(b) => self.buffers[b].pointer,
+            Object::Lines(b) => self.buffers[b].lines_pointer,
+            Object::Line(b, l) => self.buffers[b].lines[l].pointer,
+            Object::LineData(b, l) => self.buffers[b].lines[l].data_pointer,
+        }
+    }
+
+    fn pointer_var(&self, object: Object, var: &str) -> Option<u128> {
+        match self.value(object, var)? {
+            WeechatType::Pointer(pointer) if pointer.is_null() => None,
+            WeechatType::Pointer(pointer) => Some(pointer.address()),
+            _ => None,
+        }
+    }
+
+    // Value of an hdata variable, typed as listed in the *_VARS tables
+    fn value(&self, object: Object, var: &str) -> Option<WeechatType> {
+        let pointer =
+            |b: Option<&Buffer>| ptr(b.map_or(0, |b| b.pointer));
+        Some(match object {
+            Object::Buffer(b) => {
+                let buffer = &self.buffers[b];
+                match var {
+                    "number" => WeechatType::Int(buffer.number),
+                    "full_name" => string(&buffer.full_name),
+                    "short_name" => string(&buffer.short_name),
+                    "name" => {
+                        let name =
+                            buffer.local_variables.iter().find(|(k, _)| k == "name");
+                        string(name.map_or("", |(_, v)| v.as_str()))
+                    }
+                    "type" => WeechatType::Int(0),
+                    "nicklist" => {
+                        WeechatType::Int((buffer.nicklist.len() > 1) as i32)
+                    }
+                    "title" => string(&buffer.title),
+                    "local_variables" => WeechatType::HashTable(
+                        buffer
+                            .local_variables
+                            .iter()
+                            .map(|(k, v)| (string(k), string(v)))
+                            .collect(),
+                    ),
+                    "prev_buffer" => {
+                        pointer(b.checked_sub(1).map(|b| &self.buffers[b]))
+                    }
+                    "next_buffer" => pointer(self.buffers.get(b + 1)),
+                    "own_lines" | "lines" => {
+                        ptr(buffer.lines_pointer)
+                    }
+                    _ => return None,
+                }
+            }
+            Object::Lines(b) => {
+                let lines = &self.buffers[b].lines;
+                match var {
+                    "first_line" => {
+                        ptr(lines.first().map_or(0, |l| l.pointer))
+                    }
+                    "last_line" => {
+                        ptr(lines.last().map_or(0, |l| l.pointer))
+                    }
+                    "lines_count" => WeechatType::Int(lines.len() as i32),
+                    _ => return None,
+                }
+            }
+            Object::Line(b, l) => {
+                let lines = &self.buffers[b].lines;
+                match var {
+                    "data" => ptr(lines[l].data_pointer),
+                    "prev_line" => ptr(
+                        l.checked_sub(1).map_or(0, |l| lines[l].pointer),
+                    ),
+                    "next_line" => ptr(
+                        lines.get(l + 1).map_or(0, |l| l.pointer),
+                    ),
+                    _ => return None,
+                }
+            }
+            Object::LineData(b, l) => {
+                let line = &self.buffers[b].lines[l];
+                match var {
+                    "buffer" => ptr(self.buffers[b].pointer),
+                    "date" => time(line.date),
+                    "date_printed" => time(line.date_printed),
+                    "displayed" => WeechatType::Char(line.displayed as i8),
+                    "notify_level" => WeechatType::Char(line.notify_level),
+                    "highlight" => WeechatType::Char(line.highlight as i8),
+                    "tags_array" => WeechatType::Array(
+                        line.tags.iter().map(|t| string(t)).collect(),
+                    ),
+                    "prefix" => string(&line.prefix),
+                    "message" => string(&line.message),
+                    _ => return None,
+                }
+            }
+        })
+    }
+}
+
+//
+// Helper functions
+//
+
+fn hdata_vars(hdata: &str) -> Option<&'static [HdataVar]> {
+    match hdata {
+        "buffer" => Some(BUFFER_VARS),
+        "lines" => Some(LINES_VARS),
+        "line" => Some(LINE_VARS),
+        "line_data" => Some(LINE_DATA_VARS),
+        _ => None,
+    }
+}
+
+fn object_hdata_name(object: Object) -> &'static str {
+    match object {
+        Object::Buffer(_) => "buffer",
+        Object::Lines(_) => "lines",
+        Object::Line(_, _) => "line",
+        Object::LineData(_, _) => "line_data",
+    }
+}
+
+fn all_sync_args() -> Vec<(String, SyncOption)> {
+    [
+        SyncOption::Buffers,
+        SyncOption::Upgrade,
+        SyncOption::Buffer,
+        SyncOption::Nicklist,
+    ]
+    .iter()
+    .map(|option| ("*".to_owned(), *option))
+    .collect()
+}
+
+fn nicklist_values(item: &NicklistItem) -> Vec<WeechatType> {
+    vec![
+        WeechatType::Char(item.group as i8),
+        WeechatType::Char(item.visible as i8),
+        WeechatType::Int(item.level),
+        string(&item.name),
+        string(&item.color),
+        string(&item.prefix),
+        string(&item.prefix_color),
+    ]
+}
+
+fn empty_hdata(h_path: &[&str], keys: &str) -> Hdata {
+    Hdata {
+        h_path: h_path.iter().map(|p| (*p).to_owned()).collect(),
+        keys: keys
+            .split(',')
+            .filter_map(|key| {
+                key.find(':').map(|c| (key[..c].to_owned(), key[c + 1..].to_owned()))
+            })
+            .collect(),
+        values: vec![],
+    }
+}
+
+fn string(s: &str) -> WeechatType {
+    WeechatType::String(WeechatString::from(s))
+}
+
+fn ptr(address: u128) -> WeechatType {
+    WeechatType::Pointer(Pointer::new(address))
+}
+
+fn time(secs: u64) -> WeechatType {
+    WeechatType::Time(Timestamp::from_secs(secs as i64))
+}
+
+fn parse_pointer(p: &str) -> Option<u128> {
+    p.strip_prefix("0x").and_then(|hex| u128::from_str_radix(hex, 16).ok())
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
@@ -0,0 +1,231 @@
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::spanned::Spanned;
+use syn::{
+    parse_macro_input, Data, DeriveInput, Error, Field, Fields, GenericArgument,
+    LitStr, Path, PathArguments, Type,
+};
+
+// #[derive(FromHdata)] implements libdingy::hdata::FromHdata for a struct
+// with named fields. Every field is read from the hdata key of the same name
+// unless one of these #[hdata(...)] attributes says otherwise:
+//   rename = "key"      read from key instead
+//   pointer             the row's own pointer (last in the pointer path)
+//   pointer = "name"    the row's pointer for name in the hdata path
+//   default             use Default::default() when the key is missing
+//   with = "path::fn"   convert with fn(&WeechatType) -> Result<T, String>
+// Fields of type Option<T> are None when their key is missing.
+#[proc_macro_derive(FromHdata, attributes(hdata))]
+pub fn derive_from_hdata(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+enum Source {
+    Key(String),
+    Pointer(Option<String>),
+}
+
+struct FieldOptions {
+    source: Source,
+    default: bool,
+    with: Option<Path>,
+}
+
+fn expand(input: &DeriveInput) -> Result<TokenStream2, Error> {
+    let name = &input.ident;
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(Error::new(
+                    input.span(),
+                    "FromHdata needs a struct with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(Error::new(input.span(), "FromHdata only supports structs"))
+        }
+    };
+
+    let what = name.to_string();
+    let reads = fields
+        .iter()
+        .map(|field| expand_field(field, &what))
+        .collect::<Result<Vec<_>, _>>()?;
+    let idents = fields.iter().map(|field| &field.ident);
+    let locals = fields.iter().map(local);
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::libdingy::hdata::FromHdata for #name #ty_generics #where_clause {
+            fn from_hdata(
+                hdata: &::libdingy::message::Hdata,
+                index: usize,
+            ) -> ::std::result::Result<Self, ::libdingy::hdata::HdataError> {
+                ::libdingy::hdata::derive::check_index(hdata, index, #what)?;
+                #(#reads)*
+                ::std::result::Result::Ok(#name { #(#idents: #locals),* })
+            }
+        }
+    })
+}
+
+fn expand_field(field: &Field, what: &str) -> Result<TokenStream2, Error> {
+    let ident = field.ident.as_ref().unwrap();
+    let local = local(field);
+    let options = field_options(field)?;
+    let option_inner = option_inner(&field.ty);
+    if options.default && option_inner.is_some() {
+        return Err(Error::new(
+            field.span(),
+            "Option fields are already None when missing, drop `default`",
+        ));
+    }
+
+    // Where the value comes from, what to call it in errors and what to do
+    // when it isn't there
+    let (lookup, key, missing_error) = match &options.source {
+        Source::Pointer(name) => {
+            let name = match name {
+                Some(name) => quote!(::std::option::Option::Some(#name)),
+                None => quote!(::std::option::Option::None),
+            };
+            (
+                quote!(::libdingy::hdata::derive::pointer(hdata, index, #name)),
+                ident.to_string(),
+                quote!(::libdingy::hdata::derive::missing_pointer(hdata, #what, #name)),
+            )
+        }
+        Source::Key(key) => (
+            quote!(::libdingy::hdata::derive::value(hdata, index, #key)),
+            key.clone(),
+            quote!(::libdingy::hdata::derive::missing(hdata, #what, #key)),
+        ),
+    };
+
+    let (convert, missing) = match option_inner {
+        Some(inner) => {
+            let convert = conversion(&options, inner, &key, what);
+            (
+                quote!(::std::option::Option::Some(#convert)),
+                quote!(::std::option::Option::None),
+            )
+        }
+        None if options.default => (
+            conversion(&options, &field.ty, &key, what),
+            quote!(::std::default::Default::default()),
+        ),
+        None => (
+            conversion(&options, &field.ty, &key, what),
+            quote!(return ::std::result::Result::Err(#missing_error)),
+        ),
+    };
+    Ok(quote! {
+        let #local = match #lookup {
+            ::std::option::Option::Some(value) => #convert,
+            ::std::option::Option::None => #missing,
+        };
+    })
+}
+
+// What a field is read into before building the struct. The field's own
+// name could shadow hdata, index or an earlier field's value.
+fn local(field: &Field) -> proc_macro2::Ident {
+    let ident = field.ident.as_ref().unwrap().to_string();
+    format_ident!("__field_{}", ident.trim_start_matches("r#"))
+}
+
+// Expression turning `value` into ty, or returning the error
+fn conversion(
+    options: &FieldOptions,
+    ty: &Type,
+    key: &str,
+    what: &str,
+) -> TokenStream2 {
+    match &options.with {
+        Some(with) => quote! {
+            ::libdingy::hdata::derive::convert(value, #what, #key, #with)?
+        },
+        None => quote! {
+            ::libdingy::hdata::derive::unwrap::<#ty>(value, #what, #key)?
+        },
+    }
+}
+
+fn field_options(field: &Field) -> Result<FieldOptions, Error> {
+    let mut rename = None;
+    let mut pointer = None;
+    let mut default = false;
+    let mut with = None;
+
+    for attr in field.attrs.iter().filter(|attr| attr.path().is_ident("hdata")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                let key: LitStr = meta.value()?.parse()?;
+                rename = Some(key.value());
+            } else if meta.path.is_ident("pointer") {
+                pointer = Some(if meta.input.peek(syn::Token![=]) {
+                    let name: LitStr = meta.value()?.parse()?;
+                    Some(name.value())
+                } else {
+                    None
+                });
+            } else if meta.path.is_ident("default") {
+                default = true;
+            } else if meta.path.is_ident("with") {
+                let path: LitStr = meta.value()?.parse()?;
+                with = Some(path.parse::<Path>()?);
+            } else {
+                return Err(meta.error(
+                    "expected rename, pointer, default or with in #[hdata(...)]",
+                ));
+            }
+            Ok(())
+        })?;
+    }
+
+    let source = match (rename, pointer) {
+        (Some(_), Some(_)) => {
+            return Err(Error::new(
+                field.span(),
+                "a field can't be both a renamed key and a pointer",
+            ))
+        }
+        (_, Some(name)) => Source::Pointer(name),
+        (Some(key), None) => Source::Key(key),
+        (None, None) => {
+            let ident = field.ident.as_ref().unwrap().to_string();
+            Source::Key(ident.trim_start_matches("r#").to_owned())
+        }
+    };
+    Ok(FieldOptions { source, default, with })
+}
+
+// T for a field typed Option<T>
+fn option_inner(ty: &Type) -> Option<&Type> {
+    let path = match ty {
+        Type::Path(path) if path.qself.is_none() => &path.path,
+        _ => return None,
+    };
+    let segment = path.segments.last()?;
+    if segment.ident != "Option" {
+        return None;
+    }
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args) if args.args.len() == 1 => {
+            match args.args.first() {
+                Some(GenericArgument::Type(inner)) => Some(inner),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
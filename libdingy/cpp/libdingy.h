@@ -6,8 +6,17 @@
 typedef enum {
   None,
   Zlib,
+  Zstd,
 } CompressionType;
 
+typedef enum {
+  Plain,
+  Sha256,
+  Sha512,
+  Pbkdf2Sha256,
+  Pbkdf2Sha512,
+} PasswordHashAlgo;
+
 typedef enum {
   Buffers,
   Upgrade,
@@ -32,10 +41,48 @@ typedef enum {
 
 typedef struct Hdata Hdata;
 
+typedef struct HdataPath HdataPath;
+
 typedef struct Message Message;
 
 typedef struct WeechatType WeechatType;
 
+/**
+ * Remove WeeChat color and attribute codes from a string, like a line's prefix or message
+ * @param input: String with color codes (invalid UTF-8 is replaced with U+FFFD)
+ * @param input_length: Length of input string
+ * @param output: Output buffer
+ * @param output_length: Capacity of output buffer
+ * @return Number of bytes copied
+ */
+uintptr_t color_strip(const uint8_t *input,
+                      uintptr_t input_length,
+                      uint8_t *output,
+                      uintptr_t output_length);
+
+/**
+ * Create a completion command
+ * @param id: Id of command or null
+ * @param id_length: Length of id string
+ * @param buffer: Buffer for completion
+ * @param buffer_length: Length of buffer string
+ * @param position: Position in data to complete at, -1 for the end
+ * @param data: Buffer for data or null
+ * @param data_length: Length of data string
+ * @param output: Output buffer
+ * @param output_length: Capacity of output buffer
+ * @return Number of bytes in full message (even if truncated)
+ */
+uintptr_t command_completion_print(const uint8_t *id,
+                                   uintptr_t id_length,
+                                   const uint8_t *buffer,
+                                   uintptr_t buffer_length,
+                                   int32_t position,
+                                   const uint8_t *data,
+                                   uintptr_t data_length,
+                                   uint8_t *output,
+                                   uintptr_t output_length);
+
 /**
  * Create a desync command
  * @param id: Id of command or null
@@ -57,6 +104,29 @@ uintptr_t command_desync_print(const uint8_t *id,
                                uint8_t *output,
                                uintptr_t output_length);
 
+/**
+ * Create a handshake command
+ * @param id: Id of command or null
+ * @param id_length: Length of id string
+ * @param password_hash_algos: List of supported hash algorithms or null
+ * @param password_hash_algos_length: Number of hash algorithms
+ * @param compressions: List of supported compression types or null
+ * @param compressions_length: Number of compression types
+ * @param escape_commands: Whether to enable escaped commands or null
+ * @param output: Output buffer
+ * @param output_length: Capacity of output buffer
+ * @return Number of bytes in full message (even if truncated)
+ */
+uintptr_t command_handshake_print(const uint8_t *id,
+                                  uintptr_t id_length,
+                                  const PasswordHashAlgo *password_hash_algos,
+                                  uintptr_t password_hash_algos_length,
+                                  const CompressionType *compressions,
+                                  uintptr_t compressions_length,
+                                  const bool *escape_commands,
+                                  uint8_t *output,
+                                  uintptr_t output_length);
+
 /**
  * Create an hdata command
  * @param id: Id of command or null
@@ -144,6 +214,8 @@ uintptr_t command_infolist_print(const uint8_t *id,
  * @param id_length: Length of id string
  * @param password: Password for server or null
  * @param password_length: Length of password string
+ * @param totp: One time password or null
+ * @param totp_length: Length of totp string
  * @param compression: Compression type or null
  * @param output: Output buffer
  * @param output_length: Capacity of output buffer
@@ -153,6 +225,8 @@ uintptr_t command_init_print(const uint8_t *id,
                              uintptr_t id_length,
                              const uint8_t *password,
                              uintptr_t password_length,
+                             const uint8_t *totp,
+                             uintptr_t totp_length,
                              const CompressionType *compression,
                              uint8_t *output,
                              uintptr_t output_length);
@@ -286,6 +360,83 @@ WeechatType *hdata_buffer_object_item(Hdata *hdata, uintptr_t buffer_index, uint
  */
 WeechatType *hdata_buffer_path_item(Hdata *hdata, uintptr_t buffer_index, uintptr_t path_index);
 
+/**
+ * Get every item from the last list, pointer or variable
+ * @param builder: Builder pointer
+ */
+void hdata_builder_all(HdataPath *builder);
+
+/**
+ * Get count items from the last list, pointer or variable
+ * @param builder: Builder pointer
+ * @param count: Number of items, negative to go backwards (0 is invalid)
+ */
+void hdata_builder_count(HdataPath *builder, int32_t count);
+
+/**
+ * Free a builder without printing it
+ * @param builder: Builder pointer
+ */
+void hdata_builder_free(HdataPath *builder);
+
+/**
+ * Only return some keys
+ * @param builder: Builder pointer
+ * @param keys: List of strings
+ * @param keys_lengths: List of lengths of keys
+ * @param keys_length: Number of keys
+ */
+void hdata_builder_keys(HdataPath *builder,
+                        const uint8_t *const *keys,
+                        const uintptr_t *keys_lengths,
+                        uintptr_t keys_length);
+
+/**
+ * Start an hdata path at a named list
+ * @param builder: Builder pointer
+ * @param name: Name of list, like gui_buffers
+ * @param name_length: Length of name string
+ */
+void hdata_builder_list(HdataPath *builder, const uint8_t *name, uintptr_t name_length);
+
+/**
+ * Start building an hdata command, see the other hdata_builder_* functions
+ * @param hdata: Name of hdata
+ * @param hdata_length: Length of hdata string
+ * @return Builder pointer, freed by hdata_builder_print or hdata_builder_free
+ */
+HdataPath *hdata_builder_new(const uint8_t *hdata, uintptr_t hdata_length);
+
+/**
+ * Start an hdata path at a pointer
+ * @param builder: Builder pointer
+ * @param pointer: Pointer value
+ */
+void hdata_builder_pointer(HdataPath *builder, uintptr_t pointer);
+
+/**
+ * Print the hdata command and free the builder
+ * @param builder: Builder pointer, invalid after this call
+ * @param id: Id of command or null
+ * @param id_length: Length of id string
+ * @param output: Output buffer
+ * @param output_length: Capacity of output buffer
+ * @return Number of bytes in full message (even if truncated), 0 if the path is invalid
+ */
+uintptr_t hdata_builder_print(HdataPath *builder,
+                              const uint8_t *id,
+                              uintptr_t id_length,
+                              uint8_t *output,
+                              uintptr_t output_length);
+
+/**
+ * Follow a variable in an hdata path
+ * @param builder: Builder pointer
+ * @param var: Name of variable
+ * @param var_length: Length of var string
+ */
+void hdata_builder_then(HdataPath *builder, const uint8_t *var, uintptr_t var_length);
+
 /**
  * Get count of keys in an Hdata
  * @param hdata: Pointer to Hdata struct
@@ -364,6 +515,19 @@ Message *message_parse(const uint8_t *bytes, uintptr_t length, uintptr_t *parse_
  */
 uintptr_t message_parse_length(const uint8_t *bytes, uintptr_t length);
 
+/**
+ * Generate a TOTP code for the current time
+ * @param secret: Base32 encoded TOTP secret
+ * @param secret_length: Length of secret string
+ * @param output: Output buffer
+ * @param output_length: Capacity of output buffer
+ * @return Number of bytes in code (even if truncated), 0 if the secret is invalid
+ */
+uintptr_t totp_generate(const uint8_t *secret,
+                        uintptr_t secret_length,
+                        uint8_t *output,
+                        uintptr_t output_length);
+
 /**
  * Get number of items in a WeechatType::Array
  * @param weechat_type: WeechatType pointer
@@ -504,9 +668,9 @@ int32_t weechat_type_int_get(WeechatType *weechat_type);
 /**
  * Get long value from a WeechatType::Long
  * @param weechat_type: WeechatType pointer
- * @return Long value
+ * @return Long value (64-bit, like WeeChat's longs)
  */
-intptr_t weechat_type_long_get(WeechatType *weechat_type);
+int64_t weechat_type_long_get(WeechatType *weechat_type);
 
 /**
  * Get pointer value from a WeechatType::Pointer
@@ -515,17 +679,46 @@ intptr_t weechat_type_long_get(WeechatType *weechat_type);
  */
 uintptr_t weechat_type_pointer_get(WeechatType *weechat_type);
 
+/**
+ * Print a WeechatType::Pointer in the 0x form commands use
+ * @param weechat_type: WeechatType pointer
+ * @param output: Output buffer
+ * @param output_length: Capacity of output buffer
+ * @return Number of bytes copied (0 if not a pointer)
+ */
+uintptr_t weechat_type_pointer_print(WeechatType *weechat_type,
+                                     uint8_t *output,
+                                     uintptr_t output_length);
+
 /**
  * Get string value from a WeechatType::String
  * @param weechat_type: WeechatType pointer
  * @param length: Pointer to length of string
- * @return String buffer (not null-terminated)
+ * @return String buffer (not null-terminated), the bytes the relay sent which may not be UTF-8
  */
 const uint8_t *weechat_type_string_get(WeechatType *weechat_type, uintptr_t *length);
 
+/**
+ * Check whether a WeechatType::String is the null string
+ * @param weechat_type: WeechatType pointer
+ * @return True for the null string (false for other types)
+ */
+bool weechat_type_string_is_null(WeechatType *weechat_type);
+
+/**
+ * Copy a WeechatType::String as UTF-8, replacing invalid sequences with U+FFFD
+ * @param weechat_type: WeechatType pointer
+ * @param output: Output buffer
+ * @param output_length: Capacity of output buffer
+ * @return Number of bytes copied (0 for the null string)
+ */
+uintptr_t weechat_type_string_lossy(WeechatType *weechat_type,
+                                    uint8_t *output,
+                                    uintptr_t output_length);
+
 /**
  * Get time value from a WeechatType::Time
  * @param weechat_type: WeechatType pointer
- * @return Time value
+ * @return Time value (seconds since the Unix epoch)
  */
-uintptr_t weechat_type_time_get(WeechatType *weechat_type);
+int64_t weechat_type_time_get(WeechatType *weechat_type);
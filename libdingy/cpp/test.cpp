@@ -55,7 +55,7 @@ void print_type(WeechatType *type) {
 			printf("Int(%d)", weechat_type_int_get(type));
 			break;
 		case LongType:
-			printf("Long(%zu)", weechat_type_long_get(type));
+			printf("Long(%lld)", (long long)weechat_type_long_get(type));
 			break;
 		case StringType: {
 			printf("String(");
@@ -73,7 +73,7 @@ void print_type(WeechatType *type) {
 			printf("Pointer(%p)", (void *)weechat_type_pointer_get(type));
 			break;
 		case TimeType:
-			printf("Time(%zu)", weechat_type_time_get(type));
+			printf("Time(%lld)", (long long)weechat_type_time_get(type));
 			break;
 		case HashTableType: {
 			printf("HashTable(");
@@ -210,7 +210,147 @@ void print_message(Message *msg) {
 	}
 }
 
+// Longs above INT32_MAX used to be truncated to ints
+bool check_long_fixtures() {
+	// lon 2147483648, lon 9223372036854775807, lon -9223372036854775808
+	uint8_t longs[] = {0x00, 0x00, 0x00, 0x4B, 0x00, 0x00, 0x00, 0x00, 0x05, 0x6C, 0x6F, 0x6E, 0x67, 0x73, 0x6C, 0x6F, 0x6E, 0x0A, 0x32, 0x31, 0x34, 0x37, 0x34, 0x38, 0x33, 0x36, 0x34, 0x38, 0x6C, 0x6F, 0x6E, 0x13, 0x39, 0x32, 0x32, 0x33, 0x33, 0x37, 0x32, 0x30, 0x33, 0x36, 0x38, 0x35, 0x34, 0x37, 0x37, 0x35, 0x38, 0x30, 0x37, 0x6C, 0x6F, 0x6E, 0x14, 0x2D, 0x39, 0x32, 0x32, 0x33, 0x33, 0x37, 0x32, 0x30, 0x33, 0x36, 0x38, 0x35, 0x34, 0x37, 0x37, 0x35, 0x38, 0x30, 0x38};
+	int64_t expected_longs[] = {2147483648LL, INT64_MAX, INT64_MIN};
+
+	// hdata relay with bytes_recv:lon 5000000000, bytes_sent:lon 4294967296
+	uint8_t counters[] = {0x00, 0x00, 0x00, 0x65, 0x00, 0x00, 0x00, 0x00, 0x08, 0x63, 0x6F, 0x75, 0x6E, 0x74, 0x65, 0x72, 0x73, 0x68, 0x64, 0x61, 0x00, 0x00, 0x00, 0x05, 0x72, 0x65, 0x6C, 0x61, 0x79, 0x00, 0x00, 0x00, 0x1D, 0x62, 0x79, 0x74, 0x65, 0x73, 0x5F, 0x72, 0x65, 0x63, 0x76, 0x3A, 0x6C, 0x6F, 0x6E, 0x2C, 0x62, 0x79, 0x74, 0x65, 0x73, 0x5F, 0x73, 0x65, 0x6E, 0x74, 0x3A, 0x6C, 0x6F, 0x6E, 0x00, 0x00, 0x00, 0x01, 0x0C, 0x35, 0x35, 0x64, 0x30, 0x61, 0x38, 0x65, 0x38, 0x30, 0x30, 0x30, 0x30, 0x0A, 0x35, 0x30, 0x30, 0x30, 0x30, 0x30, 0x30, 0x30, 0x30, 0x30, 0x0A, 0x34, 0x32, 0x39, 0x34, 0x39, 0x36, 0x37, 0x32, 0x39, 0x36};
+	int64_t expected_counters[] = {5000000000LL, 4294967296LL};
+
+	bool ok = true;
+	uintptr_t parsed;
+
+	Message *msg = message_parse(longs, sizeof(longs), &parsed);
+	if (msg == nullptr || message_data_count(msg) != 3) {
+		printf("longs fixture did not parse\n");
+		return false;
+	}
+	for (int i = 0; i < 3; ++i) {
+		WeechatType *item = message_data_item(msg, i);
+		if (weechat_type_enum(item) != LongType || weechat_type_long_get(item) != expected_longs[i]) {
+			printf("longs fixture item %d: ", i);
+			print_type(item);
+			printf("\n");
+			ok = false;
+		}
+	}
+	message_free(msg);
+
+	msg = message_parse(counters, sizeof(counters), &parsed);
+	if (msg == nullptr || message_data_count(msg) != 1) {
+		printf("counters fixture did not parse\n");
+		return false;
+	}
+	Hdata *hdata = weechat_type_hdata_get(message_data_item(msg, 0));
+	for (int i = 0; i < 2; ++i) {
+		WeechatType *item = hdata_buffer_object_item(hdata, 0, i);
+		if (weechat_type_enum(item) != LongType || weechat_type_long_get(item) != expected_counters[i]) {
+			printf("counters fixture item %d: ", i);
+			print_type(item);
+			printf("\n");
+			ok = false;
+		}
+	}
+	message_free(msg);
+
+	return ok;
+}
+
+// Strings are passed through as sent, even when they aren't UTF-8
+bool check_latin1_fixture() {
+	// str "caf\xE9" (latin-1), str null
+	uint8_t strings[] = {0x00, 0x00, 0x00, 0x1B, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0x73, 0x74, 0x72, 0x00, 0x00, 0x00, 0x04, 0x63, 0x61, 0x66, 0xE9, 0x73, 0x74, 0x72, 0xFF, 0xFF, 0xFF, 0xFF};
+
+	bool ok = true;
+	uintptr_t parsed;
+	Message *msg = message_parse(strings, sizeof(strings), &parsed);
+	if (msg == nullptr || message_data_count(msg) != 2) {
+		printf("latin-1 fixture did not parse\n");
+		return false;
+	}
+
+	WeechatType *item = message_data_item(msg, 0);
+	uintptr_t length;
+	const uint8_t *raw = weechat_type_string_get(item, &length);
+	if (length != 4 || memcmp(raw, "caf\xE9", 4) != 0 || weechat_type_string_is_null(item)) {
+		printf("latin-1 fixture lost its raw bytes\n");
+		ok = false;
+	}
+	uint8_t output[16];
+	length = weechat_type_string_lossy(item, output, sizeof(output));
+	if (length != 6 || memcmp(output, "caf\xEF\xBF\xBD", 6) != 0) {
+		printf("latin-1 fixture lossy copy is %d bytes\n", (int)length);
+		ok = false;
+	}
+
+	if (!weechat_type_string_is_null(message_data_item(msg, 1))) {
+		printf("latin-1 fixture null string is not null\n");
+		ok = false;
+	}
+	message_free(msg);
+
+	return ok;
+}
+
+// Colors in line prefixes and messages can be stripped for plain output
+bool check_color_strip() {
+	const char *colored = "\x19" "14nick\x19" "F*05 bold\x1C \x1A\x01plain\x19@";
+	const char *expected = "nick bold plain@";
+	uint8_t output[64];
+	uintptr_t length = color_strip((const uint8_t *)colored, strlen(colored), output, sizeof(output) - 1);
+	output[length] = 0;
+	if (strcmp((char *)output, expected) != 0) {
+		printf("color strip gave %s\n", output);
+		return false;
+	}
+	return true;
+}
+
+bool check_hdata_builder() {
+	uint8_t output[256];
+	uint8_t *keys[] = {(uint8_t *)"date", (uint8_t *)"message"};
+	uintptr_t keys_lengths[] = {4, 7};
+
+	HdataPath *builder = hdata_builder_new((const uint8_t *)"buffer", 6);
+	hdata_builder_list(builder, (const uint8_t *)"gui_buffers", 11);
+	hdata_builder_all(builder);
+	hdata_builder_then(builder, (const uint8_t *)"own_lines", 9);
+	hdata_builder_then(builder, (const uint8_t *)"last_line", 9);
+	hdata_builder_count(builder, -50);
+	hdata_builder_then(builder, (const uint8_t *)"data", 4);
+	hdata_builder_keys(builder, keys, keys_lengths, 2);
+	uintptr_t length = hdata_builder_print(builder, (const uint8_t *)"lines", 5, output, sizeof(output) - 1);
+	output[length] = 0;
+
+	const char *expected = "(lines) hdata buffer:gui_buffers(*)/own_lines/last_line(-50)/data date,message\n";
+	if (strcmp((char *)output, expected) != 0) {
+		printf("hdata builder printed %s", output);
+		return false;
+	}
+
+	// A count without anything to count is refused
+	builder = hdata_builder_new((const uint8_t *)"buffer", 6);
+	hdata_builder_count(builder, 1);
+	hdata_builder_pointer(builder, 0x55d0a8e8c5f0);
+	if (hdata_builder_print(builder, nullptr, 0, output, sizeof(output) - 1) != 0) {
+		printf("hdata builder accepted a count before its pointer\n");
+		return false;
+	}
+	return true;
+}
+
 int main(int argc, const char **argv) {
+	if (!check_long_fixtures() || !check_latin1_fixture() || !check_color_strip() || !check_hdata_builder()) {
+		return EXIT_FAILURE;
+	}
+	if (argc > 1 && strcmp(argv[1], "--fixtures") == 0) {
+		printf("Fixtures OK\n");
+		return EXIT_SUCCESS;
+	}
+
 	uint8_t bytes[] = {0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00, 0x00, 0x07, 0x42, 0x75, 0x66, 0x66, 0x65, 0x72, 0x73, 0x68, 0x64, 0x61, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xF1};
 
 	uintptr_t parsed;
@@ -246,7 +386,7 @@ int main(int argc, const char **argv) {
 
 	uint8_t init[2048];
 	CompressionType compression = CompressionType::Zlib;
-	uintptr_t init_length = command_init_print((const uint8_t *)"aaa", 3, (const uint8_t *)"jack2istheworst", 15, &compression, init, 2047);
+	uintptr_t init_length = command_init_print((const uint8_t *)"aaa", 3, (const uint8_t *)"jack2istheworst", 15, nullptr, 0, &compression, init, 2047);
 	init[init_length] = 0;
 	printf("%s", init);
 	send(tcp_sock, init, strlen((char *)init), 0);
@@ -0,0 +1,499 @@
+use crate::command::{CompressionType, PasswordHashAlgo};
+use crate::message::{Message, WeechatError, WeechatErrorType, WeechatString};
+use backtrace::Backtrace;
+use byteorder::{ByteOrder, BE};
+use hmac::{Hmac, Mac};
+use pbkdf2::pbkdf2_hmac;
+use rand::{thread_rng, Rng};
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Options negotiated by the relay in reply to a handshake command
+#[derive(Debug, Clone)]
+pub struct HandshakeResponse {
+    // None if the relay supports none of the algorithms we offered,
+    // in which case authentication is not possible
+    pub password_hash_algo: Option<PasswordHashAlgo>,
+    pub password_hash_iterations: u32,
+    pub totp: bool,
+    pub nonce: Vec<u8>,
+    pub compression: Option<CompressionType>,
+    pub escape_commands: bool,
+}
+
+impl HandshakeResponse {
+    pub fn parse(message: &Message) -> Result<HandshakeResponse, WeechatError> {
+        let table = message
+            .data
+            .first()
+            .and_then(|item| item.unwrap::<Vec<(WeechatString, WeechatString)>>())
+            .ok_or_else(|| {
+                handshake_error(format!(
+                    "Expected a string hashtable in handshake reply, got {:?}",
+                    message.data
+                ))
+            })?;
+
+        let get = |key: &str| {
+            table.iter().find(|(k, _)| k.to_str() == key).map(|(_, v)| v.to_str())
+        };
+
+        let password_hash_algo = match get("password_hash_algo") {
+            Some(ref algo) if !algo.is_empty() => {
+                Some(PasswordHashAlgo::parse(algo).ok_or_else(|| {
+                    handshake_error(format!("Unknown password_hash_algo {}", algo))
+                })?)
+            }
+            _ => None,
+        };
+
+        let password_hash_iterations = match get("password_hash_iterations") {
+            Some(iterations) => iterations.parse::<u32>().map_err(|_| {
+                handshake_error(format!(
+                    "Invalid password_hash_iterations {}",
+                    iterations
+                ))
+            })?,
+            None => 0,
+        };
+
+        let nonce = match get("nonce") {
+            Some(nonce) => decode_hex(&nonce).ok_or_else(|| {
+                handshake_error(format!("Invalid nonce {}", nonce))
+            })?,
+            None => vec![],
+        };
+
+        Ok(HandshakeResponse {
+            password_hash_algo,
+            password_hash_iterations,
+            totp: get("totp").is_some_and(|v| v == "on"),
+            nonce,
+            compression: get("compression")
+                .and_then(|c| CompressionType::from_handshake_str(&c)),
+            escape_commands: get("escape_commands").is_some_and(|v| v == "on"),
+        })
+    }
+}
+
+// Hashed password sent in init as password_hash=<algo>:<salt>:[iterations:]<hash>
+#[derive(Debug, Clone)]
+pub struct PasswordHash {
+    pub algo: PasswordHashAlgo,
+    pub salt: Vec<u8>,
+    pub iterations: u32,
+    pub hash: Vec<u8>,
+}
+
+impl PasswordHash {
+    // Hash the password with the given salt. Returns None for Plain, which
+    // has to be sent as password= instead.
+    pub fn compute(
+        algo: PasswordHashAlgo,
+        password: &str,
+        salt: &[u8],
+        iterations: u32,
+    ) -> Option<PasswordHash> {
+        let hash = match algo {
+            PasswordHashAlgo::Plain => return None,
+            PasswordHashAlgo::Sha256 => Sha256::new()
+                .chain_update(salt)
+                .chain_update(password)
+                .finalize()
+                .to_vec(),
+            PasswordHashAlgo::Sha512 => Sha512::new()
+                .chain_update(salt)
+                .chain_update(password)
+                .finalize()
+                .to_vec(),
+            PasswordHashAlgo::Pbkdf2Sha256 => {
+                let mut hash = vec![0u8; 32];
+                pbkdf2_hmac::<Sha256>(
+                    password.as_bytes(),
+                    salt,
+                    iterations,
+                    &mut hash,
+                );
+                hash
+            }
+            PasswordHashAlgo::Pbkdf2Sha512 => {
+                let mut hash = vec![0u8; 64];
+                pbkdf2_hmac::<Sha512>(
+                    password.as_bytes(),
+                    salt,
+                    iterations,
+                    &mut hash,
+                );
+                hash
+            }
+        };
+        Some(PasswordHash { algo, salt: salt.to_vec(), iterations, hash })
+    }
+
+    // Hash the password with the algorithm negotiated in the handshake. The salt
+    // is the server nonce followed by a random client nonce.
+    pub fn from_handshake(
+        response: &HandshakeResponse,
+        password: &str,
+    ) -> Option<PasswordHash> {
+        let client_nonce: [u8; 16] = thread_rng().gen();
+        let mut salt = response.nonce.clone();
+        salt.extend_from_slice(&client_nonce);
+
+        response.password_hash_algo.and_then(|algo| {
+            PasswordHash::compute(
+                algo,
+                password,
+                &salt,
+                response.password_hash_iterations,
+            )
+        })
+    }
+
+    // Check a password against this hash, for the relay side of init
+    pub fn verify(&self, password: &str) -> bool {
+        PasswordHash::compute(self.algo, password, &self.salt, self.iterations)
+            .is_some_and(|computed| computed.hash == self.hash)
+    }
+
+    // Parse the <algo>:<salt>:[iterations:]<hash> value sent by a client
+    pub fn decode(value: &str) -> Option<PasswordHash> {
+        let parts: Vec<&str> = value.split(':').collect();
+        let algo = PasswordHashAlgo::parse(parts.first()?)?;
+        let (salt, iterations, hash) = match (algo, parts.len()) {
+            (PasswordHashAlgo::Pbkdf2Sha256, 4)
+            | (PasswordHashAlgo::Pbkdf2Sha512, 4) => {
+                (parts[1], parts[2].parse::<u32>().ok()?, parts[3])
+            }
+            (PasswordHashAlgo::Sha256, 3) | (PasswordHashAlgo::Sha512, 3) => {
+                (parts[1], 0, parts[2])
+            }
+            _ => return None,
+        };
+        Some(PasswordHash {
+            algo,
+            salt: decode_hex(salt)?,
+            iterations,
+            hash: decode_hex(hash)?,
+        })
+    }
+
+    pub fn encode(&self) -> String {
+        match self.algo {
+            PasswordHashAlgo::Pbkdf2Sha256 | PasswordHashAlgo::Pbkdf2Sha512 => {
+                format!(
+                    "{}:{}:{}:{}",
+                    self.algo.as_str(),
+                    encode_hex(&self.salt),
+                    self.iterations,
+                    encode_hex(&self.hash)
+                )
+            }
+            _ => format!(
+                "{}:{}:{}",
+                self.algo.as_str(),
+                encode_hex(&self.salt),
+                encode_hex(&self.hash)
+            ),
+        }
+    }
+}
+
+// Time-based one time password generator (RFC 6238) for relays with
+// relay.network.totp_secret set. Uses HMAC-SHA1 like WeeChat does.
+#[derive(Debug, Clone)]
+pub struct Totp {
+    secret: Vec<u8>,
+    digits: u32,
+    period: u64,
+    // Seconds to add to the local clock, for hosts whose clock is off
+    pub skew: i64,
+}
+
+impl Totp {
+    // Secret as shown by WeeChat's /secure or an authenticator app. Spaces and
+    // padding are ignored. Returns None if it is not valid base32.
+    pub fn from_base32(secret: &str) -> Option<Totp> {
+        let secret: String = secret
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != '=')
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+        base32::decode(base32::Alphabet::RFC4648 { padding: false }, &secret)
+            .filter(|secret| !secret.is_empty())
+            .map(|secret| Totp { secret, digits: 6, period: 30, skew: 0 })
+    }
+
+    // Code length, 6 by default. The truncated HMAC is 31 bits so codes longer
+    // than 10 digits can't exist. Returns None outside of 1 to 10.
+    pub fn with_digits(mut self, digits: u32) -> Option<Totp> {
+        if digits == 0 || digits > 10 {
+            return None;
+        }
+        self.digits = digits;
+        Some(self)
+    }
+
+    // Seconds each code is valid for, 30 by default. Returns None for 0.
+    pub fn with_period(mut self, period: u64) -> Option<Totp> {
+        if period == 0 {
+            return None;
+        }
+        self.period = period;
+        Some(self)
+    }
+
+    pub fn digits(&self) -> u32 {
+        self.digits
+    }
+
+    pub fn period(&self) -> u64 {
+        self.period
+    }
+
+    // Code for the current time, adjusted by skew
+    pub fn generate(&self) -> String {
+        self.generate_at(self.now())
+    }
+
+    pub fn generate_at(&self, time: u64) -> String {
+        self.generate_counter(time / self.period)
+    }
+
+    // Check a code against the current time, accepting codes from up to `window`
+    // periods before or after to tolerate clock differences between both ends
+    pub fn verify(&self, code: &str, window: u64) -> bool {
+        let counter = self.now() / self.period;
+        (counter.saturating_sub(window)..=counter.saturating_add(window))
+            .any(|c| self.generate_counter(c) == code)
+    }
+
+    fn generate_counter(&self, counter: u64) -> String {
+        let mut counter_buf = [0u8; 8];
+        BE::write_u64(&mut counter_buf, counter);
+
+        let mut mac = Hmac::<Sha1>::new_from_slice(&self.secret)
+            .expect("HMAC accepts keys of any length");
+        mac.update(&counter_buf);
+        let digest = mac.finalize().into_bytes();
+
+        // Dynamic truncation
+        let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+        let binary = BE::read_u32(&digest[offset..offset + 4]) & 0x7fff_ffff;
+        let code = u64::from(binary) % 10u64.pow(self.digits);
+        format!("{:0width$}", code, width = self.digits as usize)
+    }
+
+    fn now(&self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+        (now + self.skew).max(0) as u64
+    }
+}
+
+//
+// Helper functions
+//
+
+fn handshake_error(message: String) -> WeechatError {
+    WeechatError::new(WeechatErrorType::InvalidHandshake, message, Backtrace::new())
+}
+
+fn decode_hex(input: &str) -> Option<Vec<u8>> {
+    if !input.len().is_multiple_of(2) || !input.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..input.len())
+        .step_by(2)
+        .map(|i| input.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
+
+fn encode_hex(input: &[u8]) -> String {
+    input.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::WeechatType;
+
+    // RFC 6238 appendix B, SHA-1
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    // The nonce of scenarios/basic.yaml in dingy-mock
+    const SALT: &str = "85b1ee00695a5b254e14f4885538df0d";
+
+    fn hash(algo: PasswordHashAlgo, iterations: u32) -> String {
+        let salt = decode_hex(SALT).unwrap();
+        PasswordHash::compute(algo, "hunter2", &salt, iterations).unwrap().encode()
+    }
+
+    #[test]
+    fn password_hash_vectors() {
+        let sha256 = "cb64d02d64a21aea20d72ac0d585640437eff69f028ba9a6bd321e6d863e66ce";
+        let sha512 = concat!(
+            "70c1c6238b8ddf77c5b5a973b34a8beea27aa1a0a2aaf4ac28d234a3009a3f8d",
+            "79a4c47c9504ce1943b088964c90ea5531ba5c127656c0887a67c9c8db86ea78"
+        );
+        let pbkdf2_sha256 = "09aa465298d175fbe66abed3fa093ac5e5ecc8e86c9cc7fd82c0de280ae45ff3";
+        let pbkdf2_sha512 = concat!(
+            "d855e9556f100a646baba9fc6c5e698183c11e7c430c5e9dd0f7de03c5962f81",
+            "b65d55159960f511aecef14b42099b0e9f60b75917653ccf349ea24a90a9cebc"
+        );
+
+        assert_eq!(hash(PasswordHashAlgo::Sha256, 0), format!("sha256:{}:{}", SALT, sha256));
+        assert_eq!(hash(PasswordHashAlgo::Sha512, 0), format!("sha512:{}:{}", SALT, sha512));
+        assert_eq!(
+            hash(PasswordHashAlgo::Pbkdf2Sha256, 1000),
+            format!("pbkdf2+sha256:{}:1000:{}", SALT, pbkdf2_sha256)
+        );
+        assert_eq!(
+            hash(PasswordHashAlgo::Pbkdf2Sha512, 1000),
+            format!("pbkdf2+sha512:{}:1000:{}", SALT, pbkdf2_sha512)
+        );
+        assert!(PasswordHash::compute(PasswordHashAlgo::Plain, "hunter2", &[], 0).is_none());
+    }
+
+    #[test]
+    fn password_hash_round_trips() {
+        for (algo, iterations) in [
+            (PasswordHashAlgo::Sha256, 0),
+            (PasswordHashAlgo::Sha512, 0),
+            (PasswordHashAlgo::Pbkdf2Sha256, 1000),
+            (PasswordHashAlgo::Pbkdf2Sha512, 1000),
+        ] {
+            let encoded = hash(algo, iterations);
+            let decoded = PasswordHash::decode(&encoded).unwrap();
+            assert_eq!(decoded.encode(), encoded);
+            assert!(decoded.verify("hunter2"));
+            assert!(!decoded.verify("hunter3"));
+        }
+
+        assert!(PasswordHash::decode("plain:00:00").is_none());
+        assert!(PasswordHash::decode("sha256:00:1000:00").is_none());
+        assert!(PasswordHash::decode("pbkdf2+sha256:00:many:00").is_none());
+        assert!(PasswordHash::decode("sha256:0g:00").is_none());
+    }
+
+    #[test]
+    fn password_hash_from_handshake() {
+        let mut response = HandshakeResponse {
+            password_hash_algo: Some(PasswordHashAlgo::Pbkdf2Sha512),
+            password_hash_iterations: 1000,
+            totp: false,
+            nonce: decode_hex(SALT).unwrap(),
+            compression: None,
+            escape_commands: false,
+        };
+        let hash = PasswordHash::from_handshake(&response, "hunter2").unwrap();
+        // The server nonce, then 16 bytes of our own
+        assert_eq!(hash.salt.len(), 32);
+        assert_eq!(hash.salt[..16], response.nonce[..]);
+        assert_eq!(hash.iterations, 1000);
+        assert!(hash.verify("hunter2"));
+
+        response.password_hash_algo = Some(PasswordHashAlgo::Plain);
+        assert!(PasswordHash::from_handshake(&response, "hunter2").is_none());
+        response.password_hash_algo = None;
+        assert!(PasswordHash::from_handshake(&response, "hunter2").is_none());
+    }
+
+    // A handshake reply with the given options, sent through a frame so the
+    // hashtable is decoded like the relay's
+    fn handshake(options: &[(&str, &str)]) -> Result<HandshakeResponse, WeechatError> {
+        let table = options
+            .iter()
+            .map(|(k, v)| (WeechatType::String((*k).into()), WeechatType::String((*v).into())))
+            .collect();
+        let message = Message::new("handshake".to_owned(), vec![WeechatType::HashTable(table)]);
+        let mut frame = Vec::new();
+        message.encode(&mut frame, CompressionType::None).unwrap();
+        HandshakeResponse::parse(&Message::parse(&mut &frame[..]).unwrap().unwrap())
+    }
+
+    #[test]
+    fn handshake_response() {
+        let response = handshake(&[
+            ("password_hash_algo", "pbkdf2+sha512"),
+            ("password_hash_iterations", "100000"),
+            ("totp", "on"),
+            ("nonce", SALT),
+            ("compression", "zstd"),
+            ("escape_commands", "on"),
+        ])
+        .unwrap();
+        assert_eq!(response.password_hash_algo, Some(PasswordHashAlgo::Pbkdf2Sha512));
+        assert_eq!(response.password_hash_iterations, 100_000);
+        assert!(response.totp);
+        assert_eq!(response.nonce[..4], [0x85, 0xb1, 0xee, 0x00]);
+        assert_eq!(response.nonce.len(), 16);
+        assert_eq!(response.compression, Some(CompressionType::Zstd));
+        assert!(response.escape_commands);
+
+        // Old relays only send some of the keys
+        let response = handshake(&[("password_hash_algo", "plain")]).unwrap();
+        assert_eq!(response.password_hash_algo, Some(PasswordHashAlgo::Plain));
+        assert_eq!(response.password_hash_iterations, 0);
+        assert!(!response.totp);
+        assert!(response.nonce.is_empty());
+        assert_eq!(response.compression, None);
+        assert!(!response.escape_commands);
+
+        let response = handshake(&[("password_hash_algo", ""), ("totp", "off")]).unwrap();
+        assert_eq!(response.password_hash_algo, None);
+        assert!(!response.totp);
+    }
+
+    #[test]
+    fn handshake_response_errors() {
+        let invalid = |options: &[(&str, &str)]| match handshake(options) {
+            Err(e) => assert!(matches!(e.error, WeechatErrorType::InvalidHandshake), "{:?}", e),
+            Ok(response) => panic!("{:?} gave {:?}", options, response),
+        };
+        invalid(&[("password_hash_algo", "md5")]);
+        invalid(&[("password_hash_iterations", "-1")]);
+        invalid(&[("password_hash_iterations", "many")]);
+        invalid(&[("password_hash_iterations", "5000000000")]);
+        invalid(&[("nonce", "85b")]);
+        invalid(&[("nonce", "zz")]);
+        invalid(&[("nonce", "+a")]);
+
+        let message = Message::new("handshake".to_owned(), vec![WeechatType::Int(1)]);
+        assert!(HandshakeResponse::parse(&message).is_err());
+        assert!(HandshakeResponse::parse(&Message::new(String::new(), vec![])).is_err());
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(decode_hex("00ff7F"), Some(vec![0x00, 0xff, 0x7f]));
+        assert_eq!(decode_hex(""), Some(vec![]));
+        assert_eq!(decode_hex("+a"), None);
+        assert_eq!(decode_hex("-1"), None);
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("é"), None);
+        assert_eq!(encode_hex(&[0x00, 0xff, 0x7f]), "00ff7f");
+    }
+
+    #[test]
+    fn totp_rfc_vectors() {
+        let totp = Totp::from_base32(SECRET).and_then(|t| t.with_digits(8)).unwrap();
+        assert_eq!(totp.generate_at(59), "94287082");
+        assert_eq!(totp.generate_at(1_111_111_109), "07081804");
+        assert_eq!(totp.generate_at(20_000_000_000), "65353130");
+    }
+
+    #[test]
+    fn totp_rejects_bad_settings() {
+        let totp = Totp::from_base32(SECRET).unwrap();
+        assert!(totp.clone().with_digits(0).is_none());
+        assert!(totp.clone().with_digits(11).is_none());
+        assert!(totp.clone().with_period(0).is_none());
+        let totp = totp.with_digits(10).and_then(|t| t.with_period(60)).unwrap();
+        assert_eq!((totp.digits(), totp.period()), (10, 60));
+        assert_eq!(totp.generate_at(59).len(), 10);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::auth::*;
 use crate::command::*;
 use crate::message::*;
 use crate::sync::*;
@@ -41,24 +42,66 @@ unsafe fn count_to_opt_hcl(count: *const i32) -> Option<HdataCommandLength> {
     })
 }
 
+// The builder's methods take it by value, C only has the pointer
+unsafe fn update_builder<F: FnOnce(HdataPath) -> HdataPath>(builder: *mut HdataPath, func: F) {
+    let updated = func(std::ptr::read(builder));
+    std::ptr::write(builder, updated);
+}
+
+/// Create a handshake command
+/// @param id: Id of command or null
+/// @param id_length: Length of id string
+/// @param password_hash_algos: List of supported hash algorithms or null
+/// @param password_hash_algos_length: Number of hash algorithms
+/// @param compressions: List of supported compression types or null
+/// @param compressions_length: Number of compression types
+/// @param escape_commands: Whether to enable escaped commands or null
+/// @param output: Output buffer
+/// @param output_length: Capacity of output buffer
+/// @return Number of bytes in full message (even if truncated)
+#[no_mangle]
+pub unsafe extern "C" fn command_handshake_print(id: *const u8, id_length: usize, password_hash_algos: *const PasswordHashAlgo, password_hash_algos_length: usize, compressions: *const CompressionType, compressions_length: usize, escape_commands: *const bool, output: *mut u8, output_length: usize) -> usize {
+    // Parameters
+    let id = str_from_raw(id, id_length);
+    let password_hash_algos = test_ptr(password_hash_algos).map(|algos| {
+        slice::from_raw_parts(algos, password_hash_algos_length).to_vec()
+    });
+    let compressions = test_ptr(compressions).map(|compressions| {
+        slice::from_raw_parts(compressions, compressions_length).to_vec()
+    });
+    let escape_commands = escape_commands.as_ref().cloned();
+
+    let command = HandshakeCommand::new(id, password_hash_algos, compressions, escape_commands);
+    let mut rbuf: Vec<u8> = vec![];
+    match command.encode(&mut Cursor::new(&mut rbuf)) {
+        Ok(_) => {
+            str_to_raw(Some(rbuf), output, output_length)
+        },
+        Err(_) => 0
+    }
+}
+
 /// Create an init command
 /// @param id: Id of command or null
 /// @param id_length: Length of id string
 /// @param password: Password for server or null
 /// @param password_length: Length of password string
+/// @param totp: One time password or null
+/// @param totp_length: Length of totp string
 /// @param compression: Compression type or null
 /// @param output: Output buffer
 /// @param output_length: Capacity of output buffer
 /// @return Number of bytes in full message (even if truncated)
 #[no_mangle]
-pub unsafe extern "C" fn command_init_print(id: *const u8, id_length: usize, password: *const u8, password_length: usize, compression: *const CompressionType, output: *mut u8, output_length: usize) -> usize {
+pub unsafe extern "C" fn command_init_print(id: *const u8, id_length: usize, password: *const u8, password_length: usize, totp: *const u8, totp_length: usize, compression: *const CompressionType, output: *mut u8, output_length: usize) -> usize {
     // Parameters
     let id = str_from_raw(id, id_length);
     let password = str_from_raw(password, password_length);
+    let totp = str_from_raw(totp, totp_length);
     let compression = compression.as_ref().cloned();
 
     // Print command
-    let command = InitCommand::new(id, password, compression);
+    let command = InitCommand::new(id, password, None, totp, compression);
     let mut rbuf: Vec<u8> = vec![];
     match command.encode(&mut Cursor::new(&mut rbuf)) {
         Ok(_) => {
@@ -136,6 +179,114 @@ pub unsafe extern "C" fn command_hdata_print(id: *const u8, id_length: usize, hd
     }
 }
 
+/// Start building an hdata command, see the other hdata_builder_* functions
+/// @param hdata: Name of hdata
+/// @param hdata_length: Length of hdata string
+/// @return Builder pointer, freed by hdata_builder_print or hdata_builder_free
+#[no_mangle]
+pub unsafe extern "C" fn hdata_builder_new(hdata: *const u8, hdata_length: usize) -> *mut HdataPath {
+    let hdata = str_from_raw(hdata, hdata_length).unwrap_or_default();
+    Box::leak(Box::new(HdataPath::new(hdata)))
+}
+
+/// Start an hdata path at a named list
+/// @param builder: Builder pointer
+/// @param name: Name of list, like gui_buffers
+/// @param name_length: Length of name string
+#[no_mangle]
+pub unsafe extern "C" fn hdata_builder_list(builder: *mut HdataPath, name: *const u8, name_length: usize) {
+    let name = str_from_raw(name, name_length).unwrap_or_default();
+    update_builder(builder, |b| b.list(name));
+}
+
+/// Start an hdata path at a pointer
+/// @param builder: Builder pointer
+/// @param pointer: Pointer value
+#[no_mangle]
+pub unsafe extern "C" fn hdata_builder_pointer(builder: *mut HdataPath, pointer: usize) {
+    update_builder(builder, |b| b.pointer(Pointer::new(pointer as u128)));
+}
+
+/// Follow a variable in an hdata path
+/// @param builder: Builder pointer
+/// @param var: Name of variable
+/// @param var_length: Length of var string
+#[no_mangle]
+pub unsafe extern "C" fn hdata_builder_then(builder: *mut HdataPath, var: *const u8, var_length: usize) {
+    let var = str_from_raw(var, var_length).unwrap_or_default();
+    update_builder(builder, |b| b.then(var));
+}
+
+/// Get every item from the last list, pointer or variable
+/// @param builder: Builder pointer
+#[no_mangle]
+pub unsafe extern "C" fn hdata_builder_all(builder: *mut HdataPath) {
+    update_builder(builder, |b| b.all());
+}
+
+/// Get count items from the last list, pointer or variable
+/// @param builder: Builder pointer
+/// @param count: Number of items, negative to go backwards (0 is invalid)
+#[no_mangle]
+pub unsafe extern "C" fn hdata_builder_count(builder: *mut HdataPath, count: i32) {
+    update_builder(builder, |b| b.count(count));
+}
+
+/// Only return some keys
+/// @param builder: Builder pointer
+/// @param keys: List of strings
+/// @param keys_lengths: List of lengths of keys
+/// @param keys_length: Number of keys
+#[no_mangle]
+pub unsafe extern "C" fn hdata_builder_keys(builder: *mut HdataPath, keys: *const *const u8, keys_lengths: *const usize, keys_length: usize) {
+    let keys = match (test_ptr(keys), test_ptr(keys_lengths)) {
+        (Some(keys), Some(keys_lengths)) => {
+            let s = slice::from_raw_parts(keys, keys_length);
+            let lengths = slice::from_raw_parts(keys_lengths, keys_length);
+
+            s.iter().zip(lengths.iter()).map(|(&arg, &length)| {
+                str_from_raw(arg, length).unwrap_or_default()
+            }).collect::<Vec<_>>()
+        },
+        _ => vec![]
+    };
+    update_builder(builder, |b| b.keys(keys));
+}
+
+/// Print the hdata command and free the builder
+/// @param builder: Builder pointer, invalid after this call
+/// @param id: Id of command or null
+/// @param id_length: Length of id string
+/// @param output: Output buffer
+/// @param output_length: Capacity of output buffer
+/// @return Number of bytes in full message (even if truncated), 0 if the path is invalid
+#[no_mangle]
+pub unsafe extern "C" fn hdata_builder_print(builder: *mut HdataPath, id: *const u8, id_length: usize, output: *mut u8, output_length: usize) -> usize {
+    let mut builder = *Box::from_raw(builder);
+    if let Some(id) = str_from_raw(id, id_length) {
+        builder = builder.id(id);
+    }
+    match builder.build() {
+        Ok(command) => {
+            let mut rbuf: Vec<u8> = vec![];
+            match command.encode(&mut Cursor::new(&mut rbuf)) {
+                Ok(_) => {
+                    str_to_raw(Some(rbuf), output, output_length)
+                },
+                Err(_) => 0
+            }
+        },
+        Err(_) => 0
+    }
+}
+
+/// Free a builder without printing it
+/// @param builder: Builder pointer
+#[no_mangle]
+pub unsafe extern "C" fn hdata_builder_free(builder: *mut HdataPath) {
+    drop(Box::from_raw(builder));
+}
+
 /// Create an info command
 /// @param id: Id of command or null
 /// @param id_length: Length of id string
@@ -270,6 +421,39 @@ pub unsafe extern "C" fn command_input_print(id: *const u8, id_length: usize, bu
     }
 }
 
+/// Create a completion command
+/// @param id: Id of command or null
+/// @param id_length: Length of id string
+/// @param buffer: Buffer for completion
+/// @param buffer_length: Length of buffer string
+/// @param position: Position in data to complete at, -1 for the end
+/// @param data: Buffer for data or null
+/// @param data_length: Length of data string
+/// @param output: Output buffer
+/// @param output_length: Capacity of output buffer
+/// @return Number of bytes in full message (even if truncated)
+#[no_mangle]
+pub unsafe extern "C" fn command_completion_print(id: *const u8, id_length: usize, buffer: *const u8, buffer_length: usize, position: i32, data: *const u8, data_length: usize, output: *mut u8, output_length: usize) -> usize {
+    // Parameters
+    let id = str_from_raw(id, id_length);
+    let buffer = str_from_raw(buffer, buffer_length);
+    let data = str_from_raw(data, data_length);
+
+    match buffer {
+        Some(buffer) => {
+            let command = CompletionCommand::new(id, buffer, position, data);
+            let mut rbuf: Vec<u8> = vec![];
+            match command.encode(&mut Cursor::new(&mut rbuf)) {
+                Ok(_) => {
+                    str_to_raw(Some(rbuf), output, output_length)
+                },
+                Err(_) => 0
+            }
+        },
+        None => 0
+    }
+}
+
 /// Create a sync command
 /// @param id: Id of command or null
 /// @param id_length: Length of id string
@@ -421,6 +605,19 @@ pub unsafe extern "C" fn command_quit_print(id: *const u8, id_length: usize, out
     }
 }
 
+/// Generate a TOTP code for the current time
+/// @param secret: Base32 encoded TOTP secret
+/// @param secret_length: Length of secret string
+/// @param output: Output buffer
+/// @param output_length: Capacity of output buffer
+/// @return Number of bytes in code (even if truncated), 0 if the secret is invalid
+#[no_mangle]
+pub unsafe extern "C" fn totp_generate(secret: *const u8, secret_length: usize, output: *mut u8, output_length: usize) -> usize {
+    let totp = str_from_raw(secret, secret_length).and_then(|secret| Totp::from_base32(&secret));
+
+    str_to_raw(totp.map(|totp| totp.generate().into_bytes()), output, output_length)
+}
+
 //-----------------------------------------------------------------------------
 
 /// Parse a message header
@@ -585,11 +782,11 @@ pub unsafe extern "C" fn weechat_type_int_get(weechat_type: *mut WeechatType) ->
 
 /// Get long value from a WeechatType::Long
 /// @param weechat_type: WeechatType pointer
-/// @return Long value
+/// @return Long value (64-bit, like WeeChat's longs)
 #[no_mangle]
-pub unsafe extern "C" fn weechat_type_long_get(weechat_type: *mut WeechatType) -> isize {
+pub unsafe extern "C" fn weechat_type_long_get(weechat_type: *mut WeechatType) -> i64 {
     match *weechat_type {
-        WeechatType::Long(l) => l as isize,
+        WeechatType::Long(l) => l as i64,
         _ => 0
     }
 }
@@ -597,7 +794,7 @@ pub unsafe extern "C" fn weechat_type_long_get(weechat_type: *mut WeechatType) -
 /// Get string value from a WeechatType::String
 /// @param weechat_type: WeechatType pointer
 /// @param length: Pointer to length of string
-/// @return String buffer (not null-terminated)
+/// @return String buffer (not null-terminated), the bytes the relay sent which may not be UTF-8
 #[no_mangle]
 pub unsafe extern "C" fn weechat_type_string_get(weechat_type: *mut WeechatType, length: *mut usize) -> *const u8 {
     match &*weechat_type {
@@ -612,6 +809,48 @@ pub unsafe extern "C" fn weechat_type_string_get(weechat_type: *mut WeechatType,
     }
 }
 
+/// Check whether a WeechatType::String is the null string
+/// @param weechat_type: WeechatType pointer
+/// @return True for the null string (false for other types)
+#[no_mangle]
+pub unsafe extern "C" fn weechat_type_string_is_null(weechat_type: *mut WeechatType) -> bool {
+    match &*weechat_type {
+        WeechatType::String(s) => s.is_null(),
+        _ => false
+    }
+}
+
+/// Copy a WeechatType::String as UTF-8, replacing invalid sequences with U+FFFD
+/// @param weechat_type: WeechatType pointer
+/// @param output: Output buffer
+/// @param output_length: Capacity of output buffer
+/// @return Number of bytes copied (0 for the null string)
+#[no_mangle]
+pub unsafe extern "C" fn weechat_type_string_lossy(weechat_type: *mut WeechatType, output: *mut u8, output_length: usize) -> usize {
+    match &*weechat_type {
+        WeechatType::String(s) => {
+            let lossy = s.to_string_lossy().map(|s| s.into_owned().into_bytes());
+            str_to_raw(lossy, output, output_length)
+        },
+        _ => 0
+    }
+}
+
+/// Remove WeeChat color and attribute codes from a string, like a line's prefix or message
+/// @param input: String with color codes (invalid UTF-8 is replaced with U+FFFD)
+/// @param input_length: Length of input string
+/// @param output: Output buffer
+/// @param output_length: Capacity of output buffer
+/// @return Number of bytes copied
+#[no_mangle]
+pub unsafe extern "C" fn color_strip(input: *const u8, input_length: usize, output: *mut u8, output_length: usize) -> usize {
+    let plain = test_ptr(input).map(|input| {
+        let input = String::from_utf8_lossy(slice::from_raw_parts(input, input_length));
+        crate::color::strip(&input).into_bytes()
+    });
+    str_to_raw(plain, output, output_length)
+}
+
 /// Get buffer value from a WeechatType::Buffer
 /// @param weechat_type: WeechatType pointer
 /// @param length: Pointer to length of buffer
@@ -636,18 +875,31 @@ pub unsafe extern "C" fn weechat_type_buffer_get(weechat_type: *mut WeechatType,
 #[no_mangle]
 pub unsafe extern "C" fn weechat_type_pointer_get(weechat_type: *mut WeechatType) -> usize {
     match *weechat_type {
-        WeechatType::Pointer(p) => p as usize,
+        WeechatType::Pointer(p) => p.address() as usize,
+        _ => 0
+    }
+}
+
+/// Print a WeechatType::Pointer in the 0x form commands use
+/// @param weechat_type: WeechatType pointer
+/// @param output: Output buffer
+/// @param output_length: Capacity of output buffer
+/// @return Number of bytes copied (0 if not a pointer)
+#[no_mangle]
+pub unsafe extern "C" fn weechat_type_pointer_print(weechat_type: *mut WeechatType, output: *mut u8, output_length: usize) -> usize {
+    match *weechat_type {
+        WeechatType::Pointer(p) => str_to_raw(Some(p.to_string().into_bytes()), output, output_length),
         _ => 0
     }
 }
 
 /// Get time value from a WeechatType::Time
 /// @param weechat_type: WeechatType pointer
-/// @return Time value
+/// @return Time value (seconds since the Unix epoch)
 #[no_mangle]
-pub unsafe extern "C" fn weechat_type_time_get(weechat_type: *mut WeechatType) -> usize {
+pub unsafe extern "C" fn weechat_type_time_get(weechat_type: *mut WeechatType) -> i64 {
     match *weechat_type {
-        WeechatType::Time(t) => t as usize,
+        WeechatType::Time(t) => t.secs(),
         _ => 0
     }
 }
@@ -0,0 +1,527 @@
+// Colors and attributes embedded in relay strings (line prefixes and
+// messages, buffer titles, nicklist prefixes...). WeeChat encodes them as:
+//   \x19 + code   set colors, see Parser::color for the forms
+//   \x1A + attr   set an attribute
+//   \x1B + attr   remove an attribute
+//   \x1C          reset colors and attributes
+// Unknown or truncated sequences lose their control character, whatever
+// follows it is kept as text.
+
+const COLOR: u8 = 0x19;
+const SET_ATTR: u8 = 0x1A;
+const REMOVE_ATTR: u8 = 0x1B;
+const RESET: u8 = 0x1C;
+
+// WeeChat's basic colors, in the order of their two digit codes
+pub const BASIC_COLORS: &[&str] = &[
+    "default",
+    "black",
+    "darkgray",
+    "red",
+    "lightred",
+    "green",
+    "lightgreen",
+    "brown",
+    "yellow",
+    "blue",
+    "lightblue",
+    "magenta",
+    "lightmagenta",
+    "cyan",
+    "lightcyan",
+    "gray",
+    "white",
+];
+
+// weechat.color.* options, in the order of their two digit codes. Codes
+// 17 to 26 were nick colors and haven't been sent since WeeChat 0.3.4.
+pub const OPTION_NAMES: &[&str] = &[
+    "separator",
+    "chat",
+    "chat_time",
+    "chat_time_delimiters",
+    "chat_prefix_error",
+    "chat_prefix_network",
+    "chat_prefix_action",
+    "chat_prefix_join",
+    "chat_prefix_quit",
+    "chat_prefix_more",
+    "chat_prefix_suffix",
+    "chat_buffer",
+    "chat_server",
+    "chat_channel",
+    "chat_nick",
+    "chat_nick_self",
+    "chat_nick_other",
+    "",
+    "",
+    "",
+    "",
+    "",
+    "",
+    "",
+    "",
+    "",
+    "",
+    "chat_host",
+    "chat_delimiters",
+    "chat_highlight",
+    "chat_read_marker",
+    "chat_text_found",
+    "chat_value",
+    "chat_prefix_buffer",
+    "chat_tags",
+    "chat_inactive_window",
+    "chat_inactive_buffer",
+    "chat_prefix_buffer_inactive_buffer",
+    "chat_nick_offline",
+    "chat_nick_offline_highlight",
+    "chat_nick_prefix",
+    "chat_nick_suffix",
+    "emphasized",
+    "chat_day_change",
+    "chat_value_null",
+];
+
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Hash)]
+pub enum Color {
+    // The terminal's own color
+    Default,
+    // Index into BASIC_COLORS, 1 to 16
+    Basic(u8),
+    // Terminal color number, 0 to 255 on most terminals
+    Extended(u32),
+    // Whatever the user set the option at this index of OPTION_NAMES to
+    Option(u8),
+    // ncurses color pair, which sets both foreground and background
+    Pair(u32),
+}
+
+impl Color {
+    // "lightred" for basic colors, "chat_nick" for option colors
+    pub fn name(&self) -> Option<&'static str> {
+        let name = match self {
+            Color::Default => Some("default"),
+            Color::Basic(i) => BASIC_COLORS.get(*i as usize).copied(),
+            Color::Option(i) => OPTION_NAMES.get(*i as usize).copied(),
+            _ => None,
+        };
+        name.filter(|name| !name.is_empty())
+    }
+
+    // A color as written in WeeChat's options: a basic color name or a
+    // terminal color number
+    pub fn from_name(name: &str) -> Option<Color> {
+        match BASIC_COLORS.iter().position(|basic| *basic == name) {
+            Some(0) => Some(Color::Default),
+            Some(i) => Some(Color::Basic(i as u8)),
+            None => name.parse().ok().map(Color::Extended),
+        }
+    }
+
+    // Option color for a weechat.color.* option name like "chat_nick"
+    pub fn from_option_name(name: &str) -> Option<Color> {
+        if name.is_empty() {
+            return None;
+        }
+        let i = OPTION_NAMES.iter().position(|option| *option == name)?;
+        Some(Color::Option(i as u8))
+    }
+}
+
+// None colors are the ones the text would have without any codes
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default, Hash)]
+pub struct Style {
+    pub fg: Option<Color>,
+    pub bg: Option<Color>,
+    pub bold: bool,
+    pub reverse: bool,
+    pub italic: bool,
+    pub underline: bool,
+}
+
+impl Style {
+    pub fn is_plain(&self) -> bool {
+        *self == Style::default()
+    }
+
+    fn reset_attributes(&mut self) {
+        self.bold = false;
+        self.reverse = false;
+        self.italic = false;
+        self.underline = false;
+    }
+
+    // Returns false for characters that aren't attributes
+    fn set_attribute(&mut self, attr: u8, value: bool) -> bool {
+        match attr {
+            b'*' | 0x01 => self.bold = value,
+            b'!' | 0x02 => self.reverse = value,
+            b'/' | 0x03 => self.italic = value,
+            b'_' | 0x04 => self.underline = value,
+            _ => return false,
+        }
+        true
+    }
+}
+
+// A run of text drawn with the same style
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct Span {
+    pub text: String,
+    pub style: Style,
+}
+
+// Split a string into styled spans, dropping the codes. Empty spans are
+// skipped and neighbours with the same style are merged.
+pub fn parse(s: &str) -> Vec<Span> {
+    let mut spans: Vec<Span> = Vec::new();
+    let mut parser = Parser { s, pos: 0, style: Style::default() };
+    while let Some(text) = parser.next_text() {
+        if text.is_empty() {
+            continue;
+        }
+        match spans.last_mut() {
+            Some(last) if last.style == parser.style => last.text.push_str(text),
+            _ => spans.push(Span { text: text.to_owned(), style: parser.style }),
+        }
+    }
+    spans
+}
+
+// Just the text, without any colors or attributes
+pub fn strip(s: &str) -> String {
+    let mut parser = Parser { s, pos: 0, style: Style::default() };
+    let mut plain = String::with_capacity(s.len());
+    while let Some(text) = parser.next_text() {
+        plain.push_str(text);
+    }
+    plain
+}
+
+// Every code is ASCII so the string can be sliced at any of them
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+    style: Style,
+}
+
+impl<'a> Parser<'a> {
+    // Apply codes until the next piece of text, which has the style
+    // parser.style. None at the end of the string.
+    fn next_text(&mut self) -> Option<&'a str> {
+        loop {
+            match self.bump()? {
+                COLOR => self.color(),
+                SET_ATTR => self.attribute(true),
+                REMOVE_ATTR => self.attribute(false),
+                RESET => self.style = Style::default(),
+                _ => {
+                    let start = self.pos - 1;
+                    let rest = &self.s.as_bytes()[start..];
+                    let len =
+                        rest.iter().position(|b| is_code(*b)).unwrap_or(rest.len());
+                    self.pos = start + len;
+                    return Some(&self.s[start..self.pos]);
+                }
+            }
+        }
+    }
+
+    // After a \x19:
+    //   STD                 color option
+    //   @EXT                color pair
+    //   F (ATTR) STD|@EXT   foreground, with attributes
+    //   B STD|@EXT          background
+    //   * (ATTR) STD|@EXT   foreground, then maybe , or ~ and a background
+    //   b + one char        bar codes, only used inside bars
+    //   E                   emphasis, for text search
+    //   \x1C                reset colors, keeping attributes
+    // where STD is two digits, EXT is five digits and ATTR is any of *!/_|
+    fn color(&mut self) {
+        let start = self.pos;
+        if !self.color_code() {
+            self.pos = start;
+        }
+    }
+
+    // False if the code is unknown or incomplete
+    fn color_code(&mut self) -> bool {
+        let code = match self.bump() {
+            Some(code) => code,
+            None => return false,
+        };
+        match code {
+            b'0'..=b'9' => {
+                self.pos -= 1;
+                let option = match self.digits(2) {
+                    Some(option) => option,
+                    None => return false,
+                };
+                self.style.fg = Some(Color::Option(option as u8));
+                self.style.reset_attributes();
+            }
+            b'@' => {
+                let pair = match self.digits(5) {
+                    Some(pair) => pair,
+                    None => return false,
+                };
+                self.style.fg = Some(Color::Pair(pair));
+                self.style.bg = None;
+            }
+            b'F' | b'*' => {
+                if !self.foreground() {
+                    return false;
+                }
+                if code == b'*' {
+                    self.separated_background();
+                }
+            }
+            b'B' => match self.color_number() {
+                Some(bg) => self.style.bg = Some(bg),
+                None => return false,
+            },
+            b'b' => {
+                if self.peek().is_some_and(|c| c.is_ascii()) {
+                    self.pos += 1;
+                }
+            }
+            b'E' => {}
+            RESET => {
+                self.style.fg = None;
+                self.style.bg = None;
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn foreground(&mut self) -> bool {
+        let mut attributes = Style::default();
+        let mut keep = false;
+        while let Some(attr) = self.peek() {
+            if attr == b'|' {
+                keep = true;
+            } else if !attributes.set_attribute(attr, true) {
+                break;
+            }
+            self.pos += 1;
+        }
+        let fg = match self.color_number() {
+            Some(fg) => fg,
+            None => return false,
+        };
+        if !keep {
+            self.style.reset_attributes();
+        }
+        self.style.fg = Some(fg);
+        self.style.bold |= attributes.bold;
+        self.style.reverse |= attributes.reverse;
+        self.style.italic |= attributes.italic;
+        self.style.underline |= attributes.underline;
+        true
+    }
+
+    // The separator is only a code if a color follows, a bare , is text
+    fn separated_background(&mut self) {
+        if let Some(b',') | Some(b'~') = self.peek() {
+            let start = self.pos;
+            self.pos += 1;
+            match self.color_number() {
+                Some(bg) => self.style.bg = Some(bg),
+                None => self.pos = start,
+            }
+        }
+    }
+
+    fn attribute(&mut self, value: bool) {
+        if let Some(attr) = self.peek() {
+            if attr == b'|' || self.style.set_attribute(attr, value) {
+                self.pos += 1;
+            }
+        }
+    }
+
+    // STD as a basic color or @EXT as an extended one
+    fn color_number(&mut self) -> Option<Color> {
+        if self.peek() == Some(b'@') {
+            let start = self.pos;
+            self.pos += 1;
+            let color = self.digits(5).map(Color::Extended);
+            if color.is_none() {
+                self.pos = start;
+            }
+            color
+        } else {
+            self.digits(2).map(|n| match n {
+                1..=16 => Color::Basic(n as u8),
+                _ => Color::Default,
+            })
+        }
+    }
+
+    // Exactly count decimal digits, or nothing is consumed
+    fn digits(&mut self, count: usize) -> Option<u32> {
+        let digits = self.s.as_bytes().get(self.pos..self.pos + count)?;
+        if !digits.iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+        self.pos += count;
+        Some(digits.iter().fold(0, |n, d| n * 10 + u32::from(d - b'0')))
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.s.as_bytes().get(self.pos).copied()
+    }
+
+    fn bump(&mut self) -> Option<u8> {
+        let byte = self.peek()?;
+        self.pos += 1;
+        Some(byte)
+    }
+}
+
+//
+// Helper functions
+//
+
+fn is_code(byte: u8) -> bool {
+    byte == COLOR || byte == SET_ATTR || byte == REMOVE_ATTR || byte == RESET
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(text: &str, style: Style) -> Span {
+        Span { text: text.to_owned(), style }
+    }
+
+    fn fg(color: Color) -> Style {
+        Style { fg: Some(color), ..Style::default() }
+    }
+
+    // The style of the only span in s
+    fn style(s: &str) -> Style {
+        match parse(s).as_slice() {
+            [span] => span.style,
+            spans => panic!("Expected one span in {:?}, got {:?}", s, spans),
+        }
+    }
+
+    #[test]
+    fn option_colors() {
+        assert_eq!(parse("\x1914nick"), vec![span("nick", fg(Color::Option(14)))]);
+        // Options reset attributes
+        assert_eq!(style("\x1A*\x1928x"), fg(Color::Option(28)));
+        assert_eq!(Color::Option(14).name(), Some("chat_nick"));
+        assert_eq!(Color::Option(20).name(), None);
+        assert_eq!(Color::from_option_name("chat_nick"), Some(Color::Option(14)));
+        assert_eq!(Color::from_option_name(""), None);
+    }
+
+    #[test]
+    fn pairs() {
+        let style = style("\x19B02\x19@00123x");
+        assert_eq!(style, fg(Color::Pair(123)));
+    }
+
+    #[test]
+    fn foregrounds_with_attributes() {
+        let bold_red = Style { bold: true, reverse: true, ..fg(Color::Basic(3)) };
+        assert_eq!(style("\x19F*!03x"), bold_red);
+        assert_eq!(style("\x19F@00200x"), fg(Color::Extended(200)));
+        assert_eq!(style("\x19F00x"), fg(Color::Default));
+        assert_eq!(Color::Basic(3).name(), Some("red"));
+        assert_eq!(Color::from_name("lightred"), Some(Color::Basic(4)));
+        assert_eq!(Color::from_name("default"), Some(Color::Default));
+        assert_eq!(Color::from_name("214"), Some(Color::Extended(214)));
+
+        // Attributes go unless | keeps them
+        assert_eq!(style("\x1A/\x19F05x"), fg(Color::Basic(5)));
+        let italic = Style { italic: true, underline: true, ..fg(Color::Basic(5)) };
+        assert_eq!(style("\x1A/\x19F|_05x"), italic);
+    }
+
+    #[test]
+    fn backgrounds() {
+        let style = style("\x19F05\x19B@00017x");
+        assert_eq!(style, Style { bg: Some(Color::Extended(17)), ..fg(Color::Basic(5)) });
+    }
+
+    #[test]
+    fn foreground_and_background() {
+        let both = Style { bg: Some(Color::Basic(2)), ..fg(Color::Basic(5)) };
+        assert_eq!(style("\x19*05,02x"), both);
+        let both = Style { bg: Some(Color::Extended(100)), ..fg(Color::Basic(5)) };
+        assert_eq!(style("\x19*05~@00100x"), both);
+        let bold = Style { bold: true, ..fg(Color::Basic(5)) };
+        assert_eq!(style("\x19**05x"), bold);
+        // A separator without a color is text
+        assert_eq!(parse("\x19*05,x"), vec![span(",x", fg(Color::Basic(5)))]);
+    }
+
+    #[test]
+    fn bar_codes_and_emphasis_are_dropped() {
+        assert_eq!(parse("\x19bFa\x19b_b\x19Ec"), vec![span("abc", Style::default())]);
+    }
+
+    #[test]
+    fn color_reset_keeps_attributes() {
+        let spans = parse("\x19*05,02\x1A*a\x19\x1Cb");
+        let bold = Style { bold: true, ..Style::default() };
+        let colored = Style { bg: Some(Color::Basic(2)), ..fg(Color::Basic(5)) };
+        assert_eq!(spans, vec![span("a", Style { bold: true, ..colored }), span("b", bold)]);
+    }
+
+    #[test]
+    fn attributes() {
+        let all = Style {
+            bold: true,
+            reverse: true,
+            italic: true,
+            underline: true,
+            ..Style::default()
+        };
+        assert_eq!(style("\x1A*\x1A!\x1A/\x1A_x"), all);
+        assert_eq!(style("\x1A\x01\x1A\x02\x1A\x03\x1A\x04x"), all);
+
+        let spans = parse("\x1A*\x1A_a\x1B*b\x1B\x04c\x1Cd");
+        let underline = Style { underline: true, ..Style::default() };
+        assert_eq!(
+            spans,
+            vec![
+                span("a", Style { bold: true, ..underline }),
+                span("b", underline),
+                span("cd", Style::default()),
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_codes_are_text() {
+        let plain = |text: &str| vec![span(text, Style::default())];
+        assert_eq!(parse("a\x19"), plain("a"));
+        assert_eq!(parse("\x195"), plain("5"));
+        assert_eq!(parse("\x19@12x"), plain("@12x"));
+        assert_eq!(parse("\x19F"), plain("F"));
+        assert_eq!(parse("\x19F*0"), plain("F*0"));
+        assert_eq!(parse("\x19*05~"), vec![span("~", fg(Color::Basic(5)))]);
+        assert_eq!(parse("\x19Zx"), plain("Zx"));
+        assert_eq!(parse("\x1Ax\x1A"), plain("x"));
+    }
+
+    #[test]
+    fn spans_merge_and_skip_empty_ones() {
+        assert_eq!(parse(""), vec![]);
+        assert_eq!(parse("\x1A*\x1B*"), vec![]);
+        assert_eq!(parse("a\x1A*\x1B*b\x19F05\x1Cc"), vec![span("abc", Style::default())]);
+        assert!(style("\x1A*\x1B*a").is_plain());
+    }
+
+    #[test]
+    fn strip_drops_every_code() {
+        assert_eq!(strip("\x19F05he\x1A*l\x19*05,02l\x1B*o\x1C \x19bFworld\x19"), "hello world");
+        assert_eq!(strip("caf\u{e9} \x1914\u{2603}"), "caf\u{e9} \u{2603}");
+    }
+}
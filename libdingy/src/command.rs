@@ -1,3 +1,6 @@
+use crate::auth::PasswordHash;
+use crate::message::Pointer;
+use backtrace::Backtrace;
 use std::io::Error;
 use std::io::Write;
 use std::option::Option;
@@ -5,30 +8,38 @@ use std::result::Result;
 use std::string::String;
 use std::vec::Vec;
 
-#[derive(Copy)]
+#[derive(Copy, Debug, Eq, PartialEq)]
 pub enum CommandType {
+    Handshake,
     Init,
     Hdata,
     Info,
     Infolist,
     Nicklist,
     Input,
+    Completion,
     Sync,
     Desync,
+    Test,
+    Ping,
     Quit,
 }
 
 impl CommandType {
     pub fn as_str(&self) -> &str {
         match self {
+            CommandType::Handshake => "handshake",
             CommandType::Init => "init",
             CommandType::Hdata => "hdata",
             CommandType::Info => "info",
             CommandType::Infolist => "infolist",
             CommandType::Nicklist => "nicklist",
             CommandType::Input => "input",
+            CommandType::Completion => "completion",
             CommandType::Sync => "sync",
             CommandType::Desync => "desync",
+            CommandType::Test => "test",
+            CommandType::Ping => "ping",
             CommandType::Quit => "quit",
         }
     }
@@ -45,6 +56,12 @@ pub trait Command {
     fn set_id(&mut self, id: Option<String>);
     fn encode(&self, out: &mut dyn Write) -> Result<usize, Error>;
     fn has_response(&self) -> bool;
+
+    // Pings are answered as _pong with their arguments rather than their id,
+    // so their reply can only be told apart by this
+    fn pong_token(&self) -> Option<String> {
+        None
+    }
 }
 
 pub trait CommandString {
@@ -61,11 +78,12 @@ where
     }
 }
 
-#[derive(Copy)]
+#[derive(Copy, Debug, Eq, PartialEq)]
 #[repr(C)]
 pub enum CompressionType {
     None,
     Zlib,
+    Zstd,
 }
 
 impl Clone for CompressionType {
@@ -79,40 +97,157 @@ impl CompressionType {
         match self {
             CompressionType::None => "none",
             CompressionType::Zlib => "zlib",
+            CompressionType::Zstd => "zstd",
+        }
+    }
+}
+
+impl CompressionType {
+    // Handshake option values differ from init: no compression is "off"
+    pub fn as_handshake_str(self) -> &'static str {
+        match self {
+            CompressionType::None => "off",
+            CompressionType::Zlib => "zlib",
+            CompressionType::Zstd => "zstd",
+        }
+    }
+
+    pub fn from_handshake_str(value: &str) -> Option<CompressionType> {
+        match value {
+            "off" => Some(CompressionType::None),
+            "zlib" => Some(CompressionType::Zlib),
+            "zstd" => Some(CompressionType::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<CompressionType> {
+        match value {
+            "none" | "off" => Some(CompressionType::None),
+            "zlib" => Some(CompressionType::Zlib),
+            "zstd" => Some(CompressionType::Zstd),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(C)]
+pub enum PasswordHashAlgo {
+    Plain,
+    Sha256,
+    Sha512,
+    Pbkdf2Sha256,
+    Pbkdf2Sha512,
+}
+
+impl PasswordHashAlgo {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PasswordHashAlgo::Plain => "plain",
+            PasswordHashAlgo::Sha256 => "sha256",
+            PasswordHashAlgo::Sha512 => "sha512",
+            PasswordHashAlgo::Pbkdf2Sha256 => "pbkdf2+sha256",
+            PasswordHashAlgo::Pbkdf2Sha512 => "pbkdf2+sha512",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<PasswordHashAlgo> {
+        match value {
+            "plain" => Some(PasswordHashAlgo::Plain),
+            "sha256" => Some(PasswordHashAlgo::Sha256),
+            "sha512" => Some(PasswordHashAlgo::Sha512),
+            "pbkdf2+sha256" => Some(PasswordHashAlgo::Pbkdf2Sha256),
+            "pbkdf2+sha512" => Some(PasswordHashAlgo::Pbkdf2Sha512),
+            _ => None,
         }
     }
 }
 
-#[derive(Constructor)]
+#[derive(Constructor, Debug)]
+pub struct HandshakeCommand {
+    pub id: Option<String>,
+    pub password_hash_algo: Option<Vec<PasswordHashAlgo>>,
+    pub compression: Option<Vec<CompressionType>>,
+    pub escape_commands: Option<bool>,
+}
+
+impl HandshakeCommand {
+    fn encode(&self, out: &mut dyn Write) -> Result<usize, Error> {
+        let mut res = handle_id(&self.id);
+        res.push_str("handshake");
+
+        let mut options: Vec<String> = vec![];
+        if let Some(algos) = &self.password_hash_algo {
+            let algos: Vec<&str> = algos.iter().map(|a| a.as_str()).collect();
+            options.push(format!("password_hash_algo={}", algos.join(":")));
+        }
+        if let Some(compression) = &self.compression {
+            let compression: Vec<&str> =
+                compression.iter().map(|c| c.as_handshake_str()).collect();
+            options.push(format!("compression={}", compression.join(":")));
+        }
+        if let Some(escape_commands) = self.escape_commands {
+            options.push(format!(
+                "escape_commands={}",
+                if escape_commands { "on" } else { "off" }
+            ));
+        }
+        if !options.is_empty() {
+            res = format!("{} {}", res, options.join(","));
+        }
+        res.push('\n');
+        out.write(res.as_bytes())
+    }
+}
+
+impl Command for HandshakeCommand {
+    fn get_id(&self) -> Option<String> {
+        self.id.clone()
+    }
+
+    fn set_id(&mut self, id: Option<String>) {
+        self.id = id;
+    }
+
+    fn encode(&self, out: &mut dyn Write) -> Result<usize, Error> {
+        self.encode(out)
+    }
+
+    fn has_response(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Constructor, Debug)]
 pub struct InitCommand {
-    id: Option<String>,
-    password: Option<String>,
-    compression: Option<CompressionType>,
+    pub id: Option<String>,
+    pub password: Option<String>,
+    pub password_hash: Option<PasswordHash>,
+    pub totp: Option<String>,
+    pub compression: Option<CompressionType>,
 }
 
 impl InitCommand {
     fn encode(&self, out: &mut dyn Write) -> Result<usize, Error> {
         let mut res = handle_id(&self.id);
         res.push_str("init");
-        if self.password.is_some() || self.compression.is_some() {
-            res.push_str(" ");
-            if self.password.is_some() {
-                res = format!(
-                    "{}password={}",
-                    res,
-                    escape_password(&self.password.clone().unwrap())
-                );
-            }
-            if self.compression.is_some() {
-                if self.password.is_some() {
-                    res.push_str(",")
-                }
-                res = format!(
-                    "{}compression={}",
-                    res,
-                    self.compression.unwrap().as_str()
-                );
-            }
+
+        let mut options: Vec<String> = vec![];
+        if let Some(password) = &self.password {
+            options.push(format!("password={}", escape_password(password)));
+        }
+        if let Some(password_hash) = &self.password_hash {
+            options.push(format!("password_hash={}", password_hash.encode()));
+        }
+        if let Some(totp) = &self.totp {
+            options.push(format!("totp={}", totp));
+        }
+        if let Some(compression) = self.compression {
+            options.push(format!("compression={}", compression.as_str()));
+        }
+        if !options.is_empty() {
+            res = format!("{} {}", res, options.join(","));
         }
         res.push_str("\n");
         out.write(res.as_bytes())
@@ -137,18 +272,19 @@ impl Command for InitCommand {
     }
 }
 
+#[derive(Debug, Eq, PartialEq)]
 pub enum HdataCommandLength {
     Infinite,
     Finite(i32),
 }
 
-#[derive(Constructor)]
+#[derive(Constructor, Debug)]
 pub struct HdataCommand {
-    id: Option<String>,
-    hdata: String,
-    pointer: (String, Option<HdataCommandLength>),
-    var: Vec<(String, Option<HdataCommandLength>)>,
-    keys: Option<Vec<String>>,
+    pub id: Option<String>,
+    pub hdata: String,
+    pub pointer: (String, Option<HdataCommandLength>),
+    pub var: Vec<(String, Option<HdataCommandLength>)>,
+    pub keys: Option<Vec<String>>,
 }
 
 impl HdataCommand {
@@ -201,10 +337,186 @@ impl Command for HdataCommand {
     }
 }
 
-#[derive(Constructor)]
-pub struct InfoCommand {
+// Checked way to put together an hdata command, for example
+//   HdataPath::buffer().list("gui_buffers").all().then("own_lines")
+//       .last_line(-50).then("data").keys(["date", "message"]).build()
+// The first mistake in the chain is kept and returned by build.
+#[derive(Debug)]
+pub struct HdataPath {
     id: Option<String>,
-    name: String,
+    hdata: String,
+    pointer: Option<(String, Option<HdataCommandLength>)>,
+    var: Vec<(String, Option<HdataCommandLength>)>,
+    keys: Option<Vec<String>>,
+    error: Option<CommandError>,
+}
+
+impl HdataPath {
+    pub fn new<S: Into<String>>(hdata: S) -> HdataPath {
+        let mut path = HdataPath {
+            id: None,
+            hdata: hdata.into(),
+            pointer: None,
+            var: vec![],
+            keys: None,
+            error: None,
+        };
+        let hdata = path.hdata.clone();
+        path.check_name(&hdata, "name");
+        path
+    }
+
+    pub fn buffer() -> HdataPath {
+        HdataPath::new("buffer")
+    }
+
+    pub fn window() -> HdataPath {
+        HdataPath::new("window")
+    }
+
+    pub fn hotlist() -> HdataPath {
+        HdataPath::new("hotlist")
+    }
+
+    pub fn id<S: Into<String>>(mut self, id: S) -> HdataPath {
+        self.id = Some(id.into());
+        self
+    }
+
+    // Start at a named list, like gui_buffers
+    pub fn list<S: Into<String>>(mut self, name: S) -> HdataPath {
+        let name = name.into();
+        self.check_name(&name, "list name");
+        self.start(name)
+    }
+
+    // Start at an object, the pointer is sent in the relay's 0x form
+    pub fn pointer(mut self, pointer: Pointer) -> HdataPath {
+        if pointer.is_null() {
+            self.fail("Hdata path can't start at a null pointer".to_owned());
+        }
+        self.start(pointer.to_string())
+    }
+
+    // Follow the variable var of the current object
+    pub fn then<S: Into<String>>(mut self, var: S) -> HdataPath {
+        let var = var.into();
+        self.check_name(&var, "variable name");
+        if self.pointer.is_none() {
+            self.fail(format!("Hdata path needs a list or pointer before {}", var));
+        }
+        if self.keys.is_some() {
+            self.fail(format!("Hdata keys only go on the last element, not before {}", var));
+        }
+        self.var.push((var, None));
+        self
+    }
+
+    // Every item from the current position on
+    pub fn all(self) -> HdataPath {
+        self.set_count(HdataCommandLength::Infinite)
+    }
+
+    // count items forwards from the current position, or backwards if negative
+    pub fn count(mut self, count: i32) -> HdataPath {
+        if count == 0 {
+            self.fail("Hdata count can't be 0, use all() for every item".to_owned());
+            return self;
+        }
+        self.set_count(HdataCommandLength::Finite(count))
+    }
+
+    pub fn first_line(self, count: i32) -> HdataPath {
+        self.then("first_line").count(count)
+    }
+
+    pub fn last_line(self, count: i32) -> HdataPath {
+        self.then("last_line").count(count)
+    }
+
+    // Only return these keys, all of them are returned otherwise
+    pub fn keys<I, S>(mut self, keys: I) -> HdataPath
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let keys: Vec<String> = keys.into_iter().map(|key| key.into()).collect();
+        if keys.is_empty() {
+            self.fail("Hdata keys can't be empty, leave them out for all".to_owned());
+        }
+        if self.keys.is_some() {
+            self.fail("Hdata keys were given twice".to_owned());
+        }
+        for key in &keys {
+            self.check_name(key, "key");
+        }
+        self.keys = Some(keys);
+        self
+    }
+
+    pub fn build(self) -> Result<HdataCommand, CommandError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        let pointer = match self.pointer {
+            Some(pointer) => pointer,
+            None => {
+                return Err(command_error(
+                    CommandErrorType::MissingArgument,
+                    format!("Hdata path for {} needs a list or pointer", self.hdata),
+                ));
+            }
+        };
+        Ok(HdataCommand::new(self.id, self.hdata, pointer, self.var, self.keys))
+    }
+
+    fn start(mut self, pointer: String) -> HdataPath {
+        if self.pointer.is_some() {
+            self.fail(format!("Hdata path already has a start before {}", pointer));
+        }
+        self.pointer = Some((pointer, None));
+        self
+    }
+
+    fn set_count(mut self, length: HdataCommandLength) -> HdataPath {
+        let segment = match self.var.last_mut() {
+            Some(var) => Some(var),
+            None => self.pointer.as_mut(),
+        };
+        let error = match segment {
+            None => Some("Hdata path needs a start before a count".to_owned()),
+            Some((name, Some(_))) => Some(format!("{} already has a count", name)),
+            Some((_, count)) => {
+                *count = Some(length);
+                None
+            }
+        };
+        if let Some(error) = error {
+            self.fail(error);
+        }
+        self
+    }
+
+    // Names end up in "hdata:list(*)/var keys,keys", so keep them from
+    // breaking that syntax
+    fn check_name(&mut self, name: &str, what: &str) {
+        if name.is_empty() || name.contains(|c: char| " :/(),\r\n".contains(c)) {
+            self.fail(format!("Invalid hdata {} {:?}", what, name));
+        }
+    }
+
+    fn fail(&mut self, message: String) {
+        if self.error.is_none() {
+            let error = command_error(CommandErrorType::InvalidArgument, message);
+            self.error = Some(error);
+        }
+    }
+}
+
+#[derive(Constructor, Debug)]
+pub struct InfoCommand {
+    pub id: Option<String>,
+    pub name: String,
 }
 
 impl InfoCommand {
@@ -232,12 +544,12 @@ impl Command for InfoCommand {
     }
 }
 
-#[derive(Constructor)]
+#[derive(Constructor, Debug)]
 pub struct InfoListCommand {
-    id: Option<String>,
-    name: String,
-    pointer: Option<String>,
-    arguments: Option<Vec<String>>,
+    pub id: Option<String>,
+    pub name: String,
+    pub pointer: Option<String>,
+    pub arguments: Option<Vec<String>>,
 }
 
 impl InfoListCommand {
@@ -275,10 +587,10 @@ impl Command for InfoListCommand {
     }
 }
 
-#[derive(Constructor)]
+#[derive(Constructor, Debug)]
 pub struct NicklistCommand {
-    id: Option<String>,
-    buffer: Option<String>,
+    pub id: Option<String>,
+    pub buffer: Option<String>,
 }
 
 impl NicklistCommand {
@@ -311,11 +623,11 @@ impl Command for NicklistCommand {
     }
 }
 
-#[derive(Constructor)]
+#[derive(Constructor, Debug)]
 pub struct InputCommand {
-    id: Option<String>,
-    buffer: String,
-    data: String,
+    pub id: Option<String>,
+    pub buffer: String,
+    pub data: String,
 }
 
 impl InputCommand {
@@ -344,8 +656,51 @@ impl Command for InputCommand {
     }
 }
 
+#[derive(Constructor, Debug)]
+pub struct CompletionCommand {
+    pub id: Option<String>,
+    pub buffer: String,
+    // Position in data, -1 for the end of it
+    pub position: i32,
+    pub data: Option<String>,
+}
+
+impl CompletionCommand {
+    fn encode(&self, out: &mut dyn Write) -> Result<usize, Error> {
+        let mut res = format!(
+            "{}completion {} {}",
+            handle_id(&self.id),
+            self.buffer,
+            self.position
+        );
+        if let Some(data) = &self.data {
+            res = format!("{} {}", res, data);
+        }
+        res.push('\n');
+        out.write(res.as_bytes())
+    }
+}
+
+impl Command for CompletionCommand {
+    fn get_id(&self) -> Option<String> {
+        self.id.clone()
+    }
+
+    fn set_id(&mut self, id: Option<String>) {
+        self.id = id;
+    }
+
+    fn encode(&self, out: &mut dyn Write) -> Result<usize, Error> {
+        self.encode(out)
+    }
+
+    fn has_response(&self) -> bool {
+        true
+    }
+}
+
 #[repr(C)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum SyncOption {
     Buffers,
     Upgrade,
@@ -362,12 +717,22 @@ impl SyncOption {
             SyncOption::Nicklist => "nicklist",
         }
     }
+
+    pub fn parse(value: &str) -> Option<SyncOption> {
+        match value {
+            "buffers" => Some(SyncOption::Buffers),
+            "upgrade" => Some(SyncOption::Upgrade),
+            "buffer" => Some(SyncOption::Buffer),
+            "nicklist" => Some(SyncOption::Nicklist),
+            _ => None,
+        }
+    }
 }
 
-#[derive(Constructor)]
+#[derive(Constructor, Debug)]
 pub struct SyncCommand {
-    id: Option<String>,
-    args: Vec<(String, SyncOption)>,
+    pub id: Option<String>,
+    pub args: Vec<(String, SyncOption)>,
 }
 
 impl SyncCommand {
@@ -375,20 +740,7 @@ impl SyncCommand {
         let mut res: String = handle_id(&self.id);
         res = format!("{}sync", res);
         if !self.args.is_empty() {
-            res.push(' ');
-            for (i, arg) in self.args.iter().enumerate() {
-                if i != 0 {
-                    res.push(',');
-                }
-                res.push_str(arg.0.as_str());
-            }
-            res.push(' ');
-            for (i, arg) in self.args.iter().enumerate() {
-                if i != 0 {
-                    res.push(',');
-                }
-                res.push_str(arg.1.as_str());
-            }
+            res = format!("{} {}", res, encode_sync_args(&self.args));
         }
         res.push('\n');
         out.write(res.as_bytes())
@@ -413,10 +765,10 @@ impl Command for SyncCommand {
     }
 }
 
-#[derive(Constructor)]
+#[derive(Constructor, Debug)]
 pub struct DesyncCommand {
-    id: Option<String>,
-    args: Vec<(String, SyncOption)>,
+    pub id: Option<String>,
+    pub args: Vec<(String, SyncOption)>,
 }
 
 impl DesyncCommand {
@@ -424,20 +776,7 @@ impl DesyncCommand {
         let mut res: String = handle_id(&self.id);
         res = format!("{}desync", res);
         if !self.args.is_empty() {
-            res.push(' ');
-            for (i, arg) in self.args.iter().enumerate() {
-                if i != 0 {
-                    res.push(',');
-                }
-                res.push_str(arg.0.as_str());
-            }
-            res.push(' ');
-            for (i, arg) in self.args.iter().enumerate() {
-                if i != 0 {
-                    res.push(',');
-                }
-                res.push_str(arg.1.as_str());
-            }
+            res = format!("{} {}", res, encode_sync_args(&self.args));
         }
         res.push('\n');
         out.write(res.as_bytes())
@@ -462,9 +801,9 @@ impl Command for DesyncCommand {
     }
 }
 
-#[derive(Constructor)]
+#[derive(Constructor, Debug)]
 pub struct TestCommand {
-    id: Option<String>,
+    pub id: Option<String>,
 }
 
 impl TestCommand {
@@ -492,10 +831,10 @@ impl Command for TestCommand {
     }
 }
 
-#[derive(Constructor)]
+#[derive(Constructor, Debug)]
 pub struct PingCommand {
-    id: Option<String>,
-    arguments: Option<Vec<String>>,
+    pub id: Option<String>,
+    pub arguments: Option<Vec<String>>,
 }
 
 impl PingCommand {
@@ -514,7 +853,7 @@ impl PingCommand {
 
 impl Command for PingCommand {
     fn get_id(&self) -> Option<String> {
-        Some("_pong".into())
+        self.id.clone()
     }
 
     fn set_id(&mut self, id: Option<String>) {
@@ -528,11 +867,16 @@ impl Command for PingCommand {
     fn has_response(&self) -> bool {
         true
     }
+
+    // Without arguments the pong is empty
+    fn pong_token(&self) -> Option<String> {
+        Some(self.arguments.as_ref().map(|args| args.join(" ")).unwrap_or_default())
+    }
 }
 
-#[derive(Constructor)]
+#[derive(Constructor, Debug)]
 pub struct QuitCommand {
-    id: Option<String>,
+    pub id: Option<String>,
 }
 
 impl QuitCommand {
@@ -560,6 +904,467 @@ impl Command for QuitCommand {
     }
 }
 
+//
+// Parsing, the relay's side of the commands above
+//
+
+#[derive(Debug)]
+pub enum CommandErrorType {
+    IoError,
+    UnknownCommand,
+    MissingArgument,
+    InvalidArgument,
+    Other,
+}
+
+#[derive(Constructor, Debug)]
+pub struct CommandError {
+    pub error: CommandErrorType,
+    pub message: String,
+    pub trace: Backtrace,
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for CommandError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+impl From<Error> for CommandError {
+    fn from(io_error: Error) -> Self {
+        CommandError {
+            error: CommandErrorType::IoError,
+            message: format!("{}", io_error),
+            trace: Backtrace::new(),
+        }
+    }
+}
+
+impl From<CommandError> for Error {
+    fn from(cerr: CommandError) -> Self {
+        Error::new(std::io::ErrorKind::InvalidInput, cerr)
+    }
+}
+
+// Any command a client can send, as parsed by parse_command
+#[derive(Debug)]
+pub enum ParsedCommand {
+    Handshake(HandshakeCommand),
+    Init(InitCommand),
+    Hdata(HdataCommand),
+    Info(InfoCommand),
+    Infolist(InfoListCommand),
+    Nicklist(NicklistCommand),
+    Input(InputCommand),
+    Completion(CompletionCommand),
+    Sync(SyncCommand),
+    Desync(DesyncCommand),
+    Test(TestCommand),
+    Ping(PingCommand),
+    Quit(QuitCommand),
+}
+
+impl ParsedCommand {
+    pub fn command_type(&self) -> CommandType {
+        match self {
+            ParsedCommand::Handshake(_) => CommandType::Handshake,
+            ParsedCommand::Init(_) => CommandType::Init,
+            ParsedCommand::Hdata(_) => CommandType::Hdata,
+            ParsedCommand::Info(_) => CommandType::Info,
+            ParsedCommand::Infolist(_) => CommandType::Infolist,
+            ParsedCommand::Nicklist(_) => CommandType::Nicklist,
+            ParsedCommand::Input(_) => CommandType::Input,
+            ParsedCommand::Completion(_) => CommandType::Completion,
+            ParsedCommand::Sync(_) => CommandType::Sync,
+            ParsedCommand::Desync(_) => CommandType::Desync,
+            ParsedCommand::Test(_) => CommandType::Test,
+            ParsedCommand::Ping(_) => CommandType::Ping,
+            ParsedCommand::Quit(_) => CommandType::Quit,
+        }
+    }
+
+    fn as_command(&self) -> &dyn Command {
+        match self {
+            ParsedCommand::Handshake(c) => c,
+            ParsedCommand::Init(c) => c,
+            ParsedCommand::Hdata(c) => c,
+            ParsedCommand::Info(c) => c,
+            ParsedCommand::Infolist(c) => c,
+            ParsedCommand::Nicklist(c) => c,
+            ParsedCommand::Input(c) => c,
+            ParsedCommand::Completion(c) => c,
+            ParsedCommand::Sync(c) => c,
+            ParsedCommand::Desync(c) => c,
+            ParsedCommand::Test(c) => c,
+            ParsedCommand::Ping(c) => c,
+            ParsedCommand::Quit(c) => c,
+        }
+    }
+
+    fn as_command_mut(&mut self) -> &mut dyn Command {
+        match self {
+            ParsedCommand::Handshake(c) => c,
+            ParsedCommand::Init(c) => c,
+            ParsedCommand::Hdata(c) => c,
+            ParsedCommand::Info(c) => c,
+            ParsedCommand::Infolist(c) => c,
+            ParsedCommand::Nicklist(c) => c,
+            ParsedCommand::Input(c) => c,
+            ParsedCommand::Completion(c) => c,
+            ParsedCommand::Sync(c) => c,
+            ParsedCommand::Desync(c) => c,
+            ParsedCommand::Test(c) => c,
+            ParsedCommand::Ping(c) => c,
+            ParsedCommand::Quit(c) => c,
+        }
+    }
+}
+
+impl Command for ParsedCommand {
+    fn get_id(&self) -> Option<String> {
+        self.as_command().get_id()
+    }
+
+    fn set_id(&mut self, id: Option<String>) {
+        self.as_command_mut().set_id(id)
+    }
+
+    fn encode(&self, out: &mut dyn Write) -> Result<usize, Error> {
+        self.as_command().encode(out)
+    }
+
+    fn has_response(&self) -> bool {
+        self.as_command().has_response()
+    }
+
+    fn pong_token(&self) -> Option<String> {
+        self.as_command().pong_token()
+    }
+}
+
+// Parse one command line as sent by a client, e.g.
+// "(id) hdata buffer:gui_buffers(*) number,name"
+pub fn parse_command(line: &str) -> Result<ParsedCommand, CommandError> {
+    let line = line.trim_end_matches(|c| c == '\n' || c == '\r');
+
+    let (id, rest) = if line.starts_with('(') {
+        match line.find(')') {
+            Some(end) => {
+                (Some(line[1..end].to_owned()), line[end + 1..].trim_start())
+            }
+            None => {
+                return Err(command_error(
+                    CommandErrorType::InvalidArgument,
+                    format!("Unterminated id in {}", line),
+                ))
+            }
+        }
+    } else {
+        (None, line)
+    };
+
+    let (name, args) = match rest.find(' ') {
+        Some(space) => (&rest[..space], rest[space + 1..].trim_start()),
+        None => (rest, ""),
+    };
+    let words: Vec<&str> = args.split_whitespace().collect();
+
+    match name {
+        "handshake" => {
+            let mut command = HandshakeCommand::new(id, None, None, None);
+            for (key, value) in split_options(args) {
+                match key.as_str() {
+                    "password_hash_algo" => {
+                        command.password_hash_algo = Some(parse_list(
+                            &value,
+                            ':',
+                            PasswordHashAlgo::parse,
+                            "password_hash_algo",
+                        )?)
+                    }
+                    "compression" => {
+                        command.compression = Some(parse_list(
+                            &value,
+                            ':',
+                            CompressionType::parse,
+                            "compression",
+                        )?)
+                    }
+                    "escape_commands" => {
+                        command.escape_commands = Some(value == "on")
+                    }
+                    // Unknown options are ignored, like the relay does
+                    _ => {}
+                }
+            }
+            Ok(ParsedCommand::Handshake(command))
+        }
+        "init" => {
+            let mut command = InitCommand::new(id, None, None, None, None);
+            for (key, value) in split_options(args) {
+                match key.as_str() {
+                    "password" => command.password = Some(value),
+                    "password_hash" => {
+                        command.password_hash =
+                            Some(PasswordHash::decode(&value).ok_or_else(|| {
+                                command_error(
+                                    CommandErrorType::InvalidArgument,
+                                    format!("Invalid password_hash {}", value),
+                                )
+                            })?)
+                    }
+                    "totp" => command.totp = Some(value),
+                    "compression" => {
+                        command.compression = Some(
+                            CompressionType::parse(&value).ok_or_else(|| {
+                                command_error(
+                                    CommandErrorType::InvalidArgument,
+                                    format!("Invalid compression {}", value),
+                                )
+                            })?,
+                        )
+                    }
+                    _ => {}
+                }
+            }
+            Ok(ParsedCommand::Init(command))
+        }
+        "hdata" => {
+            let path = required(&words, 0, "hdata path")?;
+            let mut segments = path.split('/');
+            let first = segments.next().unwrap_or("");
+            let (hdata, pointer) = match first.find(':') {
+                Some(colon) => (&first[..colon], &first[colon + 1..]),
+                None => {
+                    return Err(command_error(
+                        CommandErrorType::InvalidArgument,
+                        format!("Expected hdata:pointer in {}", path),
+                    ))
+                }
+            };
+            let pointer = parse_hdata_count(pointer)?;
+            let var =
+                segments.map(parse_hdata_count).collect::<Result<Vec<_>, _>>()?;
+            let keys =
+                words.get(1).map(|k| k.split(',').map(|k| k.to_owned()).collect());
+            Ok(ParsedCommand::Hdata(HdataCommand::new(
+                id,
+                hdata.to_owned(),
+                pointer,
+                var,
+                keys,
+            )))
+        }
+        "info" => {
+            let name = required(&words, 0, "info name")?;
+            Ok(ParsedCommand::Info(InfoCommand::new(id, name.to_owned())))
+        }
+        "infolist" => {
+            let name = required(&words, 0, "infolist name")?;
+            let pointer = words.get(1).map(|p| (*p).to_owned());
+            let arguments = if words.len() > 2 {
+                Some(words[2..].iter().map(|a| (*a).to_owned()).collect())
+            } else {
+                None
+            };
+            Ok(ParsedCommand::Infolist(InfoListCommand::new(
+                id,
+                name.to_owned(),
+                pointer,
+                arguments,
+            )))
+        }
+        "nicklist" => Ok(ParsedCommand::Nicklist(NicklistCommand::new(
+            id,
+            words.first().map(|b| (*b).to_owned()),
+        ))),
+        "input" => {
+            let buffer = required(&words, 0, "input buffer")?;
+            // Everything after the buffer is the text, spaces included
+            let data = args[buffer.len()..].strip_prefix(' ').unwrap_or("");
+            Ok(ParsedCommand::Input(InputCommand::new(
+                id,
+                buffer.to_owned(),
+                data.to_owned(),
+            )))
+        }
+        "completion" => {
+            let buffer = required(&words, 0, "completion buffer")?;
+            let position = required(&words, 1, "completion position")?;
+            let position = position.parse::<i32>().map_err(|_| {
+                command_error(
+                    CommandErrorType::InvalidArgument,
+                    format!("Invalid completion position {}", position),
+                )
+            })?;
+            let data = args
+                .splitn(3, ' ')
+                .nth(2)
+                .map(|d| d.to_owned())
+                .filter(|d| !d.is_empty());
+            Ok(ParsedCommand::Completion(CompletionCommand::new(
+                id,
+                buffer.to_owned(),
+                position,
+                data,
+            )))
+        }
+        "sync" => {
+            Ok(ParsedCommand::Sync(SyncCommand::new(id, parse_sync_args(&words)?)))
+        }
+        "desync" => Ok(ParsedCommand::Desync(DesyncCommand::new(
+            id,
+            parse_sync_args(&words)?,
+        ))),
+        "test" => Ok(ParsedCommand::Test(TestCommand::new(id))),
+        "ping" => Ok(ParsedCommand::Ping(PingCommand::new(
+            id,
+            if words.is_empty() {
+                None
+            } else {
+                Some(words.iter().map(|w| (*w).to_owned()).collect())
+            },
+        ))),
+        "quit" => Ok(ParsedCommand::Quit(QuitCommand::new(id))),
+        _ => Err(command_error(
+            CommandErrorType::UnknownCommand,
+            format!("Unknown command {}", name),
+        )),
+    }
+}
+
+fn command_error(error: CommandErrorType, message: String) -> CommandError {
+    CommandError::new(error, message, Backtrace::new())
+}
+
+fn required<'a>(
+    words: &[&'a str],
+    index: usize,
+    what: &str,
+) -> Result<&'a str, CommandError> {
+    words.get(index).cloned().ok_or_else(|| {
+        command_error(CommandErrorType::MissingArgument, format!("Missing {}", what))
+    })
+}
+
+// Split "key=value,key=value" on commas that are not escaped with a backslash
+fn split_options(args: &str) -> Vec<(String, String)> {
+    let mut options = vec![];
+    let mut current = String::new();
+    let mut chars = args.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&',') => {
+                current.push(',');
+                chars.next();
+            }
+            ',' => options.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        options.push(current);
+    }
+
+    options
+        .into_iter()
+        .map(|option| match option.find('=') {
+            Some(eq) => (option[..eq].to_owned(), option[eq + 1..].to_owned()),
+            None => (option, String::new()),
+        })
+        .collect()
+}
+
+fn parse_list<T, F>(
+    value: &str,
+    separator: char,
+    parse: F,
+    what: &str,
+) -> Result<Vec<T>, CommandError>
+where
+    F: Fn(&str) -> Option<T>,
+{
+    value
+        .split(separator)
+        .map(|item| {
+            parse(item).ok_or_else(|| {
+                command_error(
+                    CommandErrorType::InvalidArgument,
+                    format!("Invalid {} {}", what, item),
+                )
+            })
+        })
+        .collect()
+}
+
+// Parse "name(count)" where count is * or a number
+fn parse_hdata_count(
+    segment: &str,
+) -> Result<(String, Option<HdataCommandLength>), CommandError> {
+    match (segment.find('('), segment.ends_with(')')) {
+        (Some(open), true) => {
+            let count = &segment[open + 1..segment.len() - 1];
+            let length = if count == "*" {
+                HdataCommandLength::Infinite
+            } else {
+                HdataCommandLength::Finite(count.parse::<i32>().map_err(|_| {
+                    command_error(
+                        CommandErrorType::InvalidArgument,
+                        format!("Invalid hdata count in {}", segment),
+                    )
+                })?)
+            };
+            Ok((segment[..open].to_owned(), Some(length)))
+        }
+        (None, false) => Ok((segment.to_owned(), None)),
+        _ => Err(command_error(
+            CommandErrorType::InvalidArgument,
+            format!("Invalid hdata path segment {}", segment),
+        )),
+    }
+}
+
+// Options apply to every buffer in the list, without options the relay
+// syncs everything that makes sense for the buffer
+fn parse_sync_args(
+    words: &[&str],
+) -> Result<Vec<(String, SyncOption)>, CommandError> {
+    let buffers = match words.first() {
+        Some(buffers) => buffers.split(',').collect::<Vec<_>>(),
+        None => return Ok(vec![]),
+    };
+    let options = match words.get(1) {
+        Some(options) => {
+            Some(parse_list(options, ',', SyncOption::parse, "sync option")?)
+        }
+        None => None,
+    };
+
+    let mut args = vec![];
+    for buffer in buffers {
+        let buffer_options = match &options {
+            Some(options) => options.clone(),
+            None if buffer == "*" => vec![
+                SyncOption::Buffers,
+                SyncOption::Upgrade,
+                SyncOption::Buffer,
+                SyncOption::Nicklist,
+            ],
+            None => vec![SyncOption::Buffer, SyncOption::Nicklist],
+        };
+        for option in buffer_options {
+            args.push((buffer.to_owned(), option));
+        }
+    }
+    Ok(args)
+}
+
 // Helper functions
 
 fn handle_id(id: &Option<String>) -> String {
@@ -570,6 +1375,23 @@ fn handle_id(id: &Option<String>) -> String {
     }
 }
 
+// The relay applies every option to every buffer in the list, so args go out
+// as the buffers and options they contain, each once. That gives back exactly
+// what parse_command made of it.
+fn encode_sync_args(args: &[(String, SyncOption)]) -> String {
+    let mut buffers: Vec<&str> = vec![];
+    let mut options: Vec<&str> = vec![];
+    for (buffer, option) in args {
+        if !buffers.contains(&buffer.as_str()) {
+            buffers.push(buffer);
+        }
+        if !options.contains(&option.as_str()) {
+            options.push(option.as_str());
+        }
+    }
+    format!("{} {}", buffers.join(","), options.join(","))
+}
+
 fn escape_password(input: &str) -> String {
     // This implementation can probably be optimized
     let mut res = String::with_capacity(input.len());
@@ -581,3 +1403,257 @@ fn escape_password(input: &str) -> String {
     }
     res
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(command: &ParsedCommand) -> String {
+        let mut out = vec![];
+        command.encode(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn round_trip(line: &str) -> ParsedCommand {
+        let command = parse_command(line).unwrap();
+        assert_eq!(encode(&command), format!("{}\n", line));
+        command
+    }
+
+    #[test]
+    fn every_command_round_trips() {
+        let lines = [
+            "handshake",
+            "(hs) handshake password_hash_algo=plain:pbkdf2+sha512,compression=zstd:off",
+            "handshake escape_commands=on",
+            "init password=secret,compression=zlib",
+            "(init) init password_hash=pbkdf2+sha256:0a0b:100000:c0ffee,totp=123456",
+            "init password_hash=sha512:00ff:abcdef",
+            "(list) hdata buffer:gui_buffers(*) number,full_name",
+            "hdata buffer:0x1234/own_lines/last_line(-10)/data date,message",
+            "hdata hotlist:gui_hotlist(*)",
+            "info version",
+            "infolist buffer",
+            "(il) infolist window 0x1 arg1 arg2",
+            "nicklist",
+            "nicklist irc.libera.#weechat",
+            "input core.weechat /help filter",
+            "(in) input irc.libera.#weechat  leading and  double spaces ",
+            "completion core.weechat -1 /he",
+            "completion 0x1234 3",
+            "sync",
+            "sync * buffers,upgrade,buffer,nicklist",
+            "(s) sync irc.libera.#weechat,core.weechat buffer",
+            "desync * nicklist",
+            "test",
+            "(p) ping 1234 abcd",
+            "ping",
+            "quit",
+        ];
+        for line in &lines {
+            round_trip(line);
+        }
+    }
+
+    #[test]
+    fn id_prefix() {
+        let command = round_trip("(my id) info version");
+        assert_eq!(command.get_id(), Some("my id".to_owned()));
+        assert_eq!(command.command_type(), CommandType::Info);
+
+        let command = parse_command("(spaced)   test\r\n").unwrap();
+        assert_eq!(command.get_id(), Some("spaced".to_owned()));
+        assert_eq!(parse_command("test").unwrap().get_id(), None);
+        assert!(matches!(
+            parse_command("(unterminated test").unwrap_err().error,
+            CommandErrorType::InvalidArgument
+        ));
+    }
+
+    #[test]
+    fn pings_keep_their_id() {
+        let command = parse_command("(p) ping 1234 abcd").unwrap();
+        assert_eq!(command.get_id(), Some("p".to_owned()));
+        assert_eq!(command.pong_token(), Some("1234 abcd".to_owned()));
+
+        let command = parse_command("ping").unwrap();
+        assert_eq!(command.get_id(), None);
+        assert_eq!(command.pong_token(), Some(String::new()));
+        assert_eq!(parse_command("test").unwrap().pong_token(), None);
+    }
+
+    #[test]
+    fn escaped_commas_in_passwords() {
+        let command = round_trip(r"init password=a\,b\,c,totp=000000");
+        match command {
+            ParsedCommand::Init(init) => {
+                assert_eq!(init.password.as_deref(), Some("a,b,c"));
+                assert_eq!(init.totp.as_deref(), Some("000000"));
+            }
+            command => panic!("Expected init, got {:?}", command),
+        }
+    }
+
+    #[test]
+    fn hdata_counts() {
+        let command = round_trip("hdata buffer:gui_buffers(*)/lines/first_line(3)/data");
+        match command {
+            ParsedCommand::Hdata(hdata) => {
+                assert_eq!(hdata.hdata, "buffer");
+                assert_eq!(
+                    hdata.pointer,
+                    ("gui_buffers".to_owned(), Some(HdataCommandLength::Infinite))
+                );
+                assert_eq!(
+                    hdata.var,
+                    vec![
+                        ("lines".to_owned(), None),
+                        ("first_line".to_owned(), Some(HdataCommandLength::Finite(3))),
+                        ("data".to_owned(), None),
+                    ]
+                );
+                assert_eq!(hdata.keys, None);
+            }
+            command => panic!("Expected hdata, got {:?}", command),
+        }
+
+        for line in &["hdata buffer:gui_buffers(x)", "hdata buffer:gui_buffers(1", "hdata"] {
+            assert!(parse_command(line).is_err(), "{} should fail", line);
+        }
+    }
+
+    fn build(path: HdataPath) -> String {
+        let mut out = vec![];
+        path.build().unwrap().encode(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn invalid(path: HdataPath, error: &str) {
+        match path.build() {
+            Err(e) => {
+                assert!(matches!(e.error, CommandErrorType::InvalidArgument), "{:?}", e);
+                assert_eq!(e.message, error);
+            }
+            Ok(command) => panic!("Expected {:?}, got {:?}", error, command),
+        }
+    }
+
+    #[test]
+    fn hdata_path_builder() {
+        let lines = HdataPath::buffer()
+            .list("gui_buffers")
+            .all()
+            .then("own_lines")
+            .last_line(-50)
+            .then("data")
+            .keys(["date", "message"]);
+        assert_eq!(
+            build(lines),
+            "hdata buffer:gui_buffers(*)/own_lines/last_line(-50)/data date,message\n"
+        );
+        let buffer = HdataPath::buffer().id("buf").pointer(Pointer::new(0x1234)).count(1);
+        assert_eq!(build(buffer), "(buf) hdata buffer:0x1234(1)\n");
+        let hotlist = HdataPath::hotlist().list("gui_hotlist").all();
+        assert_eq!(build(hotlist), "hdata hotlist:gui_hotlist(*)\n");
+        let window = HdataPath::window().list("gui_current_window").then("buffer");
+        assert_eq!(build(window), "hdata window:gui_current_window/buffer\n");
+    }
+
+    #[test]
+    fn hdata_path_errors() {
+        match HdataPath::buffer().build() {
+            Err(e) => assert!(matches!(e.error, CommandErrorType::MissingArgument)),
+            Ok(command) => panic!("Expected an error, got {:?}", command),
+        }
+        invalid(
+            HdataPath::buffer().then("lines"),
+            "Hdata path needs a list or pointer before lines",
+        );
+        invalid(HdataPath::new(""), "Invalid hdata name \"\"");
+        invalid(HdataPath::buffer().list("a/b"), "Invalid hdata list name \"a/b\"");
+        invalid(
+            HdataPath::buffer().pointer(Pointer::new(0)),
+            "Hdata path can't start at a null pointer",
+        );
+        invalid(
+            HdataPath::buffer().list("gui_buffers").list("last_gui_buffer"),
+            "Hdata path already has a start before last_gui_buffer",
+        );
+
+        invalid(
+            HdataPath::buffer().list("gui_buffers").count(0),
+            "Hdata count can't be 0, use all() for every item",
+        );
+        invalid(
+            HdataPath::buffer().list("gui_buffers").all().count(3),
+            "gui_buffers already has a count",
+        );
+        invalid(HdataPath::buffer().all(), "Hdata path needs a start before a count");
+
+        invalid(
+            HdataPath::buffer().list("gui_buffers").keys(Vec::<String>::new()),
+            "Hdata keys can't be empty, leave them out for all",
+        );
+        invalid(
+            HdataPath::buffer().list("gui_buffers").keys(["number"]).keys(["name"]),
+            "Hdata keys were given twice",
+        );
+        invalid(
+            HdataPath::buffer().list("gui_buffers").keys(["full name"]),
+            "Invalid hdata key \"full name\"",
+        );
+        invalid(
+            HdataPath::buffer().list("gui_buffers").keys(["number"]).then("lines"),
+            "Hdata keys only go on the last element, not before lines",
+        );
+
+        // The first mistake is the one returned
+        invalid(
+            HdataPath::buffer().list("gui_buffers").count(0).then(""),
+            "Hdata count can't be 0, use all() for every item",
+        );
+    }
+
+    #[test]
+    fn sync_star_expansion() {
+        let command = parse_command("sync *").unwrap();
+        match &command {
+            ParsedCommand::Sync(sync) => assert_eq!(sync.args.len(), 4),
+            command => panic!("Expected sync, got {:?}", command),
+        }
+        assert_eq!(encode(&command), "sync * buffers,upgrade,buffer,nicklist\n");
+
+        let command = parse_command("sync irc.libera.#weechat").unwrap();
+        match &command {
+            ParsedCommand::Sync(sync) => assert_eq!(
+                sync.args,
+                vec![
+                    ("irc.libera.#weechat".to_owned(), SyncOption::Buffer),
+                    ("irc.libera.#weechat".to_owned(), SyncOption::Nicklist),
+                ]
+            ),
+            command => panic!("Expected sync, got {:?}", command),
+        }
+        assert_eq!(encode(&command), "sync irc.libera.#weechat buffer,nicklist\n");
+
+        // Expanded forms parse back to the same args
+        for line in &["sync *", "desync a,b", "sync a,b nicklist,buffer"] {
+            let encoded = encode(&parse_command(line).unwrap());
+            assert_eq!(encode(&parse_command(&encoded).unwrap()), encoded);
+        }
+    }
+
+    #[test]
+    fn unknown_commands_and_options() {
+        assert!(matches!(
+            parse_command("frobnicate").unwrap_err().error,
+            CommandErrorType::UnknownCommand
+        ));
+        assert!(parse_command("sync * everything").is_err());
+        assert!(parse_command("completion core.weechat end").is_err());
+        assert!(matches!(
+            parse_command("input").unwrap_err().error,
+            CommandErrorType::MissingArgument
+        ));
+    }
+}
@@ -0,0 +1,390 @@
+use crate::message::{Hdata, WeechatString, WeechatType, WeechatUnwrappable};
+use backtrace::Backtrace;
+use std::io::Error;
+
+pub use libdingy_derive::FromHdata;
+
+#[derive(Debug)]
+pub enum HdataErrorType {
+    NotHdata,
+    IndexOutOfRange,
+    MissingKey,
+    MissingPointer,
+    TypeMismatch,
+    Conversion,
+    Other,
+}
+
+#[derive(Constructor, Debug)]
+pub struct HdataError {
+    pub error: HdataErrorType,
+    pub message: String,
+    pub trace: Backtrace,
+}
+
+impl std::fmt::Display for HdataError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for HdataError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+impl From<HdataError> for Error {
+    fn from(herr: HdataError) -> Self {
+        Error::new(std::io::ErrorKind::InvalidData, herr)
+    }
+}
+
+// Map-like view of one row of an hdata, from Hdata::row or Hdata::rows
+#[derive(Clone, Copy, Debug)]
+pub struct HdataRow<'a> {
+    hdata: &'a Hdata,
+    index: usize,
+}
+
+impl<'a> HdataRow<'a> {
+    // index must be in range, Hdata::row checks it
+    pub(crate) fn new(hdata: &'a Hdata, index: usize) -> HdataRow<'a> {
+        HdataRow { hdata, index }
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    // One pointer per element of the hdata path, ending with the row's own
+    pub fn pointers(&self) -> &'a [WeechatType] {
+        &self.hdata.values[self.index].0
+    }
+
+    pub fn pointer(&self) -> Option<&'a WeechatType> {
+        self.pointers().last()
+    }
+
+    // Pointer for the element of the hdata path called name, like "buffer"
+    // in buffer/lines/line/line_data
+    pub fn pointer_for(&self, name: &str) -> Option<&'a WeechatType> {
+        let position = self.hdata.h_path.iter().position(|p| p == name)?;
+        self.pointers().get(position)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&'a WeechatType> {
+        self.at(self.hdata.column(key)?)
+    }
+
+    // Value in a column found with Hdata::column
+    pub fn at(&self, column: usize) -> Option<&'a WeechatType> {
+        self.hdata.values[self.index].1.get(column)
+    }
+
+    pub fn try_get<T: WeechatUnwrappable<T>>(
+        &self,
+        key: &str,
+    ) -> Result<T, HdataError> {
+        let value = self.get(key).ok_or_else(|| {
+            HdataError::new(
+                HdataErrorType::MissingKey,
+                format!(
+                    "No key {} in hdata with keys {}",
+                    key,
+                    key_list(self.hdata)
+                ),
+                Backtrace::new(),
+            )
+        })?;
+        value.unwrap::<T>().ok_or_else(|| {
+            HdataError::new(
+                HdataErrorType::TypeMismatch,
+                format!(
+                    "{} in row {} is a {} which doesn't fit {}",
+                    key,
+                    self.index,
+                    describe(value),
+                    std::any::type_name::<T>()
+                ),
+                Backtrace::new(),
+            )
+        })
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.hdata.column(key).is_some()
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &'a str> {
+        self.hdata.keys.iter().map(|(name, _)| name.as_str())
+    }
+
+    // (key, value) pairs in the order the relay sent them
+    pub fn iter(&self) -> impl Iterator<Item = (&'a str, &'a WeechatType)> {
+        self.keys().zip(self.hdata.values[self.index].1.iter())
+    }
+
+    pub fn len(&self) -> usize {
+        self.hdata.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hdata.keys.is_empty()
+    }
+}
+
+// Types that can be read from one row of an hdata, usually through
+// #[derive(FromHdata)] (see libdingy-derive for the field attributes)
+pub trait FromHdata: Sized {
+    fn from_hdata(hdata: &Hdata, index: usize) -> Result<Self, HdataError>;
+
+    // Every row, failing on the first one that doesn't fit
+    fn from_hdata_rows(hdata: &Hdata) -> Result<Vec<Self>, HdataError> {
+        (0..hdata.len()).map(|index| Self::from_hdata(hdata, index)).collect()
+    }
+
+    // Every row of a message item, which has to be an hdata
+    fn from_weechat_type(item: &WeechatType) -> Result<Vec<Self>, HdataError> {
+        match item {
+            WeechatType::Hdata(hdata) => Self::from_hdata_rows(hdata),
+            other => Err(HdataError::new(
+                HdataErrorType::NotHdata,
+                format!("Expected hda but found {}", other.type_string()),
+                Backtrace::new(),
+            )),
+        }
+    }
+}
+
+// Used by the code #[derive(FromHdata)] generates, not meant to be called
+// directly. what is the name of the struct being read, for error messages.
+#[doc(hidden)]
+pub mod derive {
+    use super::{describe, key_list, HdataError, HdataErrorType};
+    use crate::message::{Hdata, WeechatType, WeechatUnwrappable};
+    use backtrace::Backtrace;
+
+    pub fn check_index(
+        hdata: &Hdata,
+        index: usize,
+        what: &str,
+    ) -> Result<(), HdataError> {
+        if index >= hdata.len() {
+            return Err(HdataError::new(
+                HdataErrorType::IndexOutOfRange,
+                format!(
+                    "{} row {} is past the {} rows in the hdata",
+                    what,
+                    index,
+                    hdata.len()
+                ),
+                Backtrace::new(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn value<'a>(
+        hdata: &'a Hdata,
+        index: usize,
+        key: &str,
+    ) -> Option<&'a WeechatType> {
+        hdata.row(index)?.get(key)
+    }
+
+    // The row's pointer for name in the hdata path, or its own pointer
+    pub fn pointer<'a>(
+        hdata: &'a Hdata,
+        index: usize,
+        name: Option<&str>,
+    ) -> Option<&'a WeechatType> {
+        let row = hdata.row(index)?;
+        match name {
+            Some(name) => row.pointer_for(name),
+            None => row.pointer(),
+        }
+    }
+
+    pub fn missing(hdata: &Hdata, what: &str, key: &str) -> HdataError {
+        HdataError::new(
+            HdataErrorType::MissingKey,
+            format!(
+                "{}.{} is missing, the hdata only has keys {}",
+                what,
+                key,
+                key_list(hdata)
+            ),
+            Backtrace::new(),
+        )
+    }
+
+    pub fn missing_pointer(
+        hdata: &Hdata,
+        what: &str,
+        name: Option<&str>,
+    ) -> HdataError {
+        HdataError::new(
+            HdataErrorType::MissingPointer,
+            format!(
+                "{} needs the {} pointer but the hdata path is {}",
+                what,
+                name.unwrap_or("row's own"),
+                hdata.h_path.join("/")
+            ),
+            Backtrace::new(),
+        )
+    }
+
+    pub fn unwrap<T: WeechatUnwrappable<T>>(
+        value: &WeechatType,
+        what: &str,
+        key: &str,
+    ) -> Result<T, HdataError> {
+        T::unwrap(value).ok_or_else(|| {
+            HdataError::new(
+                HdataErrorType::TypeMismatch,
+                format!(
+                    "{}.{} is a {} which doesn't fit {}",
+                    what,
+                    key,
+                    describe(value),
+                    std::any::type_name::<T>()
+                ),
+                Backtrace::new(),
+            )
+        })
+    }
+
+    pub fn convert<T, F>(
+        value: &WeechatType,
+        what: &str,
+        key: &str,
+        func: F,
+    ) -> Result<T, HdataError>
+    where
+        F: FnOnce(&WeechatType) -> Result<T, String>,
+    {
+        func(value).map_err(|message| {
+            HdataError::new(
+                HdataErrorType::Conversion,
+                format!("{}.{} ({}): {}", what, key, describe(value), message),
+                Backtrace::new(),
+            )
+        })
+    }
+}
+
+//
+// Helper functions
+//
+
+fn key_list(hdata: &Hdata) -> String {
+    let keys: Vec<&str> = hdata.keys.iter().map(|(name, _)| name.as_str()).collect();
+    keys.join(",")
+}
+
+// Type of a value as the relay names it, with a hint for nulls
+fn describe(value: &WeechatType) -> String {
+    match value {
+        WeechatType::String(WeechatString::Null) => "null str".to_owned(),
+        WeechatType::String(s) if s.as_str().is_err() => "non-UTF-8 str".to_owned(),
+        WeechatType::Buffer(None) => "null buf".to_owned(),
+        WeechatType::Array(items) => match items.first() {
+            Some(item) => format!("arr of {}", item.type_string()),
+            None => "empty arr".to_owned(),
+        },
+        other => other.type_string().to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Pointer;
+
+    fn lines() -> Hdata {
+        let line = |pointer: u128, date: i32, message: &str| {
+            (
+                vec![
+                    WeechatType::Pointer(Pointer::new(0x10)),
+                    WeechatType::Pointer(Pointer::new(pointer)),
+                ],
+                vec![WeechatType::Int(date), WeechatType::String(message.into())],
+            )
+        };
+        Hdata {
+            h_path: vec!["buffer".into(), "line_data".into()],
+            keys: vec![("date".into(), "int".into()), ("message".into(), "str".into())],
+            values: vec![line(0x20, 100, "hello"), line(0x21, 200, "world")],
+        }
+    }
+
+    #[test]
+    fn dynamic_keys() {
+        let hdata = lines();
+        let key = String::from("message");
+        assert_eq!(hdata.get_dyn::<String>(1, &key).as_deref(), Some("world"));
+        assert_eq!(hdata.get::<i32>(0, "date"), Some(100));
+        assert_eq!(hdata.get_dyn::<String>(0, "nick"), None);
+        assert_eq!(hdata.get_dyn::<i32>(0, &key), None);
+        assert_eq!(hdata.get_dyn::<i32>(2, "date"), None);
+    }
+
+    #[test]
+    fn columns() {
+        let hdata = lines();
+        assert_eq!(hdata.column("date"), Some(0));
+        assert_eq!(hdata.column("message"), Some(1));
+        assert_eq!(hdata.column("nick"), None);
+        let column = hdata.column("message").unwrap();
+        let messages: Vec<String> =
+            (0..hdata.len()).filter_map(|i| hdata.get_at(i, column)).collect();
+        assert_eq!(messages, ["hello", "world"]);
+        assert_eq!(hdata.get_at::<i32>(0, column), None);
+        assert_eq!(hdata.get_at::<i32>(0, 5), None);
+        assert_eq!(hdata.get_at::<i32>(5, 0), None);
+    }
+
+    #[test]
+    fn try_get_errors() {
+        let hdata = lines();
+        assert_eq!(hdata.try_get::<i32>(1, "date").unwrap(), 200);
+
+        let e = hdata.try_get::<i32>(0, "nick").unwrap_err();
+        assert!(matches!(e.error, HdataErrorType::MissingKey));
+        assert_eq!(e.message, "No key nick in hdata with keys date,message");
+
+        let e = hdata.try_get::<i32>(1, "message").unwrap_err();
+        assert!(matches!(e.error, HdataErrorType::TypeMismatch));
+        assert_eq!(e.message, "message in row 1 is a str which doesn't fit i32");
+
+        let e = hdata.try_get::<i32>(2, "date").unwrap_err();
+        assert!(matches!(e.error, HdataErrorType::IndexOutOfRange));
+        assert_eq!(e.message, "Row 2 is past the 2 rows in the hdata");
+        assert!(hdata.try_row(usize::MAX).is_err());
+    }
+
+    #[test]
+    fn rows() {
+        let hdata = lines();
+        assert!(hdata.row(2).is_none());
+        let row = hdata.row(1).unwrap();
+        assert_eq!(row.index(), 1);
+        assert_eq!(row.pointer(), Some(&WeechatType::Pointer(Pointer::new(0x21))));
+        assert_eq!(row.pointer_for("buffer"), Some(&WeechatType::Pointer(Pointer::new(0x10))));
+        assert_eq!(row.pointer_for("window"), None);
+        assert!(row.contains_key("date"));
+        assert!(!row.contains_key("nick"));
+        assert_eq!(row.keys().collect::<Vec<_>>(), ["date", "message"]);
+        assert_eq!(row.iter().count(), 2);
+        assert_eq!(row.len(), 2);
+        let dates: Vec<i32> = hdata.rows().map(|row| row.try_get("date").unwrap()).collect();
+        assert_eq!(dates, [100, 200]);
+
+        let empty = Hdata { h_path: vec![], keys: vec![], values: vec![] };
+        assert!(empty.is_empty());
+        assert_eq!(empty.rows().count(), 0);
+        assert!(empty.try_get::<i32>(0, "date").is_err());
+    }
+}
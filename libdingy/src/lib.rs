@@ -2,7 +2,11 @@
 extern crate derive_more;
 extern crate bytes;
 
+pub mod auth;
+pub mod color;
 pub mod command;
+pub mod hdata;
 pub mod message;
+pub mod render;
 pub mod sync;
 pub mod c_interop;
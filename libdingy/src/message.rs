@@ -1,9 +1,16 @@
 #![allow(dead_code)]
 use backtrace::Backtrace;
 use byteorder::{ByteOrder, BE};
+use bytes::Bytes;
 use libflate::zlib;
+use crate::command::CompressionType;
+use crate::hdata::{HdataError, HdataErrorType, HdataRow};
+use std::borrow::Cow;
 use std::clone::Clone;
-use std::io::{Cursor, Error, Read};
+use std::convert::TryFrom;
+use std::io::{Error, Read, Write};
+use std::str::{self, Utf8Error};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 //
 // Types
@@ -18,13 +25,63 @@ pub struct Hdata {
 
 impl Hdata {
     // Get value for key at hdata index. Uses WeechatType::unwrap::<T> to return arbitrary types.
-    // Returns None if key not found or if unwrap fails
+    // Returns None if the row or key doesn't exist or if unwrap fails
     pub fn get<T>(&self, index: usize, key: &'static str) -> Option<T>
     where
         T: WeechatUnwrappable<T>,
     {
-        let key_index = self.keys.iter().enumerate().find(|(_, (k, _))| k == key).map(|(i, _)| i);
-        key_index.and_then(|i| self.values[index].1[i].unwrap::<T>())
+        self.get_dyn(index, key)
+    }
+
+    // Same as get, for keys that aren't known until runtime
+    pub fn get_dyn<T>(&self, index: usize, key: &str) -> Option<T>
+    where
+        T: WeechatUnwrappable<T>,
+    {
+        self.row(index)?.get(key)?.unwrap::<T>()
+    }
+
+    // Same as get_dyn, but says whether the row, key or type was wrong
+    pub fn try_get<T>(&self, index: usize, key: &str) -> Result<T, HdataError>
+    where
+        T: WeechatUnwrappable<T>,
+    {
+        self.try_row(index)?.try_get(key)
+    }
+
+    // Where key's values are in every row, to skip the search when reading
+    // the same key from many rows with get_at
+    pub fn column(&self, key: &str) -> Option<usize> {
+        self.keys.iter().position(|(name, _)| name == key)
+    }
+
+    pub fn get_at<T>(&self, index: usize, column: usize) -> Option<T>
+    where
+        T: WeechatUnwrappable<T>,
+    {
+        self.row(index)?.at(column)?.unwrap::<T>()
+    }
+
+    pub fn row(&self, index: usize) -> Option<HdataRow<'_>> {
+        if index < self.len() {
+            Some(HdataRow::new(self, index))
+        } else {
+            None
+        }
+    }
+
+    pub fn try_row(&self, index: usize) -> Result<HdataRow<'_>, HdataError> {
+        self.row(index).ok_or_else(|| {
+            HdataError::new(
+                HdataErrorType::IndexOutOfRange,
+                format!("Row {} is past the {} rows in the hdata", index, self.len()),
+                Backtrace::new(),
+            )
+        })
+    }
+
+    pub fn rows(&self) -> impl Iterator<Item = HdataRow<'_>> {
+        (0..self.len()).map(move |index| HdataRow::new(self, index))
     }
 
     pub fn len(&self) -> usize {
@@ -38,16 +95,21 @@ impl Hdata {
 
 pub struct InfoListEntry();
 
-#[derive(Eq, PartialEq, Ord, PartialOrd, Debug, Clone)]
+// Strings are kept as the relay sent them. WeeChat doesn't promise UTF-8
+// (IRC servers happily pass latin-1 through), so decoding is left to the
+// accessors: as_str is strict, to_string_lossy and to_str never fail.
+#[derive(Eq, PartialEq, Ord, PartialOrd, Clone)]
 pub enum WeechatString {
     Null,
-    Str(String),
+    Str(Bytes),
 }
 
 impl WeechatString {
+    // Hands over the bytes as the relay sent them, without copying or
+    // decoding them. Use as_str or to_string_lossy first for text.
     pub fn map<T, F>(self, func: F) -> Option<T>
     where
-        F: FnOnce(String) -> T,
+        F: FnOnce(Bytes) -> T,
     {
         match self {
             WeechatString::Null => None,
@@ -55,23 +117,234 @@ impl WeechatString {
         }
     }
 
+    pub fn is_null(&self) -> bool {
+        match self {
+            WeechatString::Null => true,
+            WeechatString::Str(_) => false,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            WeechatString::Null => None,
+            WeechatString::Str(s) => Some(s),
+        }
+    }
+
+    // Ok(None) for the null string, Err if the bytes aren't UTF-8
+    pub fn as_str(&self) -> Result<Option<&str>, Utf8Error> {
+        match self {
+            WeechatString::Null => Ok(None),
+            WeechatString::Str(s) => str::from_utf8(s).map(Some),
+        }
+    }
+
+    // Invalid UTF-8 sequences are replaced with U+FFFD
+    pub fn to_string_lossy(&self) -> Option<Cow<'_, str>> {
+        self.as_bytes().map(String::from_utf8_lossy)
+    }
+
     pub fn to_str(&self) -> String {
-        match &self {
-            WeechatString::Null => "(null)".to_owned(),
-            WeechatString::Str(s) => s.clone(),
+        match self.to_string_lossy() {
+            None => "(null)".to_owned(),
+            Some(s) => s.into_owned(),
+        }
+    }
+}
+
+impl std::fmt::Debug for WeechatString {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            WeechatString::Null => write!(f, "Null"),
+            WeechatString::Str(s) => match str::from_utf8(s) {
+                Ok(s) => write!(f, "Str({:?})", s),
+                Err(_) => write!(f, "Str({:?})", s),
+            },
+        }
+    }
+}
+
+impl From<String> for WeechatString {
+    fn from(s: String) -> WeechatString {
+        WeechatString::Str(Bytes::from(s))
+    }
+}
+
+impl<'a> From<&'a str> for WeechatString {
+    fn from(s: &'a str) -> WeechatString {
+        WeechatString::Str(Bytes::from(s))
+    }
+}
+
+impl From<Vec<u8>> for WeechatString {
+    fn from(s: Vec<u8>) -> WeechatString {
+        WeechatString::Str(Bytes::from(s))
+    }
+}
+
+impl From<Bytes> for WeechatString {
+    fn from(s: Bytes) -> WeechatString {
+        WeechatString::Str(s)
+    }
+}
+
+// Address of an object in the WeeChat process. The relay sends them as
+// bare hex, commands want them as 0x-prefixed hex, which is what Display
+// gives.
+#[derive(Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, Default)]
+pub struct Pointer(u128);
+
+impl Pointer {
+    pub fn new(address: u128) -> Pointer {
+        Pointer(address)
+    }
+
+    pub fn null() -> Pointer {
+        Pointer(0)
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn address(&self) -> u128 {
+        self.0
+    }
+}
+
+impl std::fmt::Display for Pointer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "0x{:x}", self.0)
+    }
+}
+
+impl std::fmt::Debug for Pointer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Pointer(0x{:x})", self.0)
+    }
+}
+
+impl std::fmt::LowerHex for Pointer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        std::fmt::LowerHex::fmt(&self.0, f)
+    }
+}
+
+impl std::fmt::UpperHex for Pointer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        std::fmt::UpperHex::fmt(&self.0, f)
+    }
+}
+
+// Accepts hex with or without the 0x, like the relay and commands use
+impl std::str::FromStr for Pointer {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Pointer, Self::Err> {
+        let hex = s.strip_prefix("0x").unwrap_or(s);
+        u128::from_str_radix(hex, 16).map(Pointer)
+    }
+}
+
+impl From<u128> for Pointer {
+    fn from(address: u128) -> Pointer {
+        Pointer(address)
+    }
+}
+
+impl From<Pointer> for u128 {
+    fn from(pointer: Pointer) -> u128 {
+        pointer.0
+    }
+}
+
+// Seconds since the Unix epoch, as WeeChat's time_t
+#[derive(Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, Default)]
+pub struct Timestamp(i64);
+
+impl Timestamp {
+    pub fn from_secs(secs: i64) -> Timestamp {
+        Timestamp(secs)
+    }
+
+    pub fn secs(&self) -> i64 {
+        self.0
+    }
+
+    pub fn now() -> Timestamp {
+        Timestamp::from(SystemTime::now())
+    }
+
+    pub fn to_system_time(&self) -> SystemTime {
+        if self.0 >= 0 {
+            UNIX_EPOCH + Duration::from_secs(self.0 as u64)
+        } else {
+            UNIX_EPOCH - Duration::from_secs(self.0.unsigned_abs())
+        }
+    }
+
+    #[cfg(feature = "chrono")]
+    pub fn to_datetime(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        chrono::DateTime::from_timestamp(self.0, 0)
+    }
+}
+
+impl std::fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::fmt::Debug for Timestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Timestamp({})", self.0)
+    }
+}
+
+impl From<i64> for Timestamp {
+    fn from(secs: i64) -> Timestamp {
+        Timestamp(secs)
+    }
+}
+
+impl From<Timestamp> for i64 {
+    fn from(timestamp: Timestamp) -> i64 {
+        timestamp.0
+    }
+}
+
+impl From<Timestamp> for SystemTime {
+    fn from(timestamp: Timestamp) -> SystemTime {
+        timestamp.to_system_time()
+    }
+}
+
+// Sub-second precision is dropped, like WeeChat does
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Timestamp {
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(since) => Timestamp(since.as_secs() as i64),
+            Err(e) => Timestamp(-(e.duration().as_secs() as i64)),
         }
     }
 }
 
+#[cfg(feature = "chrono")]
+impl<Tz: chrono::TimeZone> From<chrono::DateTime<Tz>> for Timestamp {
+    fn from(time: chrono::DateTime<Tz>) -> Timestamp {
+        Timestamp(time.timestamp())
+    }
+}
+
 #[derive(Eq, PartialEq, Ord, PartialOrd, Debug, Clone)]
 pub enum WeechatType {
     Char(i8),
     Int(i32),
     Long(i128),
     String(WeechatString),
-    Buffer(Option<Vec<u8>>),
-    Pointer(u128),
-    Time(u128),
+    Buffer(Option<Bytes>),
+    Pointer(Pointer),
+    Time(Timestamp),
     HashTable(Vec<(WeechatType, WeechatType)>),
     Hdata(Hdata),
     Info(WeechatString, WeechatString),
@@ -106,9 +379,10 @@ macro_rules! basic_unwrappable {
 basic_unwrappable!(i8, Char);
 basic_unwrappable!(i32, Int);
 basic_unwrappable!(i128, Long);
-basic_unwrappable!(u128, Pointer, Time);
+basic_unwrappable!(Pointer, Pointer);
+basic_unwrappable!(Timestamp, Time);
 basic_unwrappable!(WeechatString, String);
-basic_unwrappable!(Option<Vec<u8>>, Buffer);
+basic_unwrappable!(Option<Bytes>, Buffer);
 basic_unwrappable!(Vec<WeechatType>, Array);
 
 // Unwrapping for vectors
@@ -165,6 +439,28 @@ where
     }
 }
 
+// C longs are 64 bits on the platforms WeeChat runs on. Ints widen losslessly.
+impl WeechatUnwrappable<i64> for i64 {
+    fn unwrap(wt: &WeechatType) -> Option<i64> {
+        match wt {
+            WeechatType::Long(l) => i64::try_from(*l).ok(),
+            WeechatType::Int(i) => Some(i64::from(*i)),
+            _ => None,
+        }
+    }
+}
+
+// Null and non-UTF-8 strings don't fit in a String, use WeechatString to
+// keep them
+impl WeechatUnwrappable<String> for String {
+    fn unwrap(wt: &WeechatType) -> Option<String> {
+        match wt {
+            WeechatType::String(s) => s.as_str().ok()?.map(|s| s.to_owned()),
+            _ => None,
+        }
+    }
+}
+
 impl WeechatUnwrappable<bool> for bool {
     fn unwrap(wt: &WeechatType) -> Option<bool> {
         wt.unwrap::<i8>().map(|x| x == 1)
@@ -189,6 +485,16 @@ pub enum WeechatErrorType {
     HdataLengthMismatch,
     HdataNullType,
     HdataNullId,
+    InvalidHandshake,
+    TypeMismatch,
+    ValueTooLong,
+    TruncatedFrame,
+    InvalidCompression,
+    CorruptCompressedData,
+    InvalidUtf8,
+    InvalidNumber,
+    InvalidHdataKeys,
+    LimitExceeded,
     Other,
 }
 
@@ -227,144 +533,195 @@ impl From<WeechatError> for Error {
     }
 }
 
-// Reads three-char type signatures into a String
-fn parse_type_string(read: &mut dyn Read) -> Result<String, WeechatError> {
-    let mut res = String::new();
-    let length = read.take(3).read_to_string(&mut res);
+// Caps on what a single frame may make us allocate, so a buggy or malicious
+// relay can't exhaust memory with one message
+#[derive(Clone, Copy, Debug)]
+pub struct ParseLimits {
+    // Largest frame accepted, as given in the header (compressed size)
+    pub max_frame_size: u32,
+    // Largest payload accepted after decompression
+    pub max_decompressed_size: usize,
+    // Most entries in a single htb, arr, hda or inl
+    pub max_elements: u32,
+    // Most levels of htb, arr, hda or inl inside each other. Each level is a
+    // stack frame, so without this a small frame could overflow the stack.
+    pub max_depth: u32,
+}
 
-    if length.unwrap_or(0) != 3 {
-        return Err(WeechatError {
-            error: WeechatErrorType::IoError,
-            message: "last os error".to_owned(),
-            trace: Backtrace::new(),
-        });
+impl Default for ParseLimits {
+    fn default() -> ParseLimits {
+        ParseLimits {
+            max_frame_size: 16 * 1024 * 1024,
+            max_decompressed_size: 64 * 1024 * 1024,
+            max_elements: 1_000_000,
+            max_depth: 32,
+        }
     }
-
-    Ok(res)
 }
 
-fn parse_str_int(read: &mut dyn Read, radix: u32) -> Result<i128, WeechatError> {
-    let mut _read_res: Result<(), Error> = Ok(());
-    let buf = &mut [0u8; 1];
-    _read_res = read.read_exact(buf);
-    if _read_res.is_err() {
-        return Err(handle_io_error());
+impl ParseLimits {
+    pub fn check_frame_size(&self, length: u32) -> Result<(), WeechatError> {
+        if length > self.max_frame_size {
+            return Err(limit_error(format!(
+                "Frame of {} bytes exceeds the {} byte limit",
+                length, self.max_frame_size
+            )));
+        }
+        Ok(())
     }
-    let len = buf[0] as u8;
-    let mut val = String::new();
-    let val_res = read.take(u64::from(len)).read_to_string(&mut val);
 
-    if val_res.is_err() {
-        return Err(handle_io_error());
+    fn check_elements(&self, count: u32, what: &str) -> Result<(), WeechatError> {
+        if count > self.max_elements {
+            return Err(limit_error(format!(
+                "{} with {} elements exceeds the limit of {}",
+                what, count, self.max_elements
+            )));
+        }
+        Ok(())
     }
 
-    // val is a binary string
-    let ival = i128::from_str_radix(val.as_str(), radix);
-    if ival.is_err() {
-        return Err(WeechatError {
-            error: WeechatErrorType::IoError,
-            message: "Int parse error".to_owned(),
-            trace: Backtrace::new(),
-        });
+    fn check_depth(&self, depth: u32) -> Result<(), WeechatError> {
+        if depth > self.max_depth {
+            return Err(limit_error(format!(
+                "Values nested more than {} deep",
+                self.max_depth
+            )));
+        }
+        Ok(())
     }
+}
 
-    Ok(ival.unwrap())
+// Every parse_* function below consumes its value from the front of buf.
+// Strings and buffers are split off rather than copied, so they keep the
+// frame alive for as long as they're around.
+
+// Reads three-char type signatures
+fn parse_type_string(buf: &mut Bytes) -> Result<Bytes, WeechatError> {
+    take_bytes(buf, 3, "type")
+}
+
+fn parse_str_int(buf: &mut Bytes, radix: u32) -> Result<i128, WeechatError> {
+    let len = take_bytes(buf, 1, "number length")?[0];
+    let val = take_bytes(buf, len as usize, "number")?;
+    let val = str::from_utf8(&val).map_err(|e| utf8_error("number", e))?;
+
+    // val is a binary string
+    i128::from_str_radix(val, radix).map_err(|_| {
+        WeechatError::new(
+            WeechatErrorType::InvalidNumber,
+            format!("Invalid base {} number {:?}", radix, val),
+            Backtrace::new(),
+        )
+    })
 }
 
-// This function will parse all of the types and return a result
+// This function will parse all of the types and return a result. depth is
+// how many values this one is inside of, 0 for the items of a message.
 fn parse_weechat_type(
-    _type: String,
-    read: &mut dyn Read,
+    _type: &[u8],
+    buf: &mut Bytes,
+    limits: &ParseLimits,
+    depth: u32,
 ) -> Result<WeechatType, WeechatError> {
-    match _type.as_ref() {
-        "chr" => parse_chr(read),
-        "int" => parse_int(read),
-        "lon" => parse_lon(read),
-        "str" => parse_str(read),
-        "buf" => parse_buf(read),
-        "ptr" => parse_ptr(read),
-        "tim" => parse_tim(read),
-        "htb" => parse_htb(read),
-        "hda" => parse_hda(read),
-        "inf" => parse_inf(read),
-        "inl" => parse_inl(read),
-        "arr" => parse_arr(read),
+    limits.check_depth(depth)?;
+    match _type {
+        b"chr" => parse_chr(buf),
+        b"int" => parse_int(buf),
+        b"lon" => parse_lon(buf),
+        b"str" => parse_str(buf),
+        b"buf" => parse_buf(buf),
+        b"ptr" => parse_ptr(buf),
+        b"tim" => parse_tim(buf),
+        b"htb" => parse_htb(buf, limits, depth),
+        b"hda" => parse_hda(buf, limits, depth),
+        b"inf" => parse_inf(buf),
+        b"inl" => parse_inl(buf, limits, depth),
+        b"arr" => parse_arr(buf, limits, depth),
         _ => Err(WeechatError {
             error: WeechatErrorType::UnsupportedType,
-            message: _type,
+            message: String::from_utf8_lossy(_type).into_owned(),
             trace: Backtrace::new(),
         }),
     }
 }
 
-fn parse_chr(read: &mut dyn Read) -> Result<WeechatType, WeechatError> {
-    let mut _read_res: Result<(), Error> = Ok(());
-    let buf = &mut [0u8; 1];
-    _read_res = read.read_exact(buf);
-    if _read_res.is_err() {
-        return Err(handle_io_error());
-    }
-    Ok(WeechatType::Char(buf[0] as i8))
+fn parse_chr(buf: &mut Bytes) -> Result<WeechatType, WeechatError> {
+    let chr = take_bytes(buf, 1, "chr")?;
+    Ok(WeechatType::Char(chr[0] as i8))
 }
 
-fn parse_int(read: &mut dyn Read) -> Result<WeechatType, WeechatError> {
-    let mut _read_res: Result<(), Error> = Ok(());
-    let buf = &mut [0u8; 4];
-    _read_res = read.read_exact(buf);
-    if _read_res.is_err() {
-        return Err(handle_io_error());
-    }
-    Ok(WeechatType::Int(BE::read_i32(buf)))
+fn parse_int(buf: &mut Bytes) -> Result<WeechatType, WeechatError> {
+    let int = take_bytes(buf, 4, "int")?;
+    Ok(WeechatType::Int(BE::read_i32(&int)))
 }
 
-fn parse_lon(read: &mut dyn Read) -> Result<WeechatType, WeechatError> {
-    Ok(WeechatType::Int(parse_str_int(read, 10)? as i32))
+fn parse_lon(buf: &mut Bytes) -> Result<WeechatType, WeechatError> {
+    Ok(WeechatType::Long(parse_str_int(buf, 10)?))
 }
 
-fn parse_str(read: &mut dyn Read) -> Result<WeechatType, WeechatError> {
-    Ok(WeechatType::String(parse_str_std(read)?))
+fn parse_str(buf: &mut Bytes) -> Result<WeechatType, WeechatError> {
+    Ok(WeechatType::String(parse_str_std(buf)?))
 }
 
-fn parse_buf(read: &mut dyn Read) -> Result<WeechatType, WeechatError> {
-    Ok(WeechatType::Buffer(parse_str_std(read)?.map(|s| s.into_bytes())))
+fn parse_buf(buf: &mut Bytes) -> Result<WeechatType, WeechatError> {
+    Ok(WeechatType::Buffer(parse_bytes_std(buf)?))
 }
 
-fn parse_ptr(read: &mut dyn Read) -> Result<WeechatType, WeechatError> {
-    Ok(WeechatType::Pointer(parse_str_int(read, 16)? as u128))
+fn parse_ptr(buf: &mut Bytes) -> Result<WeechatType, WeechatError> {
+    Ok(WeechatType::Pointer(Pointer(parse_str_int(buf, 16)? as u128)))
 }
 
-fn parse_tim(read: &mut dyn Read) -> Result<WeechatType, WeechatError> {
-    Ok(WeechatType::Time(parse_str_int(read, 10)? as u128))
+fn parse_tim(buf: &mut Bytes) -> Result<WeechatType, WeechatError> {
+    let secs = parse_str_int(buf, 10)?;
+    let secs = i64::try_from(secs).map_err(|_| {
+        WeechatError::new(
+            WeechatErrorType::InvalidNumber,
+            format!("Time {} out of range", secs),
+            Backtrace::new(),
+        )
+    })?;
+    Ok(WeechatType::Time(Timestamp(secs)))
 }
 
-fn parse_htb(read: &mut dyn Read) -> Result<WeechatType, WeechatError> {
-    let key_type = parse_type_string(read)?;
-    let val_type = parse_type_string(read)?;
-    let count = parse_u32(read)?;
-    let mut htb: Vec<(WeechatType, WeechatType)> = Vec::new();
+fn parse_htb(
+    buf: &mut Bytes,
+    limits: &ParseLimits,
+    depth: u32,
+) -> Result<WeechatType, WeechatError> {
+    let key_type = parse_type_string(buf)?;
+    let val_type = parse_type_string(buf)?;
+    let count = parse_u32(buf)?;
+    limits.check_elements(count, "htb")?;
+    let mut htb: Vec<(WeechatType, WeechatType)> = Vec::with_capacity(capacity(count, buf));
     for _ in 0..count {
         htb.push((
-            parse_weechat_type(key_type.clone(), read)?,
-            parse_weechat_type(val_type.clone(), read)?,
+            parse_weechat_type(&key_type, buf, limits, depth + 1)?,
+            parse_weechat_type(&val_type, buf, limits, depth + 1)?,
         ));
     }
     Ok(WeechatType::HashTable(htb))
 }
 
-fn parse_hda(read: &mut dyn Read) -> Result<WeechatType, WeechatError> {
-    let h_path = parse_hda_path(read)?;
-    let keys = parse_hda_keys(read)?;
-    let count = parse_u32(read)?;
-    let mut values: Vec<(Vec<WeechatType>, Vec<WeechatType>)> = Vec::new();
+fn parse_hda(
+    buf: &mut Bytes,
+    limits: &ParseLimits,
+    depth: u32,
+) -> Result<WeechatType, WeechatError> {
+    let h_path = parse_hda_path(buf)?;
+    let keys = parse_hda_keys(buf)?;
+    let count = parse_u32(buf)?;
+    limits.check_elements(count, "hda")?;
+    let mut values: Vec<(Vec<WeechatType>, Vec<WeechatType>)> =
+        Vec::with_capacity(capacity(count, buf));
 
     for _ in 0..count {
-        let mut p_path = Vec::new();
+        let mut p_path = Vec::with_capacity(h_path.len());
         for _ in 0..h_path.len() {
-            p_path.push(parse_ptr(read)?);
+            p_path.push(parse_ptr(buf)?);
         }
-        let mut vals: Vec<WeechatType> = Vec::new();
+        let mut vals: Vec<WeechatType> = Vec::with_capacity(keys.len());
         for v in &keys {
-            vals.push(parse_weechat_type(v.1.clone(), read)?);
+            vals.push(parse_weechat_type(v.1.as_bytes(), buf, limits, depth + 1)?);
         }
         values.push((p_path, vals));
     }
@@ -373,28 +730,28 @@ fn parse_hda(read: &mut dyn Read) -> Result<WeechatType, WeechatError> {
     Ok(WeechatType::Hdata(hda))
 }
 
-fn parse_inf(read: &mut dyn Read) -> Result<WeechatType, WeechatError> {
-    let name = match parse_str_std(read) {
-        Ok(n) => n,
-        Err(_) => return Err(handle_io_error()),
-    };
-    let value = match parse_str_std(read) {
-        Ok(n) => n,
-        Err(_) => return Err(handle_io_error()),
-    };
+fn parse_inf(buf: &mut Bytes) -> Result<WeechatType, WeechatError> {
+    let name = parse_str_std(buf)?;
+    let value = parse_str_std(buf)?;
     Ok(WeechatType::Info(name, value))
 }
 
-fn parse_inl(read: &mut dyn Read) -> Result<WeechatType, WeechatError> {
-    let name = parse_str_std(read)?;
-    let count = parse_u32(read)?;
-    let mut items = Vec::new();
+fn parse_inl(
+    buf: &mut Bytes,
+    limits: &ParseLimits,
+    depth: u32,
+) -> Result<WeechatType, WeechatError> {
+    let name = parse_str_std(buf)?;
+    let count = parse_u32(buf)?;
+    limits.check_elements(count, "inl")?;
+    let mut items = Vec::with_capacity(capacity(count, buf));
     for _ in 0..count {
-        let item_count = parse_u32(read)?;
-        let mut item = Vec::new();
+        let item_count = parse_u32(buf)?;
+        limits.check_elements(item_count, "inl item")?;
+        let mut item = Vec::with_capacity(capacity(item_count, buf));
         for _ in 0..item_count {
-            let iname = match parse_str_std(read)? {
-                WeechatString::Str(i) => i,
+            let iname = match parse_str_std(buf)? {
+                WeechatString::Str(i) => String::from_utf8_lossy(&i).into_owned(),
                 WeechatString::Null => {
                     return Err(WeechatError {
                         error: WeechatErrorType::HdataNullType,
@@ -404,8 +761,8 @@ fn parse_inl(read: &mut dyn Read) -> Result<WeechatType, WeechatError> {
                 }
             };
 
-            let _type = parse_type_string(read)?;
-            let obj = parse_weechat_type(_type, read)?;
+            let _type = parse_type_string(buf)?;
+            let obj = parse_weechat_type(&_type, buf, limits, depth + 1)?;
             item.push((iname, obj));
         }
         items.push(item);
@@ -413,21 +770,215 @@ fn parse_inl(read: &mut dyn Read) -> Result<WeechatType, WeechatError> {
     Ok(WeechatType::InfoList(name, items))
 }
 
-fn parse_arr(read: &mut dyn Read) -> Result<WeechatType, WeechatError> {
-    let _type = parse_type_string(read)?;
-    let len = parse_u32(read)?;
-    let mut res: Vec<WeechatType> = Vec::new();
+fn parse_arr(
+    buf: &mut Bytes,
+    limits: &ParseLimits,
+    depth: u32,
+) -> Result<WeechatType, WeechatError> {
+    let _type = parse_type_string(buf)?;
+    let len = parse_u32(buf)?;
+    limits.check_elements(len, "arr")?;
+    let mut res: Vec<WeechatType> = Vec::with_capacity(capacity(len, buf));
     for _ in 0..len {
-        res.push(parse_weechat_type(_type.clone(), read)?);
+        res.push(parse_weechat_type(&_type, buf, limits, depth + 1)?);
     }
     Ok(WeechatType::Array(res))
 }
 
+//
+// Encoding, mirrors the parse_* functions above
+//
+
+impl WeechatType {
+    // Three-char type signature used on the wire
+    pub fn type_string(&self) -> &'static str {
+        match self {
+            WeechatType::Char(_) => "chr",
+            WeechatType::Int(_) => "int",
+            WeechatType::Long(_) => "lon",
+            WeechatType::String(_) => "str",
+            WeechatType::Buffer(_) => "buf",
+            WeechatType::Pointer(_) => "ptr",
+            WeechatType::Time(_) => "tim",
+            WeechatType::HashTable(_) => "htb",
+            WeechatType::Hdata(_) => "hda",
+            WeechatType::Info(_, _) => "inf",
+            WeechatType::InfoList(_, _) => "inl",
+            WeechatType::Array(_) => "arr",
+        }
+    }
+
+    // Encode the value without its type signature
+    pub fn encode(&self, write: &mut dyn Write) -> Result<(), WeechatError> {
+        match self {
+            WeechatType::Char(c) => encode_chr(write, *c),
+            WeechatType::Int(i) => encode_int(write, *i),
+            WeechatType::Long(l) => encode_lon(write, *l),
+            WeechatType::String(s) => encode_str(write, s),
+            WeechatType::Buffer(b) => encode_buf(write, b),
+            WeechatType::Pointer(p) => encode_ptr(write, *p),
+            WeechatType::Time(t) => encode_tim(write, *t),
+            WeechatType::HashTable(htb) => encode_htb(write, htb),
+            WeechatType::Hdata(hda) => encode_hda(write, hda),
+            WeechatType::Info(name, value) => encode_inf(write, name, value),
+            WeechatType::InfoList(name, items) => encode_inl(write, name, items),
+            WeechatType::Array(arr) => encode_arr(write, arr),
+        }
+    }
+}
+
+fn encode_type_string(write: &mut dyn Write, _type: &str) -> Result<(), WeechatError> {
+    write.write_all(_type.as_bytes())?;
+    Ok(())
+}
+
+fn encode_str_int(write: &mut dyn Write, val: &str) -> Result<(), WeechatError> {
+    if val.len() > 0xFF {
+        return Err(WeechatError::new(
+            WeechatErrorType::ValueTooLong,
+            format!("Integer string {} does not fit in one length byte", val),
+            Backtrace::new(),
+        ));
+    }
+    write.write_all(&[val.len() as u8])?;
+    write.write_all(val.as_bytes())?;
+    Ok(())
+}
+
+fn encode_chr(write: &mut dyn Write, c: i8) -> Result<(), WeechatError> {
+    write.write_all(&[c as u8])?;
+    Ok(())
+}
+
+fn encode_int(write: &mut dyn Write, i: i32) -> Result<(), WeechatError> {
+    let buf = &mut [0u8; 4];
+    BE::write_i32(buf, i);
+    write.write_all(buf)?;
+    Ok(())
+}
+
+fn encode_lon(write: &mut dyn Write, l: i128) -> Result<(), WeechatError> {
+    encode_str_int(write, &l.to_string())
+}
+
+fn encode_str(write: &mut dyn Write, s: &WeechatString) -> Result<(), WeechatError> {
+    encode_str_std(write, s)
+}
+
+fn encode_buf(write: &mut dyn Write, b: &Option<Bytes>) -> Result<(), WeechatError> {
+    match b {
+        Some(b) => {
+            encode_u32(write, b.len() as u32)?;
+            write.write_all(b)?;
+            Ok(())
+        }
+        None => encode_u32(write, 0xFF_FF_FF_FF),
+    }
+}
+
+fn encode_ptr(write: &mut dyn Write, p: Pointer) -> Result<(), WeechatError> {
+    encode_str_int(write, &format!("{:x}", p))
+}
+
+fn encode_tim(write: &mut dyn Write, t: Timestamp) -> Result<(), WeechatError> {
+    encode_str_int(write, &t.to_string())
+}
+
+fn encode_htb(
+    write: &mut dyn Write,
+    htb: &[(WeechatType, WeechatType)],
+) -> Result<(), WeechatError> {
+    // Empty tables carry no type information, so fall back to strings
+    let key_type = htb.first().map_or("str", |(k, _)| k.type_string());
+    let val_type = htb.first().map_or("str", |(_, v)| v.type_string());
+    encode_type_string(write, key_type)?;
+    encode_type_string(write, val_type)?;
+    encode_u32(write, htb.len() as u32)?;
+    for (key, value) in htb {
+        check_type(key, key_type)?;
+        check_type(value, val_type)?;
+        key.encode(write)?;
+        value.encode(write)?;
+    }
+    Ok(())
+}
+
+fn encode_hda(write: &mut dyn Write, hda: &Hdata) -> Result<(), WeechatError> {
+    encode_str_std(write, &WeechatString::from(hda.h_path.join("/")))?;
+    let keys: Vec<String> =
+        hda.keys.iter().map(|(name, _type)| format!("{}:{}", name, _type)).collect();
+    encode_str_std(write, &WeechatString::from(keys.join(",")))?;
+    encode_u32(write, hda.values.len() as u32)?;
+
+    for (p_path, vals) in &hda.values {
+        if p_path.len() != hda.h_path.len() || vals.len() != hda.keys.len() {
+            return Err(WeechatError::new(
+                WeechatErrorType::HdataLengthMismatch,
+                format!(
+                    "Expected {} pointers and {} values, got {} and {}",
+                    hda.h_path.len(),
+                    hda.keys.len(),
+                    p_path.len(),
+                    vals.len()
+                ),
+                Backtrace::new(),
+            ));
+        }
+        for p in p_path {
+            check_type(p, "ptr")?;
+            p.encode(write)?;
+        }
+        for (v, (_, _type)) in vals.iter().zip(hda.keys.iter()) {
+            check_type(v, _type)?;
+            v.encode(write)?;
+        }
+    }
+    Ok(())
+}
+
+fn encode_inf(
+    write: &mut dyn Write,
+    name: &WeechatString,
+    value: &WeechatString,
+) -> Result<(), WeechatError> {
+    encode_str_std(write, name)?;
+    encode_str_std(write, value)
+}
+
+fn encode_inl(
+    write: &mut dyn Write,
+    name: &WeechatString,
+    items: &[Vec<(String, WeechatType)>],
+) -> Result<(), WeechatError> {
+    encode_str_std(write, name)?;
+    encode_u32(write, items.len() as u32)?;
+    for item in items {
+        encode_u32(write, item.len() as u32)?;
+        for (iname, obj) in item {
+            encode_str_std(write, &WeechatString::from(iname.as_str()))?;
+            encode_type_string(write, obj.type_string())?;
+            obj.encode(write)?;
+        }
+    }
+    Ok(())
+}
+
+fn encode_arr(write: &mut dyn Write, arr: &[WeechatType]) -> Result<(), WeechatError> {
+    let _type = arr.first().map_or("str", |v| v.type_string());
+    encode_type_string(write, _type)?;
+    encode_u32(write, arr.len() as u32)?;
+    for v in arr {
+        check_type(v, _type)?;
+        v.encode(write)?;
+    }
+    Ok(())
+}
+
 //
 // Actual composed Messages
 //
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 #[repr(C)]
 pub struct MessageHeader {
     pub length: u32,
@@ -436,19 +987,20 @@ pub struct MessageHeader {
 
 impl MessageHeader {
     pub fn parse(read: &mut dyn Read) -> Result<Option<Self>, WeechatError> {
-        let len_buf = &mut [0u8; 4];
-        // TODO: error checking?
-        read.read_exact(len_buf)?;
-        let compression = &mut [0u8; 1];
-        read.read_exact(compression)?;
-        Ok(Some(MessageHeader {
-            length: BE::read_u32(len_buf),
-            compression: compression[0],
-        }))
+        let buf = read_bytes(read, 5, "header")?;
+        Ok(MessageHeader::peek(&buf))
+    }
+
+    // Header at the start of buf, or None if fewer than 5 bytes are there yet
+    pub fn peek(buf: &[u8]) -> Option<Self> {
+        if buf.len() < 5 {
+            return None;
+        }
+        Some(MessageHeader { length: BE::read_u32(buf), compression: buf[4] })
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Message {
     header: MessageHeader,
     pub id: String,
@@ -456,86 +1008,260 @@ pub struct Message {
 }
 
 impl Message {
+    pub fn new(id: String, data: Vec<WeechatType>) -> Message {
+        Message { header: MessageHeader { length: 0, compression: 0 }, id, data }
+    }
+
+    // Encode the message with its header, returns the full frame length
+    pub fn encode(
+        &self,
+        write: &mut dyn Write,
+        compression: CompressionType,
+    ) -> Result<usize, WeechatError> {
+        let mut payload = Vec::new();
+        // Messages without an id are sent with a null id
+        let id = if self.id.is_empty() {
+            WeechatString::Null
+        } else {
+            WeechatString::from(self.id.as_str())
+        };
+        encode_str_std(&mut payload, &id)?;
+        for item in &self.data {
+            encode_type_string(&mut payload, item.type_string())?;
+            item.encode(&mut payload)?;
+        }
+
+        let (compression, payload) = match compression {
+            CompressionType::None => (0, payload),
+            CompressionType::Zlib => {
+                let mut enc = zlib::Encoder::new(Vec::new())?;
+                enc.write_all(&payload)?;
+                (1, enc.finish().into_result()?)
+            }
+            CompressionType::Zstd => {
+                (2, zstd::stream::encode_all(payload.as_slice(), 0)?)
+            }
+        };
+
+        let length = payload.len() + 5;
+        encode_u32(write, length as u32)?;
+        write.write_all(&[compression])?;
+        write.write_all(&payload)?;
+        Ok(length)
+    }
+
+    // Parse one frame. Once the header passes check_header the whole frame is
+    // consumed from read, even if its contents turn out to be malformed, so
+    // the next frame can still be read. Frames whose length is shorter than
+    // the header or over the limits, and frames cut short by the end of read,
+    // leave read in the middle of a frame and the stream can't be used after.
     pub fn parse(read: &mut dyn Read) -> Result<Option<Message>, WeechatError> {
-        MessageHeader::parse(read).and_then(|opt_header| {
-            match opt_header {
-                Some(header) => {
-                    let mut buffer = Vec::new();
-                    // TODO: error check this
-                    read.take(u64::from(header.length) - 5)
-                        .read_to_end(&mut buffer)
-                        .unwrap();
-                    let decompressed = match header.compression {
-                        0 => buffer,
-                        1 => {
-                            let mut dec =
-                                zlib::Decoder::new(buffer.as_slice()).unwrap();
-                            let mut dec_buf = Vec::new();
-                            dec.read_to_end(&mut dec_buf).unwrap();
-                            dec_buf
-                        }
-                        // TODO: error here
-                        _ => buffer,
-                    };
-                    let mut cursor = Cursor::new(decompressed);
+        Message::parse_with_limits(read, &ParseLimits::default())
+    }
 
-                    let id: String = match parse_str_std(&mut cursor)? {
-                        WeechatString::Str(i) => i,
-                        WeechatString::Null => "".to_owned(),
-                    };
-                    let mut data = Vec::new();
-                    while let Ok(parse) = parse_type_string(&mut cursor) {
-                        data.push(parse_weechat_type(parse, &mut cursor)?);
-                    }
-                    Ok(Some(Message { header, id, data }))
-                }
-                None => Ok(None),
+    pub fn parse_with_limits(
+        read: &mut dyn Read,
+        limits: &ParseLimits,
+    ) -> Result<Option<Message>, WeechatError> {
+        let header = match MessageHeader::parse(read)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        check_header(&header, limits)?;
+
+        let payload_length = header.length as usize - 5;
+        let payload = read_bytes(read, payload_length, "payload")?;
+        Message::parse_payload(header, Bytes::from(payload), limits).map(Some)
+    }
+
+    // Parse one complete frame, header included. Strings and buffers in the
+    // result point into frame (or its decompressed payload), nothing is copied.
+    pub fn parse_frame(frame: Bytes, limits: &ParseLimits) -> Result<Message, WeechatError> {
+        let header = match MessageHeader::peek(&frame) {
+            Some(header) => header,
+            None => {
+                return Err(WeechatError::new(
+                    WeechatErrorType::TruncatedFrame,
+                    format!("Frame of {} bytes is shorter than its header", frame.len()),
+                    Backtrace::new(),
+                ));
             }
-        })
+        };
+        check_header(&header, limits)?;
+        if header.length as usize != frame.len() {
+            return Err(WeechatError::new(
+                WeechatErrorType::TruncatedFrame,
+                format!(
+                    "Header gives a {} byte frame but {} bytes were passed",
+                    header.length,
+                    frame.len()
+                ),
+                Backtrace::new(),
+            ));
+        }
+        Message::parse_payload(header, frame.slice_from(5), limits)
+    }
+
+    // Parse the (possibly compressed) payload following an already read header
+    pub fn parse_payload(
+        header: MessageHeader,
+        payload: Bytes,
+        limits: &ParseLimits,
+    ) -> Result<Message, WeechatError> {
+        let mut buf = match header.compression {
+            0 => payload,
+            1 => Bytes::from(decompress_zlib(&payload, limits)?),
+            2 => Bytes::from(decompress_zstd(&payload, limits)?),
+            compression => {
+                return Err(WeechatError::new(
+                    WeechatErrorType::InvalidCompression,
+                    format!("Unknown compression byte {}", compression),
+                    Backtrace::new(),
+                ));
+            }
+        };
+
+        let id: String = match parse_str_std(&mut buf)? {
+            WeechatString::Str(i) => String::from_utf8_lossy(&i).into_owned(),
+            WeechatString::Null => "".to_owned(),
+        };
+        let mut data = Vec::new();
+        while !buf.is_empty() {
+            let parse = parse_type_string(&mut buf)?;
+            data.push(parse_weechat_type(&parse, &mut buf, limits, 0)?);
+        }
+        Ok(Message { header, id, data })
     }
 }
 
 //
 // Helper functions
 //
-fn handle_io_error() -> WeechatError {
-    WeechatError {
-        error: WeechatErrorType::IoError,
-        message: "".to_owned(),
-        trace: Backtrace::new(),
+// Read exactly len bytes of what, failing if the payload ends first
+fn read_bytes(read: &mut dyn Read, len: usize, what: &str) -> Result<Vec<u8>, WeechatError> {
+    let mut buf = Vec::new();
+    read.take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() != len {
+        return Err(WeechatError::new(
+            WeechatErrorType::TruncatedFrame,
+            format!("Payload ended {} bytes into a {} byte {}", buf.len(), len, what),
+            Backtrace::new(),
+        ));
     }
+    Ok(buf)
 }
 
-fn parse_str_std(read: &mut dyn Read) -> Result<WeechatString, WeechatError> {
-    let mut _read_res: Result<(), Error> = Ok(());
-    let len = parse_u32(read)?;
-    if len == 0xFF_FF_FF_FF {
-        // Null string
-        return Ok(WeechatString::Null);
+// Split len bytes of what off the front of buf, failing if the payload ends first
+fn take_bytes(buf: &mut Bytes, len: usize, what: &str) -> Result<Bytes, WeechatError> {
+    if buf.len() < len {
+        return Err(WeechatError::new(
+            WeechatErrorType::TruncatedFrame,
+            format!("Payload ended {} bytes into a {} byte {}", buf.len(), len, what),
+            Backtrace::new(),
+        ));
     }
-    let mut res = String::new();
-    let str_read_res = read.take(u64::from(len)).read_to_string(&mut res);
-    if str_read_res.is_err() {
-        println!("error: {:?}", str_read_res.err());
-        for _ in 0..100 {
-            println!("{:?}", Backtrace::new());
-        }
-        return Err(handle_io_error());
+    Ok(buf.split_to(len))
+}
+
+// Every element takes at least a byte, so this bounds preallocation by what
+// the payload could actually hold rather than by what it claims to
+fn capacity(count: u32, buf: &Bytes) -> usize {
+    (count as usize).min(buf.len())
+}
+
+fn check_header(header: &MessageHeader, limits: &ParseLimits) -> Result<(), WeechatError> {
+    if header.length < 5 {
+        return Err(WeechatError::new(
+            WeechatErrorType::TruncatedFrame,
+            format!("Frame length {} is shorter than its header", header.length),
+            Backtrace::new(),
+        ));
     }
-    Ok(WeechatString::Str(res))
+    limits.check_frame_size(header.length)
 }
 
-fn parse_u32(read: &mut dyn Read) -> Result<u32, WeechatError> {
-    let buf = &mut [0u8; 4];
-    match read.read_exact(buf) {
-        Ok(_) => Ok(BE::read_u32(buf)),
-        _ => Err(handle_io_error()),
+// Names in the protocol itself (hdata paths and keys) have to be UTF-8
+fn to_utf8(bytes: Bytes, what: &str) -> Result<String, WeechatError> {
+    str::from_utf8(&bytes).map(|s| s.to_owned()).map_err(|e| utf8_error(what, e))
+}
+
+fn utf8_error(what: &str, e: Utf8Error) -> WeechatError {
+    WeechatError::new(
+        WeechatErrorType::InvalidUtf8,
+        format!("Invalid UTF-8 in {}: {}", what, e),
+        Backtrace::new(),
+    )
+}
+
+fn decompress_zlib(buffer: &[u8], limits: &ParseLimits) -> Result<Vec<u8>, WeechatError> {
+    let dec = zlib::Decoder::new(buffer).map_err(|e| corrupt_error("zlib", e))?;
+    decompress_limited(dec, "zlib", limits)
+}
+
+fn decompress_zstd(buffer: &[u8], limits: &ParseLimits) -> Result<Vec<u8>, WeechatError> {
+    let dec =
+        zstd::stream::read::Decoder::new(buffer).map_err(|e| corrupt_error("zstd", e))?;
+    decompress_limited(dec, "zstd", limits)
+}
+
+// Inflate at most one byte past the limit, so bombs are caught without
+// ever holding more than that in memory
+fn decompress_limited<R: Read>(
+    dec: R,
+    compression: &str,
+    limits: &ParseLimits,
+) -> Result<Vec<u8>, WeechatError> {
+    let mut dec_buf = Vec::new();
+    let max = limits.max_decompressed_size;
+    dec.take(max as u64 + 1)
+        .read_to_end(&mut dec_buf)
+        .map_err(|e| corrupt_error(compression, e))?;
+    if dec_buf.len() > max {
+        return Err(limit_error(format!(
+            "{} payload decompresses to more than the {} byte limit",
+            compression, max
+        )));
     }
+    Ok(dec_buf)
+}
+
+fn corrupt_error(compression: &str, e: Error) -> WeechatError {
+    WeechatError::new(
+        WeechatErrorType::CorruptCompressedData,
+        format!("Corrupt {} payload: {}", compression, e),
+        Backtrace::new(),
+    )
+}
+
+fn limit_error(message: String) -> WeechatError {
+    WeechatError::new(WeechatErrorType::LimitExceeded, message, Backtrace::new())
+}
+
+// Length-prefixed bytes shared by str and buf, None for the null length
+fn parse_bytes_std(buf: &mut Bytes) -> Result<Option<Bytes>, WeechatError> {
+    let len = parse_u32(buf)?;
+    if len == 0xFF_FF_FF_FF {
+        return Ok(None);
+    }
+    take_bytes(buf, len as usize, "string").map(Some)
+}
+
+fn parse_str_std(buf: &mut Bytes) -> Result<WeechatString, WeechatError> {
+    match parse_bytes_std(buf)? {
+        Some(bytes) => Ok(WeechatString::Str(bytes)),
+        // Null string
+        None => Ok(WeechatString::Null),
+    }
+}
+
+fn parse_u32(buf: &mut Bytes) -> Result<u32, WeechatError> {
+    let len = take_bytes(buf, 4, "length")?;
+    Ok(BE::read_u32(&len))
 }
 
-fn parse_hda_path(read: &mut dyn Read) -> Result<Vec<String>, WeechatError> {
-    let base = match parse_str_std(read)? {
-        WeechatString::Str(e) => e,
+fn parse_hda_path(buf: &mut Bytes) -> Result<Vec<String>, WeechatError> {
+    let base = match parse_str_std(buf)? {
+        WeechatString::Str(e) => to_utf8(e, "hdata path")?,
         WeechatString::Null => {
             return Err(WeechatError::new(
                 WeechatErrorType::HdataNullId,
@@ -547,9 +1273,9 @@ fn parse_hda_path(read: &mut dyn Read) -> Result<Vec<String>, WeechatError> {
     Ok(base.split('/').map(|s| s.to_string()).collect())
 }
 
-fn parse_hda_keys(read: &mut dyn Read) -> Result<Vec<(String, String)>, WeechatError> {
-    let keys = match parse_str_std(read)? {
-        WeechatString::Str(e) => e,
+fn parse_hda_keys(buf: &mut Bytes) -> Result<Vec<(String, String)>, WeechatError> {
+    let keys = match parse_str_std(buf)? {
+        WeechatString::Str(e) => to_utf8(e, "hdata keys")?,
         WeechatString::Null => {
             return Err(WeechatError::new(
                 WeechatErrorType::HdataNullId,
@@ -558,11 +1284,229 @@ fn parse_hda_keys(read: &mut dyn Read) -> Result<Vec<(String, String)>, WeechatE
             ));
         }
     };
+    if keys.is_empty() {
+        return Ok(vec![]);
+    }
     let split_keys: Vec<&str> = keys.split(',').collect();
     let mut res: Vec<(String, String)> = Vec::new();
     for i in split_keys {
-        let i_split: Vec<&str> = i.split(':').collect();
-        res.push((i_split[0].to_string(), i_split[1].to_string()));
+        match i.split_once(':') {
+            Some((name, _type)) if !name.is_empty() && _type.len() == 3 => {
+                res.push((name.to_string(), _type.to_string()));
+            }
+            _ => {
+                return Err(WeechatError::new(
+                    WeechatErrorType::InvalidHdataKeys,
+                    format!("Expected name:type for hdata key {:?} in {:?}", i, keys),
+                    Backtrace::new(),
+                ));
+            }
+        }
     }
     Ok(res)
 }
+
+fn encode_str_std(write: &mut dyn Write, s: &WeechatString) -> Result<(), WeechatError> {
+    match s {
+        WeechatString::Null => encode_u32(write, 0xFF_FF_FF_FF),
+        WeechatString::Str(s) => {
+            encode_u32(write, s.len() as u32)?;
+            write.write_all(s)?;
+            Ok(())
+        }
+    }
+}
+
+fn encode_u32(write: &mut dyn Write, val: u32) -> Result<(), WeechatError> {
+    let buf = &mut [0u8; 4];
+    BE::write_u32(buf, val);
+    write.write_all(buf)?;
+    Ok(())
+}
+
+fn check_type(value: &WeechatType, _type: &str) -> Result<(), WeechatError> {
+    if value.type_string() == _type {
+        Ok(())
+    } else {
+        Err(WeechatError::new(
+            WeechatErrorType::TypeMismatch,
+            format!("Expected {} but found {:?}", _type, value),
+            Backtrace::new(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(data: Vec<WeechatType>, compression: CompressionType) -> Message {
+        let message = Message::new("id".to_owned(), data);
+        let mut frame = Vec::new();
+        let length = message.encode(&mut frame, compression).unwrap();
+        assert_eq!(length, frame.len());
+        Message::parse_frame(Bytes::from(frame), &ParseLimits::default()).unwrap()
+    }
+
+    fn string(s: &str) -> WeechatString {
+        WeechatString::from(s)
+    }
+
+    fn every_type() -> Vec<WeechatType> {
+        vec![
+            WeechatType::Char(b'A' as i8),
+            WeechatType::Char(-1),
+            WeechatType::Int(123_456),
+            WeechatType::Int(-42),
+            WeechatType::Long(1_234_567_890_123),
+            WeechatType::Long(-1),
+            WeechatType::String(string("a string")),
+            WeechatType::String(string("")),
+            WeechatType::String(WeechatString::Null),
+            WeechatType::String(WeechatString::from(vec![0xE9, b'!'])),
+            WeechatType::Buffer(Some(Bytes::from(&b"\x00\x01\x02"[..]))),
+            WeechatType::Buffer(None),
+            WeechatType::Pointer(Pointer::new(0x1a2b_3c4d)),
+            WeechatType::Pointer(Pointer::null()),
+            WeechatType::Time(Timestamp::from_secs(1_321_993_456)),
+            WeechatType::HashTable(vec![
+                (WeechatType::String(string("key1")), WeechatType::Int(1)),
+                (WeechatType::String(string("key2")), WeechatType::Int(2)),
+            ]),
+            WeechatType::HashTable(vec![]),
+            WeechatType::Hdata(Hdata {
+                h_path: vec!["buffer".to_owned(), "lines".to_owned()],
+                keys: vec![
+                    ("number".to_owned(), "int".to_owned()),
+                    ("name".to_owned(), "str".to_owned()),
+                ],
+                values: vec![
+                    (
+                        vec![
+                            WeechatType::Pointer(Pointer::new(0x1)),
+                            WeechatType::Pointer(Pointer::new(0x2)),
+                        ],
+                        vec![WeechatType::Int(1), WeechatType::String(string("core.weechat"))],
+                    ),
+                    (
+                        vec![
+                            WeechatType::Pointer(Pointer::new(0x3)),
+                            WeechatType::Pointer(Pointer::new(0x4)),
+                        ],
+                        vec![WeechatType::Int(2), WeechatType::String(WeechatString::Null)],
+                    ),
+                ],
+            }),
+            WeechatType::Info(string("version"), string("4.1.0")),
+            WeechatType::Info(string("nothing"), WeechatString::Null),
+            WeechatType::InfoList(
+                string("buffer"),
+                vec![
+                    vec![
+                        ("pointer".to_owned(), WeechatType::Pointer(Pointer::new(0xabc))),
+                        ("number".to_owned(), WeechatType::Int(1)),
+                    ],
+                    vec![],
+                ],
+            ),
+            WeechatType::Array(vec![
+                WeechatType::String(string("irc")),
+                WeechatType::String(string("python")),
+            ]),
+            WeechatType::Array(vec![WeechatType::Int(1), WeechatType::Int(2)]),
+            WeechatType::Array(vec![WeechatType::Array(vec![WeechatType::Char(1)])]),
+            WeechatType::Array(vec![]),
+        ]
+    }
+
+    #[test]
+    fn every_type_round_trips() {
+        for value in every_type() {
+            let message = round_trip(vec![value.clone()], CompressionType::None);
+            assert_eq!(message.id, "id");
+            assert_eq!(message.data, vec![value]);
+        }
+    }
+
+    #[test]
+    fn compressed_messages_round_trip() {
+        for compression in &[CompressionType::Zlib, CompressionType::Zstd] {
+            let message = round_trip(every_type(), *compression);
+            assert_eq!(message.data, every_type());
+        }
+    }
+
+    #[test]
+    fn empty_id_round_trips_as_null() {
+        let message = Message::new(String::new(), vec![WeechatType::Int(1)]);
+        let mut frame = Vec::new();
+        message.encode(&mut frame, CompressionType::None).unwrap();
+        assert_eq!(&frame[5..9], &[0xFF; 4]);
+        let parsed = Message::parse(&mut frame.as_slice()).unwrap().unwrap();
+        assert_eq!(parsed.id, "");
+        assert_eq!(parsed.data, vec![WeechatType::Int(1)]);
+    }
+
+    #[test]
+    fn map_passes_the_raw_bytes() {
+        // Long enough not to be stored inline, so the pointer shows no copy
+        let raw = b"caf\xe9 ".repeat(16);
+        let bytes = Bytes::from(raw.clone());
+        let ptr = bytes.as_ptr();
+        let mapped = WeechatString::from(bytes).map(|b| (b.as_ptr(), b.to_vec()));
+        assert_eq!(mapped, Some((ptr, raw)));
+        assert_eq!(WeechatString::Null.map(|b| b.len()), None);
+    }
+
+    #[test]
+    fn deep_nesting_is_refused() {
+        // A frame of arrays each holding the next one
+        let nested = |depth: usize| {
+            let mut frame = vec![0, 0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF];
+            frame.extend_from_slice(b"arr");
+            for _ in 0..depth {
+                frame.extend_from_slice(b"arr\x00\x00\x00\x01");
+            }
+            frame.extend_from_slice(b"int\x00\x00\x00\x01\x00\x00\x00\x07");
+            let length = frame.len() as u32;
+            BE::write_u32(&mut frame, length);
+            Message::parse_frame(Bytes::from(frame), &ParseLimits::default())
+        };
+
+        assert!(nested(31).is_ok());
+        let error = nested(32).unwrap_err().error;
+        assert!(matches!(error, WeechatErrorType::LimitExceeded));
+        let error = nested(5000).unwrap_err().error;
+        assert!(matches!(error, WeechatErrorType::LimitExceeded));
+    }
+
+    #[test]
+    fn longs_past_i32() {
+        // id "longs", lon 5000000000 and lon -2147483649, as the relay sends them
+        let mut frame = vec![0, 0, 0, 0, 0, 0, 0, 0, 5];
+        frame.extend_from_slice(b"longs");
+        frame.extend_from_slice(b"lon\x0a5000000000");
+        frame.extend_from_slice(b"lon\x0b-2147483649");
+        let length = frame.len() as u32;
+        BE::write_u32(&mut frame, length);
+
+        let message = Message::parse(&mut frame.as_slice()).unwrap().unwrap();
+        assert_eq!(message.id, "longs");
+        assert_eq!(
+            message.data,
+            vec![WeechatType::Long(5_000_000_000), WeechatType::Long(-2_147_483_649)]
+        );
+        assert_eq!(message.data[0].unwrap::<i64>(), Some(5_000_000_000));
+        assert_eq!(message.data[1].unwrap::<i64>(), Some(i64::from(i32::MIN) - 1));
+        assert_eq!(message.data[0].unwrap::<i32>(), None);
+    }
+
+    #[test]
+    fn mismatched_array_types_fail_to_encode() {
+        let arr = WeechatType::Array(vec![WeechatType::Int(1), WeechatType::Char(1)]);
+        let result = Message::new("id".to_owned(), vec![arr])
+            .encode(&mut Vec::new(), CompressionType::None);
+        let error = result.unwrap_err().error;
+        assert!(matches!(error, WeechatErrorType::TypeMismatch));
+    }
+}
@@ -0,0 +1,608 @@
+use crate::color::{self, Color, Span, Style, OPTION_NAMES};
+use std::env;
+use std::fmt::Write;
+
+// Turns colored relay strings (see the color module) into ANSI escape
+// sequences for terminals or HTML for web pages.
+
+// Colors a terminal can show. Basic WeeChat colors always use the 16 color
+// codes so they follow the terminal's theme, extended colors are sent as
+// they are or downgraded to the closest color the terminal has.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum ColorDepth {
+    Ansi16,
+    Ansi256,
+    TrueColor,
+}
+
+impl ColorDepth {
+    // Guess from COLORTERM and TERM like most terminal programs do
+    pub fn from_env() -> ColorDepth {
+        let colorterm = env::var("COLORTERM").unwrap_or_default();
+        let term = env::var("TERM").unwrap_or_default();
+        if colorterm == "truecolor" || colorterm == "24bit" {
+            ColorDepth::TrueColor
+        } else if term.contains("256color") {
+            ColorDepth::Ansi256
+        } else {
+            ColorDepth::Ansi16
+        }
+    }
+}
+
+// Defaults of the weechat.color.* options, as (foreground, background) in
+// the order of color::OPTION_NAMES. Empty backgrounds are left alone.
+const DEFAULT_OPTION_COLORS: &[(&str, &str)] = &[
+    ("blue", ""),
+    ("default", ""),
+    ("default", ""),
+    ("brown", ""),
+    ("yellow", ""),
+    ("magenta", ""),
+    ("white", ""),
+    ("lightgreen", ""),
+    ("lightred", ""),
+    ("lightmagenta", ""),
+    ("green", ""),
+    ("white", ""),
+    ("brown", ""),
+    ("white", ""),
+    ("lightcyan", ""),
+    ("white", ""),
+    ("cyan", ""),
+    ("default", ""),
+    ("default", ""),
+    ("default", ""),
+    ("default", ""),
+    ("default", ""),
+    ("default", ""),
+    ("default", ""),
+    ("default", ""),
+    ("default", ""),
+    ("default", ""),
+    ("cyan", ""),
+    ("green", ""),
+    ("yellow", "magenta"),
+    ("magenta", ""),
+    ("yellow", "lightmagenta"),
+    ("cyan", ""),
+    ("brown", ""),
+    ("red", ""),
+    ("default", ""),
+    ("default", ""),
+    ("default", ""),
+    ("default", ""),
+    ("default", ""),
+    ("green", ""),
+    ("green", ""),
+    ("yellow", "magenta"),
+    ("cyan", ""),
+    ("blue", ""),
+];
+
+// What the weechat.color.* options are set to. Starts with WeeChat's
+// defaults, set_option takes the user's values if the client fetched them.
+#[derive(Clone, Debug)]
+pub struct Palette {
+    options: Vec<(Color, Option<Color>)>,
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        let color = |name: &str| Color::from_name(name);
+        let options = DEFAULT_OPTION_COLORS
+            .iter()
+            .map(|(fg, bg)| (color(fg).unwrap_or(Color::Default), color(bg)))
+            .collect();
+        Palette { options }
+    }
+}
+
+impl Palette {
+    // Returns false if name isn't a color option of the relay protocol
+    pub fn set_option(&mut self, name: &str, fg: Color, bg: Option<Color>) -> bool {
+        match Color::from_option_name(name) {
+            Some(Color::Option(i)) => {
+                self.options[i as usize] = (resolved(fg), bg.map(resolved));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Foreground and background of an option, by its index in OPTION_NAMES
+    pub fn option(&self, index: u8) -> (Color, Option<Color>) {
+        self.options.get(index as usize).copied().unwrap_or((Color::Default, None))
+    }
+
+    // Options are looked up, the other colors are returned as they are
+    pub fn resolve(&self, style: &Style) -> (Option<Color>, Option<Color>) {
+        match style.fg {
+            Some(Color::Option(i)) => {
+                let (fg, bg) = self.option(i);
+                (Some(fg), style.bg.map(|bg| self.resolve_color(bg)).or(bg))
+            }
+            fg => (fg, style.bg.map(|bg| self.resolve_color(bg))),
+        }
+    }
+
+    fn resolve_color(&self, color: Color) -> Color {
+        match color {
+            Color::Option(i) => self.option(i).0,
+            color => color,
+        }
+    }
+}
+
+pub struct AnsiRenderer {
+    pub depth: ColorDepth,
+    pub palette: Palette,
+}
+
+impl AnsiRenderer {
+    pub fn new(depth: ColorDepth) -> AnsiRenderer {
+        AnsiRenderer { depth, palette: Palette::default() }
+    }
+
+    pub fn render(&self, s: &str) -> String {
+        self.render_spans(&color::parse(s))
+    }
+
+    // Every span sets its full style, and the output ends reset so it can
+    // be printed next to other text
+    pub fn render_spans(&self, spans: &[Span]) -> String {
+        let mut out = String::new();
+        let mut styled = false;
+        for span in spans {
+            if span.style.is_plain() {
+                if styled {
+                    out.push_str("\x1b[0m");
+                    styled = false;
+                }
+            } else {
+                out.push_str(&self.sgr(&span.style));
+                styled = true;
+            }
+            out.push_str(&span.text);
+        }
+        if styled {
+            out.push_str("\x1b[0m");
+        }
+        out
+    }
+
+    fn sgr(&self, style: &Style) -> String {
+        let mut codes = vec!["0".to_owned()];
+        if style.bold {
+            codes.push("1".to_owned());
+        }
+        if style.italic {
+            codes.push("3".to_owned());
+        }
+        if style.underline {
+            codes.push("4".to_owned());
+        }
+        if style.reverse {
+            codes.push("7".to_owned());
+        }
+        let (fg, bg) = self.palette.resolve(style);
+        codes.extend(fg.and_then(|fg| self.color_code(fg, false)));
+        codes.extend(bg.and_then(|bg| self.color_code(bg, true)));
+        format!("\x1b[{}m", codes.join(";"))
+    }
+
+    fn color_code(&self, color: Color, background: bool) -> Option<String> {
+        let (base, bright, extended) =
+            if background { (40, 100, 48) } else { (30, 90, 38) };
+        let ansi16 = |index: u8| match index {
+            0..=7 => (base + index as u32).to_string(),
+            _ => (bright + index as u32 - 8).to_string(),
+        };
+        match color {
+            Color::Default => Some((base + 9).to_string()),
+            Color::Basic(i) => {
+                BASIC_ANSI.get(i as usize).map(|index| ansi16(*index))
+            }
+            Color::Extended(n) if n < 256 => Some(match self.depth {
+                ColorDepth::Ansi16 => ansi16(nearest_ansi16(n as u8)),
+                ColorDepth::Ansi256 => format!("{};5;{}", extended, n),
+                ColorDepth::TrueColor => {
+                    let (r, g, b) = xterm_rgb(n as u8);
+                    format!("{};2;{};{};{}", extended, r, g, b)
+                }
+            }),
+            // Pairs and numbers past 255 depend on the terminal WeeChat runs in
+            _ => None,
+        }
+    }
+}
+
+// Spans become <span> elements with classes, so pages can theme them:
+//   weechat-fg-NAME / weechat-bg-NAME   basic colors and option names
+//   weechat-fg-N / weechat-bg-N         extended colors
+//   weechat-bold, -italic, -underline   attributes
+// Reverse swaps the color classes, using weechat-fg-default and
+// weechat-bg-default for sides without a color. stylesheet() has rules for
+// all of these.
+#[derive(Default)]
+pub struct HtmlRenderer {
+    pub palette: Palette,
+}
+
+impl HtmlRenderer {
+    pub fn render(&self, s: &str) -> String {
+        self.render_spans(&color::parse(s))
+    }
+
+    pub fn render_spans(&self, spans: &[Span]) -> String {
+        let mut out = String::new();
+        for span in spans {
+            let classes = classes(&span.style);
+            if classes.is_empty() {
+                escape_html(&mut out, &span.text);
+            } else {
+                let _ = write!(out, "<span class=\"{}\">", classes.join(" "));
+                escape_html(&mut out, &span.text);
+                out.push_str("</span>");
+            }
+        }
+        out
+    }
+
+    // CSS for every class render can produce, with options set to the
+    // palette's colors
+    pub fn stylesheet(&self) -> String {
+        let mut css = String::new();
+        css.push_str(".weechat-bold { font-weight: bold; }\n");
+        css.push_str(".weechat-italic { font-style: italic; }\n");
+        css.push_str(".weechat-underline { text-decoration: underline; }\n");
+        css.push_str(".weechat-fg-default { color: Canvas; }\n");
+        css.push_str(".weechat-bg-default { background-color: CanvasText; }\n");
+        // Options first, so a background set after an option wins over the
+        // option's own background
+        for (i, name) in OPTION_NAMES.iter().enumerate() {
+            if name.is_empty() {
+                continue;
+            }
+            let (fg, bg) = self.palette.option(i as u8);
+            let fg = color_rgb(fg).map_or("inherit".to_owned(), css_rgb);
+            match bg.and_then(color_rgb) {
+                Some(bg) => {
+                    let _ = writeln!(
+                        css,
+                        ".weechat-fg-{} {{ color: {}; background-color: {}; }}",
+                        name,
+                        fg,
+                        css_rgb(bg)
+                    );
+                }
+                None => {
+                    let _ =
+                        writeln!(css, ".weechat-fg-{} {{ color: {}; }}", name, fg);
+                }
+            }
+            let _ = writeln!(
+                css,
+                ".weechat-bg-{} {{ background-color: {}; }}",
+                name, fg
+            );
+        }
+        for (i, name) in color::BASIC_COLORS.iter().enumerate().skip(1) {
+            rules(&mut css, name, &css_rgb(xterm_rgb(BASIC_ANSI[i])));
+        }
+        for n in 0..=255u8 {
+            rules(&mut css, &n.to_string(), &css_rgb(xterm_rgb(n)));
+        }
+        css
+    }
+}
+
+//
+// Helper functions
+//
+
+// Basic WeeChat colors as 16 color terminal indices, like WeeChat maps them
+const BASIC_ANSI: &[u8] = &[0, 0, 8, 1, 9, 2, 10, 3, 11, 4, 12, 5, 13, 6, 14, 7, 15];
+
+// Options can't be set to another option or to a pair
+fn resolved(color: Color) -> Color {
+    match color {
+        Color::Option(_) | Color::Pair(_) => Color::Default,
+        color => color,
+    }
+}
+
+fn classes(style: &Style) -> Vec<String> {
+    let mut classes = Vec::new();
+    let (mut fg, mut bg) =
+        (style.fg.and_then(class_name), style.bg.and_then(class_name));
+    if style.reverse {
+        let swapped = (bg.unwrap_or_else(|| "default".to_owned()), fg);
+        fg = Some(swapped.0);
+        bg = Some(swapped.1.unwrap_or_else(|| "default".to_owned()));
+    }
+    classes.extend(fg.map(|fg| format!("weechat-fg-{}", fg)));
+    classes.extend(bg.map(|bg| format!("weechat-bg-{}", bg)));
+    if style.bold {
+        classes.push("weechat-bold".to_owned());
+    }
+    if style.italic {
+        classes.push("weechat-italic".to_owned());
+    }
+    if style.underline {
+        classes.push("weechat-underline".to_owned());
+    }
+    classes
+}
+
+fn class_name(color: Color) -> Option<String> {
+    match color {
+        Color::Default => None,
+        Color::Extended(n) if n < 256 => Some(n.to_string()),
+        color => color.name().map(str::to_owned),
+    }
+}
+
+fn rules(css: &mut String, name: &str, rgb: &str) {
+    let _ = writeln!(css, ".weechat-fg-{} {{ color: {}; }}", name, rgb);
+    let _ = writeln!(css, ".weechat-bg-{} {{ background-color: {}; }}", name, rgb);
+}
+
+fn escape_html(out: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+}
+
+// None for the terminal's default, which only the page or terminal knows
+fn color_rgb(color: Color) -> Option<(u8, u8, u8)> {
+    match color {
+        Color::Basic(i) => BASIC_ANSI.get(i as usize).map(|index| xterm_rgb(*index)),
+        Color::Extended(n) if n < 256 => Some(xterm_rgb(n as u8)),
+        _ => None,
+    }
+}
+
+fn css_rgb((r, g, b): (u8, u8, u8)) -> String {
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+// xterm's default palette: 16 system colors, a 6x6x6 cube and 24 grays
+fn xterm_rgb(n: u8) -> (u8, u8, u8) {
+    const SYSTEM: [(u8, u8, u8); 16] = [
+        (0, 0, 0),
+        (205, 0, 0),
+        (0, 205, 0),
+        (205, 205, 0),
+        (0, 0, 238),
+        (205, 0, 205),
+        (0, 205, 205),
+        (229, 229, 229),
+        (127, 127, 127),
+        (255, 0, 0),
+        (0, 255, 0),
+        (255, 255, 0),
+        (92, 92, 255),
+        (255, 0, 255),
+        (0, 255, 255),
+        (255, 255, 255),
+    ];
+    match n {
+        0..=15 => SYSTEM[n as usize],
+        16..=231 => {
+            let level = |v: u8| if v == 0 { 0 } else { 55 + v * 40 };
+            let n = n - 16;
+            (level(n / 36), level(n / 6 % 6), level(n % 6))
+        }
+        _ => {
+            let gray = 8 + (n - 232) * 10;
+            (gray, gray, gray)
+        }
+    }
+}
+
+// Closest of the 16 system colors to an extended color
+fn nearest_ansi16(n: u8) -> u8 {
+    if n < 16 {
+        return n;
+    }
+    let (r, g, b) = xterm_rgb(n);
+    let distance = |index: &u8| {
+        let (r2, g2, b2) = xterm_rgb(*index);
+        let d = |a: u8, b: u8| (i32::from(a) - i32::from(b)).pow(2);
+        d(r, r2) + d(g, g2) + d(b, b2)
+    };
+    (0..16).min_by_key(distance).unwrap_or(7)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn styled(style: Style) -> Vec<Span> {
+        vec![Span { text: "x".to_owned(), style }]
+    }
+
+    fn fg(color: Color) -> Style {
+        Style { fg: Some(color), ..Style::default() }
+    }
+
+    // The SGR sequence of a single styled span
+    fn sgr(depth: ColorDepth, style: Style) -> String {
+        let rendered = AnsiRenderer::new(depth).render_spans(&styled(style));
+        assert!(rendered.ends_with("x\x1b[0m"), "{:?}", rendered);
+        rendered.trim_end_matches("x\x1b[0m").to_owned()
+    }
+
+    const DEPTHS: [ColorDepth; 3] =
+        [ColorDepth::Ansi16, ColorDepth::Ansi256, ColorDepth::TrueColor];
+
+    #[test]
+    fn basic_colors_use_16_color_codes_at_every_depth() {
+        for depth in DEPTHS.iter().copied() {
+            assert_eq!(sgr(depth, fg(Color::Basic(3))), "\x1b[0;31m");
+            assert_eq!(sgr(depth, fg(Color::Basic(4))), "\x1b[0;91m");
+            assert_eq!(sgr(depth, fg(Color::Default)), "\x1b[0;39m");
+            let style = Style { bg: Some(Color::Basic(10)), ..fg(Color::Basic(1)) };
+            assert_eq!(sgr(depth, style), "\x1b[0;30;104m");
+        }
+    }
+
+    #[test]
+    fn extended_colors_follow_the_depth() {
+        let red = fg(Color::Extended(196));
+        assert_eq!(sgr(ColorDepth::Ansi16, red), "\x1b[0;91m");
+        assert_eq!(sgr(ColorDepth::Ansi256, red), "\x1b[0;38;5;196m");
+        assert_eq!(sgr(ColorDepth::TrueColor, red), "\x1b[0;38;2;255;0;0m");
+        let bg = Style { bg: Some(Color::Extended(21)), ..Style::default() };
+        assert_eq!(sgr(ColorDepth::Ansi16, bg), "\x1b[0;44m");
+        assert_eq!(sgr(ColorDepth::Ansi256, bg), "\x1b[0;48;5;21m");
+        assert_eq!(sgr(ColorDepth::TrueColor, bg), "\x1b[0;48;2;0;0;255m");
+        // Nothing the terminal could show, only the attributes are kept
+        for depth in DEPTHS.iter().copied() {
+            let style = Style { bold: true, ..fg(Color::Extended(300)) };
+            assert_eq!(sgr(depth, style), "\x1b[0;1m");
+            assert_eq!(sgr(depth, fg(Color::Pair(3))), "\x1b[0m");
+        }
+    }
+
+    #[test]
+    fn option_colors_use_the_palette() {
+        for depth in DEPTHS.iter().copied() {
+            // chat_nick is lightcyan, chat_highlight yellow on magenta
+            assert_eq!(sgr(depth, fg(Color::Option(14))), "\x1b[0;96m");
+            assert_eq!(sgr(depth, fg(Color::Option(29))), "\x1b[0;93;45m");
+            // A background after the option wins over the option's own
+            let style = Style { bg: Some(Color::Basic(9)), ..fg(Color::Option(29)) };
+            assert_eq!(sgr(depth, style), "\x1b[0;93;44m");
+        }
+        let mut renderer = AnsiRenderer::new(ColorDepth::Ansi16);
+        assert!(renderer.palette.set_option("chat_nick", Color::Extended(196), None));
+        let expected = [
+            (ColorDepth::Ansi16, "\x1b[0;91mx\x1b[0m"),
+            (ColorDepth::Ansi256, "\x1b[0;38;5;196mx\x1b[0m"),
+            (ColorDepth::TrueColor, "\x1b[0;38;2;255;0;0mx\x1b[0m"),
+        ];
+        for (depth, sgr) in expected.iter() {
+            renderer.depth = *depth;
+            assert_eq!(renderer.render_spans(&styled(fg(Color::Option(14)))), *sgr);
+        }
+    }
+
+    #[test]
+    fn attributes_and_resets() {
+        let style = Style {
+            bold: true,
+            reverse: true,
+            italic: true,
+            underline: true,
+            ..Style::default()
+        };
+        assert_eq!(sgr(ColorDepth::Ansi16, style), "\x1b[0;1;3;4;7m");
+        let renderer = AnsiRenderer::new(ColorDepth::Ansi16);
+        assert_eq!(renderer.render("plain"), "plain");
+        assert_eq!(renderer.render("a\x19F03b\x1Cc"), "a\x1b[0;31mb\x1b[0mc");
+    }
+
+    #[test]
+    fn xterm_palette() {
+        assert_eq!(xterm_rgb(0), (0, 0, 0));
+        assert_eq!(xterm_rgb(12), (92, 92, 255));
+        assert_eq!(xterm_rgb(16), (0, 0, 0));
+        assert_eq!(xterm_rgb(21), (0, 0, 255));
+        assert_eq!(xterm_rgb(196), (255, 0, 0));
+        assert_eq!(xterm_rgb(110), (135, 175, 215));
+        assert_eq!(xterm_rgb(231), (255, 255, 255));
+        assert_eq!(xterm_rgb(232), (8, 8, 8));
+        assert_eq!(xterm_rgb(255), (238, 238, 238));
+    }
+
+    #[test]
+    fn nearest_16_colors() {
+        for n in 0..16 {
+            assert_eq!(nearest_ansi16(n), n);
+        }
+        assert_eq!(nearest_ansi16(196), 9);
+        assert_eq!(nearest_ansi16(21), 4);
+        assert_eq!(nearest_ansi16(231), 15);
+        assert_eq!(nearest_ansi16(232), 0);
+        assert_eq!(nearest_ansi16(244), 8);
+    }
+
+    #[test]
+    fn html_classes_and_escaping() {
+        let html = HtmlRenderer::default();
+        assert_eq!(
+            html.render("<a href=\"x\">&'"),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&#39;"
+        );
+        assert_eq!(
+            html.render("\x19F*03<b>\x1C!"),
+            "<span class=\"weechat-fg-red weechat-bold\">&lt;b&gt;</span>!"
+        );
+        let style = Style { bg: Some(Color::Extended(21)), ..fg(Color::Option(14)) };
+        assert_eq!(
+            html.render_spans(&styled(style)),
+            "<span class=\"weechat-fg-chat_nick weechat-bg-21\">x</span>"
+        );
+        assert_eq!(html.render_spans(&styled(fg(Color::Default))), "x");
+    }
+
+    #[test]
+    fn html_reverse_swaps_classes() {
+        let html = HtmlRenderer::default();
+        let reverse = |fg: Option<Color>, bg: Option<Color>| {
+            let style = Style { fg, bg, reverse: true, ..Style::default() };
+            html.render_spans(&styled(style))
+        };
+        assert_eq!(
+            reverse(Some(Color::Basic(3)), None),
+            "<span class=\"weechat-fg-default weechat-bg-red\">x</span>"
+        );
+        assert_eq!(
+            reverse(None, Some(Color::Basic(9))),
+            "<span class=\"weechat-fg-blue weechat-bg-default\">x</span>"
+        );
+        assert_eq!(
+            reverse(Some(Color::Basic(3)), Some(Color::Extended(21))),
+            "<span class=\"weechat-fg-21 weechat-bg-red\">x</span>"
+        );
+        assert_eq!(
+            reverse(None, None),
+            "<span class=\"weechat-fg-default weechat-bg-default\">x</span>"
+        );
+    }
+
+    #[test]
+    fn set_option() {
+        let mut palette = Palette::default();
+        assert_eq!(palette.option(14), (Color::Basic(14), None));
+        assert_eq!(palette.option(29), (Color::Basic(8), Some(Color::Basic(11))));
+        assert!(!palette.set_option("nope", Color::Basic(3), None));
+        assert!(!palette.set_option("", Color::Basic(3), None));
+        assert!(palette.set_option("chat_nick", Color::Extended(196), Some(Color::Basic(9))));
+        assert_eq!(palette.option(14), (Color::Extended(196), Some(Color::Basic(9))));
+        // Options and pairs can't be option values
+        assert!(palette.set_option("chat", Color::Option(14), Some(Color::Pair(2))));
+        assert_eq!(palette.option(1), (Color::Default, Some(Color::Default)));
+        // Out of range indices fall back to the default colors
+        assert_eq!(palette.option(200), (Color::Default, None));
+
+        let html = HtmlRenderer { palette };
+        let css = html.stylesheet();
+        assert!(css.contains(
+            ".weechat-fg-chat_nick { color: #ff0000; background-color: #0000ee; }\n"
+        ));
+        assert!(css.contains(".weechat-bg-chat_nick { background-color: #ff0000; }\n"));
+        assert!(css.contains(".weechat-fg-chat { color: inherit; }\n"));
+        assert!(css.contains(".weechat-fg-red { color: #cd0000; }\n"));
+        assert!(css.contains(".weechat-bg-255 { background-color: #eeeeee; }\n"));
+    }
+}
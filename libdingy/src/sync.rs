@@ -1,6 +1,6 @@
 use crate::message;
 use backtrace::Backtrace;
-use message::WeechatString;
+use message::{Pointer, Timestamp, WeechatString};
 use std::io::Error;
 
 #[derive(Debug)]
@@ -62,12 +62,16 @@ pub enum SyncMessage {
     BufferLocalvarChanged(BufferLocalvarChanged),
     BufferLocalvarRemoved(BufferLocalvarRemoved),
     BufferLineAdded(BufferLineAdded),
+    BufferLineDataChanged(BufferLineDataChanged),
     BufferClosing(BufferClosing),
     Nicklist(Nicklist),
     NicklistDiff(NicklistDiff),
     Pong(WeechatString),
     Upgrade,
     UpgradeEnded,
+    // Sync ids this version doesn't know, e.g. from a newer WeeChat, with
+    // the item they came with
+    Unknown { id: String, data: message::WeechatType },
 }
 
 pub trait SyncHdataItem<T> {
@@ -152,6 +156,11 @@ impl SyncMessage {
             "_buffer_line_added" => BufferLineAdded::parse_into(item, |a| {
                 SyncMessage::BufferLineAdded(a.clone())
             }),
+            "_buffer_line_data_changed" => {
+                BufferLineDataChanged::parse_into(item, |a| {
+                    SyncMessage::BufferLineDataChanged(a.clone())
+                })
+            }
             "_buffer_closing" => BufferClosing::parse_into(item, |a| {
                 SyncMessage::BufferClosing(a.clone())
             }),
@@ -172,19 +181,21 @@ impl SyncMessage {
             },
             "_upgrade" => Ok(vec![SyncMessage::Upgrade]),
             "_upgrade_ended" => Ok(vec![SyncMessage::UpgradeEnded]),
-            _ => Err(SyncError {
-                error: SyncErrorType::InvalidId,
-                message: format!("Unknown sync id {}", id).to_owned(),
-                trace: Backtrace::new(),
-            }),
+            _ => Ok(vec![SyncMessage::Unknown {
+                id: id.to_owned(),
+                data: item.clone(),
+            }]),
         }
     }
 }
 
 // Macro that lets us define weechat sync data structures with their name and fields,
-// will generate a parse method that turns an Hdata into a Result<$name>
+// will generate a parse method that turns an Hdata into a Result<$name>.
+// Fields in the optional block are keys only some WeeChat versions send,
+// they end up as Option<$type> and are None when the key is missing.
 macro_rules! sync_struct {
-    ($name: ident {$($field : ident: $type : ty;)*}) => {
+    ($name: ident {$($field : ident: $type : ty;)*}
+     $(optional {$($ofield : ident: $otype : ty;)*})?) => {
 
         // Define the structure to have all public fields (for ease of use)
         #[derive(Debug, Clone)]
@@ -192,6 +203,9 @@ macro_rules! sync_struct {
             $(
                 pub $field: $type,
             )*
+            $($(
+                pub $ofield: Option<$otype>,
+            )*)?
         }
 
         impl SyncHdataItem<$name> for $name {
@@ -211,21 +225,35 @@ macro_rules! sync_struct {
             fn parse_one(data: &message::Hdata, index: usize) -> Result<$name, SyncError> {
                 // Get all fields out of the hdata with their correct types and make sure they worked
                 $(
-                    let $field = data.get::<$type>(index, stringify!($field));
-                    if $field.is_none() {
-                        return Err(SyncError{
+                    let $field = data.try_get::<$type>(index, stringify!($field)).map_err(|e| {
+                        SyncError{
                             error: SyncErrorType::InvalidData,
-                            message: stringify!($field, " expected Some").to_owned(),
+                            message: format!("{}: {}", stringify!($name), e),
                             trace: Backtrace::new()
-                        });
-                    }
+                        }
+                    })?;
                 )*
+                $($(
+                    let $ofield = match data.column(stringify!($ofield)) {
+                        Some(_) => Some(data.try_get::<$otype>(index, stringify!($ofield)).map_err(|e| {
+                            SyncError{
+                                error: SyncErrorType::InvalidData,
+                                message: format!("{}: {}", stringify!($name), e),
+                                trace: Backtrace::new()
+                            }
+                        })?),
+                        None => None,
+                    };
+                )*)?
 
                 // Then just send off the new object!
                 Ok($name{
                     $(
-                        $field: $field.unwrap(),
+                        $field,
                     )*
+                    $($(
+                        $ofield,
+                    )*)?
                 })
             }
         }
@@ -239,43 +267,43 @@ sync_struct!(BufferOpened {
     nicklist: i32;
     title: WeechatString;
     local_variables: Vec<(WeechatString, WeechatString)>;
-    prev_buffer: u128;
-    next_buffer: u128;
+    prev_buffer: Pointer;
+    next_buffer: Pointer;
 });
 
 sync_struct!(BufferMoved {
     number: i32;
     full_name: WeechatString;
-    prev_buffer: u128;
-    next_buffer: u128;
+    prev_buffer: Pointer;
+    next_buffer: Pointer;
 });
 
 sync_struct!(BufferMerged {
     number: i32;
     full_name: WeechatString;
-    prev_buffer: u128;
-    next_buffer: u128;
+    prev_buffer: Pointer;
+    next_buffer: Pointer;
 });
 
 sync_struct!(BufferUnmerged {
     number: i32;
     full_name: WeechatString;
-    prev_buffer: u128;
-    next_buffer: u128;
+    prev_buffer: Pointer;
+    next_buffer: Pointer;
 });
 
 sync_struct!(BufferHidden {
     number: i32;
     full_name: WeechatString;
-    prev_buffer: u128;
-    next_buffer: u128;
+    prev_buffer: Pointer;
+    next_buffer: Pointer;
 });
 
 sync_struct!(BufferUnhidden {
     number: i32;
     full_name: WeechatString;
-    prev_buffer: u128;
-    next_buffer: u128;
+    prev_buffer: Pointer;
+    next_buffer: Pointer;
 });
 
 sync_struct!(BufferRenamed {
@@ -321,14 +349,39 @@ sync_struct!(BufferLocalvarRemoved {
 });
 
 sync_struct!(BufferLineAdded {
-    buffer: u128;
-    date: u128;
-    date_printed: u128;
+    buffer: Pointer;
+    date: Timestamp;
+    date_printed: Timestamp;
+    displayed: bool;
+    highlight: bool;
+    tags_array: Vec<WeechatString>;
+    prefix: WeechatString;
+    message: WeechatString;
+} optional {
+    id: i32;
+    y: i32;
+    date_usec: i32;
+    date_printed_usec: i32;
+    notify_level: i8;
+});
+
+// Sent when a line is edited in place, like lines of free content buffers
+// or messages changed by a script. id (or y) says which line it was.
+sync_struct!(BufferLineDataChanged {
+    buffer: Pointer;
+    date: Timestamp;
+    date_printed: Timestamp;
     displayed: bool;
     highlight: bool;
     tags_array: Vec<WeechatString>;
     prefix: WeechatString;
     message: WeechatString;
+} optional {
+    id: i32;
+    y: i32;
+    date_usec: i32;
+    date_printed_usec: i32;
+    notify_level: i8;
 });
 
 sync_struct!(BufferClosing {
@@ -357,3 +410,109 @@ sync_struct!(NicklistDiff {
     prefix_color: WeechatString;
 
 });
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use message::{Hdata, Message, WeechatType};
+
+    // A _buffer_line_added item with the keys every WeeChat sends plus extra
+    fn line_added(extra: Vec<(&str, &str, WeechatType)>) -> WeechatType {
+        let tags = vec![WeechatType::String("irc_privmsg".into())];
+        let mut keys = vec![
+            ("buffer", "ptr", WeechatType::Pointer(Pointer::new(0x10))),
+            ("date", "tim", WeechatType::Time(Timestamp::from_secs(1_700_000_000))),
+            ("date_printed", "tim", WeechatType::Time(Timestamp::from_secs(1_700_000_001))),
+            ("displayed", "chr", WeechatType::Char(1)),
+            ("highlight", "chr", WeechatType::Char(0)),
+            ("tags_array", "arr", WeechatType::Array(tags)),
+            ("prefix", "str", WeechatType::String("nick".into())),
+            ("message", "str", WeechatType::String("hello".into())),
+        ];
+        keys.extend(extra);
+        WeechatType::Hdata(Hdata {
+            h_path: vec!["line_data".into()],
+            keys: keys.iter().map(|(k, t, _)| ((*k).into(), (*t).into())).collect(),
+            values: vec![(
+                vec![WeechatType::Pointer(Pointer::new(0x20))],
+                keys.into_iter().map(|(_, _, v)| v).collect(),
+            )],
+        })
+    }
+
+    fn line(item: &WeechatType) -> BufferLineAdded {
+        match SyncMessage::parse_message_item("_buffer_line_added", item).unwrap().as_slice() {
+            [SyncMessage::BufferLineAdded(line)] => line.clone(),
+            messages => panic!("Expected one line, got {:?}", messages),
+        }
+    }
+
+    #[test]
+    fn unknown_ids() {
+        let message = Message::new("_foo".to_owned(), vec![WeechatType::Int(3)]);
+        match SyncMessage::parse(&message).unwrap().as_slice() {
+            [item] => match item.as_slice() {
+                [SyncMessage::Unknown { id, data: WeechatType::Int(3) }] => {
+                    assert_eq!(id, "_foo")
+                }
+                messages => panic!("Expected an unknown message, got {:?}", messages),
+            },
+            items => panic!("Expected one item, got {:?}", items),
+        }
+    }
+
+    #[test]
+    fn optional_keys() {
+        let old = line(&line_added(vec![]));
+        assert_eq!(old.buffer, Pointer::new(0x10));
+        assert_eq!(old.date.secs(), 1_700_000_000);
+        assert!(old.displayed);
+        assert!(!old.highlight);
+        assert_eq!(old.tags_array.len(), 1);
+        assert_eq!(old.message.to_str(), "hello");
+        assert_eq!((old.id, old.y, old.date_usec, old.notify_level), (None, None, None, None));
+
+        let new = line(&line_added(vec![
+            ("id", "int", WeechatType::Int(42)),
+            ("notify_level", "chr", WeechatType::Char(2)),
+        ]));
+        assert_eq!(new.id, Some(42));
+        assert_eq!(new.notify_level, Some(2));
+        assert_eq!(new.y, None);
+    }
+
+    #[test]
+    fn malformed_syncs_are_errors() {
+        let invalid = |id: &str, item: &WeechatType| {
+            match SyncMessage::parse_message_item(id, item) {
+                Err(e) => e,
+                Ok(messages) => panic!("{} gave {:?}", id, messages),
+            }
+        };
+
+        let e = invalid("_buffer_line_added", &WeechatType::Int(1));
+        assert!(matches!(e.error, SyncErrorType::InvalidData));
+
+        // A key every WeeChat sends is missing
+        let mut item = line_added(vec![]);
+        if let WeechatType::Hdata(hdata) = &mut item {
+            hdata.keys.pop();
+            hdata.values[0].1.pop();
+        }
+        let e = invalid("_buffer_line_added", &item);
+        assert!(matches!(e.error, SyncErrorType::InvalidData));
+        assert!(e.message.starts_with("BufferLineAdded: No key message"), "{}", e.message);
+
+        // Optional keys still have to have the right type
+        let item = line_added(vec![("id", "str", WeechatType::String("42".into()))]);
+        let e = invalid("_buffer_line_added", &item);
+        assert!(matches!(e.error, SyncErrorType::InvalidData));
+        assert_eq!(e.message, "BufferLineAdded: id in row 0 is a str which doesn't fit i32");
+
+        let e = invalid("_buffer_closing", &line_added(vec![]));
+        assert!(e.message.starts_with("BufferClosing: No key number"), "{}", e.message);
+
+        let e = invalid("_pong", &WeechatType::Int(1));
+        assert!(matches!(e.error, SyncErrorType::InvalidId));
+    }
+}
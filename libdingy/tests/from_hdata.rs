@@ -0,0 +1,191 @@
+use libdingy::hdata::{FromHdata, HdataError, HdataErrorType};
+use libdingy::message::{Hdata, Pointer, WeechatString, WeechatType};
+
+// Two buffers as read by "hdata buffer:gui_buffers(*)/lines", so every row
+// has a buffer and a lines pointer
+fn buffers() -> Hdata {
+    let row = |buffer: u128, lines: u128, number: i32, name: &str, title: WeechatType| {
+        (
+            vec![
+                WeechatType::Pointer(Pointer::new(buffer)),
+                WeechatType::Pointer(Pointer::new(lines)),
+            ],
+            vec![
+                WeechatType::Int(number),
+                WeechatType::String(name.into()),
+                title,
+                WeechatType::Char(1),
+            ],
+        )
+    };
+    Hdata {
+        h_path: vec!["buffer".into(), "lines".into()],
+        keys: vec![
+            ("number".into(), "int".into()),
+            ("full_name".into(), "str".into()),
+            ("title".into(), "str".into()),
+            ("hidden".into(), "chr".into()),
+        ],
+        values: vec![
+            row(0x10, 0x11, 1, "core.weechat", WeechatType::String("Welcome".into())),
+            row(0x20, 0x21, 2, "irc.libera", WeechatType::String("Libera".into())),
+        ],
+    }
+}
+
+fn error<T: std::fmt::Debug>(result: Result<T, HdataError>) -> HdataError {
+    match result {
+        Err(e) => e,
+        Ok(value) => panic!("Expected an error, got {:?}", value),
+    }
+}
+
+#[derive(FromHdata, Debug)]
+struct Buffer {
+    #[hdata(pointer)]
+    lines: Pointer,
+    #[hdata(pointer = "buffer")]
+    pointer: Pointer,
+    number: i32,
+    #[hdata(rename = "full_name")]
+    name: String,
+    title: Option<String>,
+    hidden: bool,
+    // Not in the hdata
+    short_name: Option<String>,
+    #[hdata(default)]
+    notify: i32,
+}
+
+#[test]
+fn reads_every_row() {
+    let rows = Buffer::from_hdata_rows(&buffers()).unwrap();
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0].pointer, Pointer::new(0x10));
+    assert_eq!(rows[0].lines, Pointer::new(0x11));
+    assert_eq!(rows[0].number, 1);
+    assert_eq!(rows[0].name, "core.weechat");
+    assert_eq!(rows[0].title.as_deref(), Some("Welcome"));
+    assert!(rows[0].hidden);
+    assert_eq!(rows[0].short_name, None);
+    assert_eq!(rows[0].notify, 0);
+    assert_eq!(rows[1].pointer, Pointer::new(0x20));
+    assert_eq!(rows[1].name, "irc.libera");
+
+    let item = WeechatType::Hdata(buffers());
+    assert_eq!(Buffer::from_weechat_type(&item).unwrap().len(), 2);
+    let e = error(Buffer::from_weechat_type(&WeechatType::Int(1)));
+    assert!(matches!(e.error, HdataErrorType::NotHdata));
+    assert_eq!(e.message, "Expected hda but found int");
+}
+
+// A key that is there but null is still an error for an Option field,
+// only missing keys are None
+#[test]
+fn option_fields_check_present_values() {
+    let mut hdata = buffers();
+    hdata.values[1].1[2] = WeechatType::String(WeechatString::Null);
+    let e = error(Buffer::from_hdata(&hdata, 1));
+    assert!(matches!(e.error, HdataErrorType::TypeMismatch));
+    assert_eq!(e.message, "Buffer.title is a null str which doesn't fit alloc::string::String");
+}
+
+fn shout(value: &WeechatType) -> Result<String, String> {
+    match value.unwrap::<String>() {
+        Some(s) => Ok(s.to_uppercase()),
+        None => Err("expected a string".to_owned()),
+    }
+}
+
+#[derive(FromHdata, Debug)]
+struct Shouted {
+    #[hdata(rename = "full_name", with = "shout")]
+    name: String,
+    #[hdata(with = "shout")]
+    title: Option<String>,
+}
+
+#[test]
+fn with_converts_values() {
+    let row = Shouted::from_hdata(&buffers(), 0).unwrap();
+    assert_eq!(row.name, "CORE.WEECHAT");
+    assert_eq!(row.title.as_deref(), Some("WELCOME"));
+
+    #[derive(FromHdata, Debug)]
+    #[allow(dead_code)]
+    struct Number {
+        #[hdata(with = "shout")]
+        number: String,
+    }
+    let e = error(Number::from_hdata(&buffers(), 0));
+    assert!(matches!(e.error, HdataErrorType::Conversion));
+    assert_eq!(e.message, "Number.number (int): expected a string");
+}
+
+#[test]
+fn missing_and_mistyped_keys() {
+    #[derive(FromHdata, Debug)]
+    #[allow(dead_code)]
+    struct Missing {
+        number: i32,
+        nicklist: bool,
+    }
+    let e = error(Missing::from_hdata(&buffers(), 0));
+    assert!(matches!(e.error, HdataErrorType::MissingKey));
+    assert_eq!(
+        e.message,
+        "Missing.nicklist is missing, the hdata only has keys number,full_name,title,hidden"
+    );
+
+    #[derive(FromHdata, Debug)]
+    #[allow(dead_code)]
+    struct Mistyped {
+        #[hdata(rename = "full_name")]
+        name: i32,
+    }
+    let e = error(Mistyped::from_hdata(&buffers(), 0));
+    assert!(matches!(e.error, HdataErrorType::TypeMismatch));
+    assert_eq!(e.message, "Mistyped.full_name is a str which doesn't fit i32");
+
+    #[derive(FromHdata, Debug)]
+    #[allow(dead_code)]
+    struct Window {
+        #[hdata(pointer = "window")]
+        window: Pointer,
+    }
+    let e = error(Window::from_hdata(&buffers(), 0));
+    assert!(matches!(e.error, HdataErrorType::MissingPointer));
+    assert_eq!(e.message, "Window needs the window pointer but the hdata path is buffer/lines");
+
+    let e = error(Buffer::from_hdata(&buffers(), 2));
+    assert!(matches!(e.error, HdataErrorType::IndexOutOfRange));
+    assert_eq!(e.message, "Buffer row 2 is past the 2 rows in the hdata");
+}
+
+// Fields named after from_hdata's own parameters and locals
+#[derive(FromHdata)]
+struct Shadowing {
+    index: i32,
+    hdata: String,
+    value: Option<i32>,
+    #[hdata(rename = "index")]
+    again: i32,
+}
+
+#[test]
+fn fields_can_shadow_parameters() {
+    let hdata = Hdata {
+        h_path: vec!["buffer".into()],
+        keys: vec![("index".into(), "int".into()), ("hdata".into(), "str".into())],
+        values: vec![(
+            vec![WeechatType::Pointer(Pointer::new(1))],
+            vec![WeechatType::Int(7), WeechatType::String("core".into())],
+        )],
+    };
+
+    let row = Shadowing::from_hdata(&hdata, 0).unwrap();
+    assert_eq!(row.index, 7);
+    assert_eq!(row.hdata, "core");
+    assert_eq!(row.value, None);
+    assert_eq!(row.again, 7);
+}
@@ -1,16 +1,18 @@
 use libdingy::command::Command;
 use libdingy::message::Message;
 use libdingy::message::MessageHeader;
+use libdingy::message::ParseLimits;
 use bytes::BufMut;
 use bytes::BytesMut;
-use std::io::Cursor;
 use tokio::codec::{Decoder, Encoder};
 
-pub struct WeechatCodec;
+pub struct WeechatCodec {
+    limits: ParseLimits,
+}
 
 impl WeechatCodec {
-    pub fn new() -> WeechatCodec {
-        WeechatCodec {}
+    pub fn new(limits: ParseLimits) -> WeechatCodec {
+        WeechatCodec { limits }
     }
 }
 
@@ -22,27 +24,29 @@ impl Decoder for WeechatCodec {
         &mut self,
         src: &mut BytesMut,
     ) -> Result<Option<Self::Item>, Self::Error> {
-        if src.len() < 5 {
-            return Ok(None);
-        }
-        let mut header_cursor = Cursor::new(&src);
-        let opt_header = MessageHeader::parse(&mut header_cursor)?;
-
-        if let Some(header) = opt_header {
-            if src.len() < header.length as usize {
+        // One malformed frame shouldn't take the connection down with it, the
+        // header still tells us where the next one starts
+        loop {
+            let header = match MessageHeader::peek(src) {
+                Some(header) => header,
+                None => return Ok(None),
+            };
+            let length = header.length as usize;
+            // Refuse oversized frames before buffering them. Their end can't be
+            // found without reading them, so the connection can't recover.
+            self.limits.check_frame_size(header.length)?;
+            if src.len() < length {
                 return Ok(None);
             }
-        } else {
-            return Ok(None);
-        }
 
-        println!("{:?}", src.as_mut());
-
-        let mut cursor = Cursor::new(&src);
-        let parsed = Message::parse(&mut cursor);
-        src.split_to(cursor.position() as _);
-
-        parsed.map_err(|werr| Self::Error::from(werr))
+            // Drop too-short frames along with their header, so we always advance
+            let mut frame = src.split_to(length.max(5)).freeze();
+            let payload = frame.split_off(5);
+            match Message::parse_payload(header, payload, &self.limits) {
+                Ok(message) => return Ok(Some(message)),
+                Err(werr) => eprintln!("Dropping malformed message: {}", werr),
+            }
+        }
     }
 }
 
@@ -60,3 +64,31 @@ impl Encoder for WeechatCodec {
         Ok(dst.put(value))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libdingy::command::CompressionType;
+    use libdingy::message::WeechatType;
+
+    #[test]
+    fn skips_malformed_frames() {
+        let mut src = BytesMut::new();
+        // Empty frames with an unknown compression byte
+        for _ in 0..10_000 {
+            src.extend_from_slice(&[0, 0, 0, 5, 9]);
+        }
+        let mut frame = vec![];
+        Message::new("ok".to_owned(), vec![WeechatType::Int(1)])
+            .encode(&mut frame, CompressionType::None)
+            .unwrap();
+        src.extend_from_slice(&frame);
+
+        let mut codec = WeechatCodec::new(ParseLimits::default());
+        let message = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(message.id, "ok");
+        assert_eq!(message.data, vec![WeechatType::Int(1)]);
+        assert!(src.is_empty());
+        assert!(codec.decode(&mut src).unwrap().is_none());
+    }
+}
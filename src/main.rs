@@ -6,11 +6,22 @@ extern crate rand;
 extern crate tokio;
 extern crate libdingy;
 
+use libdingy::auth::*;
 use libdingy::command::*;
+use libdingy::hdata::FromHdata;
+use libdingy::message::Pointer;
+use libdingy::render::{AnsiRenderer, ColorDepth};
 use libdingy::sync::*;
 use crate::server::CommandSender;
+use crate::server::Keepalive;
+use crate::server::ReconnectPolicy;
+use crate::server::ServerError;
+use crate::server::ServerErrorType;
+use crate::server::SyncEvent;
 use crate::server::WeechatServer;
-use futures::future::lazy;
+use crate::tls::TlsConfig;
+use backtrace::Backtrace;
+use futures::future::{err, lazy, Either};
 use futures::sync::mpsc;
 use std::env;
 use std::io;
@@ -21,12 +32,25 @@ use libdingy::command::CommandType::Infolist;
 
 mod codec;
 pub mod server;
+pub mod tls;
+
+// One row of the buffer list asked for at startup
+#[derive(FromHdata)]
+struct BufferListItem {
+    #[hdata(pointer)]
+    pointer: Pointer,
+    number: i32,
+    name: String,
+    // Not asked for, so always None
+    short_name: Option<String>,
+}
 
 fn main() {
     let env_server_addr = env::var("server");
     let env_password = env::var("password");
 
     let server_addr = if let Some(server_addr) = env_server_addr
+        .as_ref()
         .ok()
         .and_then(|addr| addr.to_socket_addrs().ok())
         .and_then(|mut addrs| addrs.next())
@@ -43,19 +67,40 @@ fn main() {
         return;
     };
 
+    // Only needed for relays with a TOTP secret set
+    let totp = match env::var("totp_secret") {
+        Ok(secret) => match Totp::from_base32(&secret) {
+            Some(totp) => Some(totp),
+            None => {
+                println!("Env totp_secret must be a base32 TOTP secret");
+                return;
+            }
+        },
+        Err(_) => None,
+    };
+
+    // Bad TLS settings shouldn't quietly fall back to plain text
+    let tls = match tls_config(env_server_addr.as_ref().unwrap()) {
+        Ok(tls) => tls,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
     let (stdin_tx, stdin_rx) = mpsc::channel(0);
     thread::spawn(|| read_stdin(stdin_tx));
     let stdin_rx = stdin_rx.map_err(|_| panic!("errors not possible on rx"));
 
     println!("Addr: {:?}", server_addr);
-    let server = WeechatServer::new(&server_addr);
-
-    let init_command = InitCommand::new(
-        Some("login".into()),
-        Some(password.to_owned()),
-        Some(CompressionType::None),
-    );
-    init_command.encode(&mut std::io::stdout()).unwrap();
+    let mut builder = WeechatServer::builder(&server_addr)
+        .reconnect(ReconnectPolicy::default())
+        .keepalive(Keepalive::default())
+        .login(move |tx| login(tx, password.clone(), totp.clone()));
+    if let Some(tls) = tls {
+        builder = builder.tls(tls);
+    }
+    let server = builder.connect();
 
     let send_task = stdin_rx
         .fold(server.sender(), |tx, data| {
@@ -71,7 +116,11 @@ fn main() {
                             message.into(),
                         );
                         input_command.encode(&mut std::io::stdout()).unwrap();
-                        Box::new(tx.send(input_command).map(|(tx, _)| tx))
+                        Box::new(
+                            tx.send(input_command)
+                                .map(|(tx, _)| tx)
+                                .map_err(|e| println!("Input error: {}", e)),
+                        )
                     } else {
                         Box::new(lazy(|| Ok(tx)))
                     }
@@ -84,123 +133,239 @@ fn main() {
         .map_err(|_| ());
 
     let sync = server.sync();
-    let init_task = server
-        .send(init_command)
-        .and_then(|(tx, _)| {
-            // Send stuff on separate thread.
-            thread::spawn(move || {
-                // TODO: Move test somewhere else
-                let test_command = TestCommand::new(Some("aaa".into()));
-                test_command.encode(&mut std::io::stdout()).unwrap();
-                let commands_task = tx
-                    .send(test_command)
-                    .and_then(|(tx, msg)| {
-                        println!("Got message: {:?}", msg);
-
-                        let sync_command = SyncCommand::new(None, vec![]);
-                        sync_command.encode(&mut std::io::stdout()).unwrap();
-                        tx.send(sync_command)
-                    })
-                    .and_then(|(tx, msg)| {
-                        println!("Got message: {:?}", msg);
-
-                        let ping_command = PingCommand::new(
-                            None,
-                            Some(vec!["abcdefg".into()]),
-                        );
-                        ping_command.encode(&mut std::io::stdout()).unwrap();
-                        tx.send(ping_command)
-                    })
-                    .and_then(|(tx, msg)| {
-                        println!("Got message: {:?}", msg);
+    let events = server.events();
+    let ansi = AnsiRenderer::new(ColorDepth::from_env());
+    // Queued until the first login is done
+    let tx = server.sender();
+    let init_task = lazy(move || {
+        // Send stuff on separate thread.
+        thread::spawn(move || {
+            // TODO: Move test somewhere else
+            let test_command = TestCommand::new(Some("aaa".into()));
+            test_command.encode(&mut std::io::stdout()).unwrap();
+            let commands_task = tx
+                .send(test_command)
+                .and_then(|(tx, msg)| {
+                    println!("Got message: {:?}", msg);
 
-                        let inl_command = InfoListCommand::new(
-                            None,
-                            "buffer".into(),
-                            None,
-                            None
-                        );
-                        inl_command.encode(&mut std::io::stdout()).unwrap();
-                        tx.send(inl_command)
-                    })
-                    .and_then(|(tx, msg)| {
-                        println!("Got message: {:?}", msg);
-
-                        let info_command =
-                            InfoCommand::new(None, "version".to_owned());
-                        info_command.encode(&mut std::io::stdout()).unwrap();
-                        tx.send(info_command)
-                    })
-                    .and_then(|(tx, msg)| {
-                        println!("Got message: {:?}", msg);
-
-                        let hdata_command = HdataCommand::new(
-                            Some("HDATA HERE".to_owned()),
-                            "buffer".into(),
-                            (
-                                "gui_buffers".into(),
-                                Some(HdataCommandLength::Infinite),
-                            ),
-                            vec![],
-                            Some(vec!["number".into(), "name".into()]),
-                        );
-                        hdata_command.encode(&mut std::io::stdout()).unwrap();
-                        tx.send(hdata_command)
-                    })
-                    .and_then(|(tx, msg)| {
-                        println!("Got message: {:?}", msg);
-
-                        let nick_command = NicklistCommand::new(
-                            Some("nicks".to_owned()),
-                            None,
-                        );
-                        nick_command.encode(&mut std::io::stdout()).unwrap();
-                        tx.send(nick_command)
-                    })
-                    .and_then(|(tx, msg)| {
-                        println!("Got message: {:?}", msg);
+                    let sync_command = SyncCommand::new(None, vec![]);
+                    sync_command.encode(&mut std::io::stdout()).unwrap();
+                    tx.send(sync_command)
+                })
+                .and_then(|(tx, msg)| {
+                    println!("Got message: {:?}", msg);
 
-                        Ok(())
-                    })
-                    .then(|_| Ok(()));
+                    let ping_command = PingCommand::new(
+                        None,
+                        Some(vec!["abcdefg".into()]),
+                    );
+                    ping_command.encode(&mut std::io::stdout()).unwrap();
+                    tx.send(ping_command)
+                })
+                .and_then(|(tx, msg)| {
+                    println!("Got message: {:?}", msg);
 
-                tokio::run(commands_task);
-            });
+                    let inl_command = InfoListCommand::new(
+                        None,
+                        "buffer".into(),
+                        None,
+                        None
+                    );
+                    inl_command.encode(&mut std::io::stdout()).unwrap();
+                    tx.send(inl_command)
+                })
+                .and_then(|(tx, msg)| {
+                    println!("Got message: {:?}", msg);
 
-            Ok(())
-        })
-        .map_err(|_| ())
-        .join(
-            sync.for_each(|syncs| {
-                println!("Sync message:");
-                for m in &*syncs {
-                    match m {
-                        SyncMessage::BufferLineAdded(bla) => {
-                            println!(
-                                "<{}>: {}",
-                                bla.prefix.to_str(),
-                                bla.message.to_str()
-                            );
-                        }
-                        SyncMessage::Nicklist(nl) => {
-                            println!("{:?}", nl);
-                        }
-                        _ => {
-                            println!("{:?}", m);
+                    let info_command =
+                        InfoCommand::new(None, "version".to_owned());
+                    info_command.encode(&mut std::io::stdout()).unwrap();
+                    tx.send(info_command)
+                })
+                .and_then(|(tx, msg)| {
+                    println!("Got message: {:?}", msg);
+
+                    let hdata_command = HdataPath::buffer()
+                        .id("HDATA HERE")
+                        .list("gui_buffers")
+                        .all()
+                        .keys(["number", "name"])
+                        .build()
+                        .unwrap();
+                    hdata_command.encode(&mut std::io::stdout()).unwrap();
+                    tx.send(hdata_command)
+                })
+                .and_then(|(tx, msg)| {
+                    let buffers = msg.as_ref().and_then(|msg| msg.data.first());
+                    match buffers.map(BufferListItem::from_weechat_type) {
+                        Some(Ok(buffers)) => {
+                            for b in buffers {
+                                println!(
+                                    "Buffer {} {} ({}, short name {:?})",
+                                    b.number, b.name, b.pointer, b.short_name
+                                );
+                            }
                         }
+                        Some(Err(e)) => println!("Bad buffer list: {}", e),
+                        None => println!("Got message: {:?}", msg),
+                    }
+
+                    let nick_command = NicklistCommand::new(
+                        Some("nicks".to_owned()),
+                        None,
+                    );
+                    nick_command.encode(&mut std::io::stdout()).unwrap();
+                    tx.send(nick_command)
+                })
+                .and_then(|(tx, msg)| {
+                    println!("Got message: {:?}", msg);
+
+                    Ok(())
+                })
+                .map_err(|e| println!("Command error: {}", e))
+                .then(|_| Ok(()));
+
+            tokio::run(commands_task);
+        });
+
+        Ok::<(), ()>(())
+    })
+    .join(
+        sync.for_each(move |event| {
+            let syncs = match event {
+                SyncEvent::Messages(syncs) => syncs,
+                event => {
+                    println!("Sync event: {:?}", event);
+                    return Ok(());
+                }
+            };
+            println!("Sync message:");
+            for m in &*syncs {
+                match m {
+                    SyncMessage::BufferLineAdded(bla) => {
+                        println!(
+                            "<{}>: {}",
+                            ansi.render(&bla.prefix.to_str()),
+                            ansi.render(&bla.message.to_str())
+                        );
+                    }
+                    SyncMessage::Nicklist(nl) => {
+                        println!("{:?}", nl);
+                    }
+                    _ => {
+                        println!("{:?}", m);
                     }
                 }
+            }
 
-                Ok(())
-            })
-            .map_err(|_| ()),
-        )
-        .join(send_task)
-        .then(|_| Ok(()));
+            Ok(())
+        })
+        .map_err(|_| ()),
+    )
+    .join(events.for_each(|event| {
+        println!("Connection: {:?}", event);
+        Ok(())
+    }))
+    .join(send_task)
+    .then(|_| Ok(()));
 
     tokio::run(init_task);
 }
 
+// TLS from env tls=1, tls_ca_file=<pem>, tls_fingerprint=<sha256> or
+// tls_insecure=1, any of which turns it on. The certificate has to be for
+// the host in env server.
+fn tls_config(server: &str) -> Result<Option<TlsConfig>, tls::TlsError> {
+    let ca_file = env::var("tls_ca_file").ok();
+    let fingerprint = env::var("tls_fingerprint").ok();
+    let insecure = env::var("tls_insecure").is_ok();
+    if env::var("tls").is_err() && ca_file.is_none() && fingerprint.is_none() && !insecure
+    {
+        return Ok(None);
+    }
+
+    // host:port, or [v6 address]:port
+    let host = server.rsplit_once(':').map_or(server, |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let mut tls = TlsConfig::new(host)?;
+    if let Some(ca_file) = ca_file {
+        tls = tls.ca_file(ca_file)?;
+    }
+    if let Some(fingerprint) = fingerprint {
+        tls = tls.fingerprint(&fingerprint)?;
+    }
+    if insecure {
+        tls = tls.insecure();
+    }
+    Ok(Some(tls))
+}
+
+// Handshake then init, on every new connection to the relay
+fn login(
+    tx: CommandSender,
+    password: String,
+    totp: Option<Totp>,
+) -> impl Future<Item = CommandSender, Error = ServerError> {
+    let handshake_command = HandshakeCommand::new(
+        None,
+        Some(vec![
+            PasswordHashAlgo::Plain,
+            PasswordHashAlgo::Sha256,
+            PasswordHashAlgo::Sha512,
+            PasswordHashAlgo::Pbkdf2Sha256,
+            PasswordHashAlgo::Pbkdf2Sha512,
+        ]),
+        Some(vec![
+            CompressionType::Zstd,
+            CompressionType::Zlib,
+            CompressionType::None,
+        ]),
+        None,
+    );
+    handshake_command.encode(&mut std::io::stdout()).unwrap();
+
+    tx.handshake(handshake_command)
+        .and_then(move |(tx, response)| {
+            println!("Handshake: {:?}", response);
+
+            // Only send the plain password if that's what was negotiated
+            let (password, password_hash) = match response.password_hash_algo {
+                Some(PasswordHashAlgo::Plain) => (Some(password), None),
+                algo => match PasswordHash::from_handshake(&response, &password) {
+                    Some(hash) => (None, Some(hash)),
+                    None => {
+                        return Either::A(err(ServerError::new(
+                            ServerErrorType::InvalidResponse,
+                            format!("No password hash to log in with, relay chose {:?}", algo),
+                            Backtrace::new(),
+                        )))
+                    }
+                },
+            };
+            let totp_code = if response.totp {
+                match &totp {
+                    Some(totp) => Some(totp.generate()),
+                    None => {
+                        println!("Relay requires TOTP, define env totp_secret");
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+            let init_command = InitCommand::new(
+                Some("login".into()),
+                password,
+                password_hash,
+                totp_code,
+                response.compression,
+            );
+            init_command.encode(&mut std::io::stdout()).unwrap();
+            Either::B(tx.send(init_command))
+        })
+        .map(|(tx, _)| tx)
+}
+
 // Our helper method which will read data from stdin and send it along the
 // sender provided.
 fn read_stdin(mut tx: mpsc::Sender<Vec<u8>>) {
@@ -1,94 +1,412 @@
 use crate::codec::WeechatCodec;
+use crate::tls::TlsConfig;
+use crate::tls::Transport;
+use libdingy::auth::HandshakeResponse;
 use libdingy::command::Command;
+use libdingy::command::HandshakeCommand;
+use libdingy::command::PingCommand;
 use libdingy::message::Message;
+use libdingy::message::ParseLimits;
+use libdingy::message::WeechatString;
 use libdingy::sync::SyncMessage;
+use backtrace::Backtrace;
 use futures::future::*;
 use futures::stream::iter_ok;
+use futures::stream::once;
 use futures::sync::mpsc;
 use futures::sync::mpsc::*;
-use futures::task::Task;
+use futures::sync::oneshot;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use std::collections::HashMap;
-use std::collections::HashSet;
 use std::hash::Hash;
 use std::hash::Hasher;
+use std::io::Write;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 use tokio::codec::Framed;
 use tokio::net::TcpStream;
 use tokio::prelude::*;
+use tokio::timer::{timeout, Delay, Timeout};
 
 type BoxCommand = Box<Command + Send>;
 
+// Logs in on a fresh connection. Commands sent through the given sender go
+// out before anything queued by the rest of the program.
+type Login = dyn Fn(CommandSender) -> BoxLogin + Send + Sync;
+type BoxLogin = Box<dyn Future<Item = CommandSender, Error = ServerError> + Send>;
+
+// How long a command waits for its reply unless told otherwise
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum ServerErrorType {
+    Timeout,
+    DuplicateId,
+    Disconnected,
+    InvalidResponse,
+    Other,
+}
+
+#[derive(Constructor, Debug)]
+pub struct ServerError {
+    pub error: ServerErrorType,
+    pub message: String,
+    pub trace: Backtrace,
+}
+
+impl std::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ServerError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+impl From<ServerError> for std::io::Error {
+    fn from(serr: ServerError) -> Self {
+        std::io::Error::other(serr)
+    }
+}
+
+// What sync subscribers get: the relay's sync messages, and word of the
+// connection dropping and coming back. Syncs are sent again after
+// reconnecting, but whatever happened in between is lost.
+#[derive(Clone, Debug)]
+pub enum SyncEvent {
+    Messages(Arc<Vec<SyncMessage>>),
+    Disconnected,
+    Reconnected,
+}
+
+// Where the connection is at, see WeechatServer::events. Every Connecting is
+// followed by exactly one of AuthFailed or Disconnected, unless the server
+// stops first.
+#[derive(Clone, Debug)]
+pub enum ConnectionEvent {
+    Connecting,
+    Connected,
+    // The relay answered a ping sent right after the login. Only sent when
+    // the builder has a login.
+    Authenticated,
+    // The relay closed the connection after the login and before answering
+    AuthFailed,
+    Disconnected(DisconnectReason),
+    // No more connections will be made
+    ShuttingDown,
+}
+
+#[derive(Clone, Debug)]
+pub enum DisconnectReason {
+    // No connection could be made
+    ConnectFailed(String),
+    // The TLS handshake failed, e.g. on an untrusted certificate
+    TlsFailed(String),
+    // The relay closed the connection
+    Closed,
+    // Reading from or writing to the connection failed
+    Error(String),
+    // The login failed without the relay closing the connection
+    LoginFailed(String),
+    // Too many keepalive pings in a row went unanswered
+    PingTimeout,
+}
+
+// How long to wait before each reconnect attempt: initial_delay, growing by
+// multiplier after each failed attempt up to max_delay. Up to jitter (0 to 1)
+// of every delay is random, so clients dropped together don't all come back
+// at once.
+#[derive(Clone, Copy, Debug)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: f64,
+    pub jitter: f64,
+    // Failed attempts in a row before giving up, None to never give up
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: 0.5,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    // Delay before the next attempt after failed ones, None to give up
+    pub fn delay(&self, failed: u32) -> Option<Duration> {
+        if self.max_attempts.is_some_and(|max| failed >= max) {
+            return None;
+        }
+        let max = self.max_delay.as_secs_f64();
+        let exponent = failed.min(i32::MAX as u32) as i32;
+        let delay =
+            self.initial_delay.as_secs_f64() * self.multiplier.powi(exponent);
+        let delay = delay.min(max);
+        let jitter = self.jitter.clamp(0.0, 1.0) * thread_rng().gen::<f64>();
+        // A negative multiplier or NaN jitter gives a delay that isn't one,
+        // and a max_delay near Duration::MAX doesn't survive f64 rounding
+        let delay = Duration::try_from_secs_f64(delay * (1.0 - jitter));
+        Some(delay.unwrap_or(self.max_delay))
+    }
+}
+
+// Ping the relay every interval once logged in, and drop the connection
+// when max_missed pings in a row get no pong within the interval. Keeps NAT
+// mappings alive on idle connections and notices the ones that died anyway.
+#[derive(Clone, Copy, Debug)]
+pub struct Keepalive {
+    pub interval: Duration,
+    pub max_missed: u32,
+}
+
+impl Default for Keepalive {
+    fn default() -> Keepalive {
+        Keepalive { interval: Duration::from_secs(30), max_missed: 3 }
+    }
+}
+
+// Options for a WeechatServer, see WeechatServer::builder
+pub struct ServerBuilder {
+    addr: SocketAddr,
+    limits: ParseLimits,
+    timeout: Duration,
+    reconnect: Option<ReconnectPolicy>,
+    keepalive: Option<Keepalive>,
+    tls: Option<TlsConfig>,
+    login: Option<Arc<Login>>,
+}
+
 // Weechat server connection
 pub struct WeechatServer {
-    command_tx: Sender<BoxCommand>,
+    command_tx: Sender<Queued>,
     pending: Arc<Mutex<PendingList>>,
+    timeout: Duration,
 }
 
 // Future for sent commands, returned by .send()
 // Future param is a tuple (tx: CommandSender, msg: Message)
+// Dropping it before the reply comes forgets the command, and the reply is
+// then ignored.
 pub struct SendCommand {
     id: String,
-    tx: Sender<BoxCommand>,
-    has_response: bool,
+    // The token for pings, whose reply is matched by it instead of id
+    pong: Option<String>,
     pending: Arc<Mutex<PendingList>>,
+    timeout: Duration,
+    // Covers waiting to be sent as well as waiting for the reply
+    deadline: Delay,
+    sending: Option<futures::sink::Send<Sender<Queued>>>,
+    // Set when we stop waiting before the connection took the command
+    cancelled: Arc<AtomicBool>,
+    tx: Option<Sender<Queued>>,
+    // None for commands without a reply
+    reply: Option<oneshot::Receiver<Message>>,
 }
 
 // Helper class for sending commands in futures (for chaining)
 #[derive(Clone)]
 pub struct CommandSender {
-    tx: Sender<BoxCommand>,
+    tx: Sender<Queued>,
     pending: Arc<Mutex<PendingList>>,
+    timeout: Duration,
 }
 
 // Private mutable state for pending data
 struct PendingList {
-    messages: HashMap<String, Message>,
-    commands: HashSet<String>,
-    tasks: Vec<Task>,
-    receivers: Vec<Sender<Arc<Vec<SyncMessage>>>>,
+    // Where to deliver the reply to each command waiting for one, by id
+    requests: HashMap<String, Waiter>,
+    receivers: Vec<Sender<SyncEvent>>,
+    listeners: Vec<UnboundedSender<ConnectionEvent>>,
+    // The last event, for listeners that come later
+    state: Option<ConnectionEvent>,
+    // Round trip of the latest ping on this connection
+    latency: Option<Duration>,
+    // Where to deliver the pong to each ping, ours or sent through a
+    // CommandSender, by the token it echoes back
+    pongs: HashMap<String, Waiter>,
+    // sync and desync commands as sent, to send again after reconnecting
+    syncs: Vec<Vec<u8>>,
 }
 
 impl PendingList {
     pub fn new() -> PendingList {
         PendingList {
-            messages: HashMap::<String, Message>::new(),
-            commands: HashSet::<String>::new(),
-            tasks: Vec::<Task>::new(),
-            receivers: Vec::<Sender<Arc<Vec<SyncMessage>>>>::new(),
+            requests: HashMap::<String, Waiter>::new(),
+            receivers: Vec::<Sender<SyncEvent>>::new(),
+            listeners: Vec::<UnboundedSender<ConnectionEvent>>::new(),
+            state: None,
+            latency: None,
+            pongs: HashMap::<String, Waiter>::new(),
+            syncs: Vec::<Vec<u8>>::new(),
         }
     }
+
+    // Replies waited for by key, which is the token for pongs and the id
+    // for everything else
+    fn waiting(&mut self, pong: bool) -> &mut HashMap<String, Waiter> {
+        if pong {
+            &mut self.pongs
+        } else {
+            &mut self.requests
+        }
+    }
+
+    // Fail everything still waiting for a reply. Commands that weren't
+    // written yet are never sent then, the next connection skips them.
+    fn fail_all(&mut self) {
+        for (_, waiter) in self.requests.drain().chain(self.pongs.drain()) {
+            waiter.cancelled.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+// Everything a connection needs, shared by all the connections one after the
+// other
+#[derive(Clone)]
+struct Session {
+    addr: SocketAddr,
+    limits: ParseLimits,
+    timeout: Duration,
+    reconnect: Option<ReconnectPolicy>,
+    keepalive: Option<Keepalive>,
+    tls: Option<TlsConfig>,
+    login: Option<Arc<Login>>,
+    commands: Arc<Mutex<Receiver<Queued>>>,
+    pending: Arc<Mutex<PendingList>>,
 }
 
+// How a connection ended
+enum Closed {
+    // Lost or refused, after being authenticated or not
+    Dropped { up: bool },
+    // Every sender is gone, so there's nothing left to do
+    Shutdown,
+}
+
+// How far a connection got, to tell a refused login from a lost connection
+#[derive(Default)]
+struct Progress {
+    logged_in: AtomicBool,
+    up: AtomicBool,
+}
+
+// Lets each connection take commands from the same channel in turn
+struct SharedReceiver(Arc<Mutex<Receiver<Queued>>>);
+
+// A command on its way to a connection, which skips it if whoever sent it
+// stopped waiting first. Commands can sit in the channel for a long time
+// while logging in or reconnecting.
+struct Queued {
+    command: BoxCommand,
+    cancelled: Arc<AtomicBool>,
+}
+
+// Where to deliver a command's reply, and the cancelled flag of its Queued.
+// A command whose reply can't be delivered anymore mustn't be sent.
+struct Waiter {
+    reply: oneshot::Sender<Message>,
+    cancelled: Arc<AtomicBool>,
+}
+
+// A sync or desync sent again exactly as it was the first time
+struct Replay(Vec<u8>);
+
 impl CommandSender {
     pub fn send<C: Command + Send + 'static>(
         self,
         mut command: C,
-    ) -> impl Future<Item = (CommandSender, Option<Message>), Error = ()> {
+    ) -> impl Future<Item = (CommandSender, Option<Message>), Error = ServerError> {
         let id = if let Some(id) = command.get_id() {
             id
         } else {
             command.set_id(Some(self.generate_id()));
             command.get_id().unwrap()
         };
-        let pending = self.pending.clone();
-        let has_response = command.has_response();
 
-        if has_response {
+        let pong = command.pong_token();
+        let queued = Queued::new(Box::new(command));
+        let reply = if queued.command.has_response() {
             let mut mpending = self.pending.lock().unwrap();
-            assert_eq!(mpending.commands.insert(id.clone()), true);
-        }
+            let waiting = mpending.waiting(pong.is_some());
+            let key = pong.as_ref().unwrap_or(&id);
+            if waiting.contains_key(key) {
+                let message = match &pong {
+                    Some(token) => format!("A ping with token {:?} is already waiting", token),
+                    None => format!("A command with id {} is already waiting for a reply", id),
+                };
+                return Either::A(err(ServerError::new(
+                    ServerErrorType::DuplicateId,
+                    message,
+                    Backtrace::new(),
+                )));
+            }
+            let (reply, reply_rx) = oneshot::channel::<Message>();
+            let cancelled = queued.cancelled.clone();
+            waiting.insert(key.clone(), Waiter { reply, cancelled });
+            Some(reply_rx)
+        } else {
+            None
+        };
 
-        self.tx.send(Box::new(command)).map_err(|_| ()).and_then(move |tx| {
-            SendCommand { id, tx, has_response, pending }.map_err(|_| ())
+        Either::B(SendCommand {
+            id,
+            pong,
+            pending: self.pending,
+            timeout: self.timeout,
+            deadline: Delay::new(Instant::now() + self.timeout),
+            cancelled: queued.cancelled.clone(),
+            sending: Some(self.tx.send(queued)),
+            tx: None,
+            reply,
         })
     }
 
+    // Send a handshake and decode the options the relay negotiated.
+    // This has to happen before init so the password can be sent hashed.
+    pub fn handshake(
+        self,
+        command: HandshakeCommand,
+    ) -> impl Future<Item = (CommandSender, HandshakeResponse), Error = ServerError> {
+        self.send(command).and_then(|(tx, msg)| {
+            let msg = msg.ok_or_else(|| {
+                ServerError::new(
+                    ServerErrorType::InvalidResponse,
+                    "Handshake had no reply".to_owned(),
+                    Backtrace::new(),
+                )
+            })?;
+            HandshakeResponse::parse(&msg).map(|response| (tx, response)).map_err(|e| {
+                ServerError::new(
+                    ServerErrorType::InvalidResponse,
+                    format!("Handshake error: {}", e),
+                    Backtrace::new(),
+                )
+            })
+        })
+    }
+
+    // Same sender, waiting for replies up to timeout instead
+    pub fn with_timeout(mut self, timeout: Duration) -> CommandSender {
+        self.timeout = timeout;
+        self
+    }
+
     fn generate_id(&self) -> String {
         let rand_id: String =
             thread_rng().sample_iter(&Alphanumeric).take(10).collect();
@@ -105,66 +423,213 @@ impl Hash for SendCommand {
 
 impl Future for SendCommand {
     type Item = (CommandSender, Option<Message>);
-    type Error = std::io::Error;
+    type Error = ServerError;
 
     fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
-        if !self.has_response {
-            return Ok(Async::Ready((
-                CommandSender { tx: self.tx.clone(), pending: self.pending.clone() },
-                None,
-            )));
+        if let Some(sending) = &mut self.sending {
+            match sending.poll() {
+                Ok(Async::Ready(tx)) => {
+                    self.sending = None;
+                    self.tx = Some(tx);
+                }
+                Ok(Async::NotReady) => return self.poll_deadline(),
+                Err(_) => return Err(self.disconnected()),
+            }
         }
 
-        let mut mpending = self.pending.lock().unwrap();
-        mpending.tasks.push(task::current());
+        let msg = match &mut self.reply {
+            None => None,
+            Some(reply) => match reply.poll() {
+                Ok(Async::Ready(msg)) => Some(msg),
+                Ok(Async::NotReady) => return self.poll_deadline(),
+                Err(_) => return Err(self.disconnected()),
+            },
+        };
 
-        if let Some(msg) = mpending.messages.remove(&self.id) {
-            Ok(Async::Ready((
-                CommandSender { tx: self.tx.clone(), pending: self.pending.clone() },
-                Some(msg),
-            )))
-        } else {
-            Ok(Async::NotReady)
+        Ok(Async::Ready((
+            CommandSender {
+                tx: self.tx.clone().unwrap(),
+                pending: self.pending.clone(),
+                timeout: self.timeout,
+            },
+            msg,
+        )))
+    }
+}
+
+impl SendCommand {
+    // NotReady until the deadline, then a Timeout. A command that wasn't
+    // sent by then never will be.
+    fn poll_deadline(&mut self) -> Result<Async<(CommandSender, Option<Message>)>, ServerError> {
+        match self.deadline.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(())) => {
+                let message = if self.sending.take().is_some() {
+                    self.cancelled.store(true, Ordering::SeqCst);
+                    format!("{} wasn't sent within {:?}", self.id, self.timeout)
+                } else {
+                    format!("No reply to {} after {:?}", self.id, self.timeout)
+                };
+                Err(ServerError::new(ServerErrorType::Timeout, message, Backtrace::new()))
+            }
+            Err(e) => Err(ServerError::new(
+                ServerErrorType::Other,
+                format!("Timer error waiting for {}: {:?}", self.id, e),
+                Backtrace::new(),
+            )),
         }
     }
+
+    fn disconnected(&self) -> ServerError {
+        ServerError::new(
+            ServerErrorType::Disconnected,
+            format!("Connection closed before {} got a reply", self.id),
+            Backtrace::new(),
+        )
+    }
 }
 
-impl WeechatServer {
-    pub fn new(addr: &SocketAddr) -> WeechatServer {
-        let (command_tx, command_rx) = mpsc::channel::<BoxCommand>(0);
-        let (message_tx, message_rx) = mpsc::channel::<Message>(0);
+impl Drop for SendCommand {
+    fn drop(&mut self) {
+        if self.sending.take().is_some() {
+            self.cancelled.store(true, Ordering::SeqCst);
+        }
+        // Dropping the receiver cancels our entry, so an entry that isn't
+        // cancelled belongs to a newer command that reused the id
+        if self.reply.take().is_none() {
+            return;
+        }
+        let mut mpending = self.pending.lock().unwrap();
+        let waiting = mpending.waiting(self.pong.is_some());
+        let key = self.pong.as_ref().unwrap_or(&self.id);
+        if waiting.get(key).is_some_and(|waiter| waiter.reply.is_canceled()) {
+            waiting.remove(key);
+        }
+    }
+}
+
+impl ServerBuilder {
+    // Caps on incoming frame sizes, e.g. for small hosts
+    pub fn limits(mut self, limits: ParseLimits) -> ServerBuilder {
+        self.limits = limits;
+        self
+    }
+
+    // How long commands wait for their reply before failing with a Timeout
+    pub fn timeout(mut self, timeout: Duration) -> ServerBuilder {
+        self.timeout = timeout;
+        self
+    }
+
+    // Connect again whenever the connection is lost or can't be made.
+    // Without a policy the server stops at the first disconnect.
+    pub fn reconnect(mut self, policy: ReconnectPolicy) -> ServerBuilder {
+        self.reconnect = Some(policy);
+        self
+    }
+
+    // Send keepalive pings, and measure latency with them
+    pub fn keepalive(mut self, keepalive: Keepalive) -> ServerBuilder {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    // Connect over TLS, for relays on ssl. ports
+    pub fn tls(mut self, tls: TlsConfig) -> ServerBuilder {
+        self.tls = Some(tls);
+        self
+    }
+
+    // Run login (handshake and init) on every connection, the first one
+    // included. Other commands wait until it's done, and a failed login drops
+    // the connection.
+    pub fn login<F, R>(mut self, login: F) -> ServerBuilder
+    where
+        F: Fn(CommandSender) -> R + Send + Sync + 'static,
+        R: IntoFuture<Item = CommandSender, Error = ServerError>,
+        R::Future: Send + 'static,
+    {
+        self.login = Some(Arc::new(move |tx| Box::new(login(tx).into_future())));
+        self
+    }
+
+    pub fn connect(self) -> WeechatServer {
+        let (command_tx, command_rx) = mpsc::channel::<Queued>(0);
 
         let pending = Arc::new(Mutex::new(PendingList::new()));
 
-        let future = WeechatServer::start(
-            addr,
-            command_rx,
-            message_rx,
-            message_tx,
-            pending.clone(),
-        );
+        let session = Session {
+            addr: self.addr,
+            limits: self.limits,
+            timeout: self.timeout,
+            reconnect: self.reconnect,
+            keepalive: self.keepalive,
+            tls: self.tls,
+            login: self.login,
+            commands: Arc::new(Mutex::new(command_rx)),
+            pending: pending.clone(),
+        };
 
         thread::spawn(move || {
-            tokio::run(future);
+            tokio::run(session.run());
         });
 
-        WeechatServer { command_tx, pending }
+        WeechatServer { command_tx, pending, timeout: self.timeout }
+    }
+}
+
+impl WeechatServer {
+    pub fn new(addr: &SocketAddr) -> WeechatServer {
+        WeechatServer::builder(addr).connect()
+    }
+
+    // Connect with custom caps on incoming frame sizes, e.g. for small hosts
+    pub fn with_limits(addr: &SocketAddr, limits: ParseLimits) -> WeechatServer {
+        WeechatServer::builder(addr).limits(limits).connect()
+    }
+
+    pub fn builder(addr: &SocketAddr) -> ServerBuilder {
+        ServerBuilder {
+            addr: *addr,
+            limits: ParseLimits::default(),
+            timeout: DEFAULT_TIMEOUT,
+            reconnect: None,
+            keepalive: None,
+            tls: None,
+            login: None,
+        }
+    }
+
+    // How long commands wait for their reply before failing with a Timeout
+    pub fn timeout(mut self, timeout: Duration) -> WeechatServer {
+        self.timeout = timeout;
+        self
     }
 
     pub fn send<C: Command + Send + 'static>(
         &self,
         command: C,
-    ) -> impl Future<Item = (CommandSender, Option<Message>), Error = ()> {
-        CommandSender { tx: self.command_tx.clone(), pending: self.pending.clone() }
-            .send(command)
+    ) -> impl Future<Item = (CommandSender, Option<Message>), Error = ServerError> {
+        self.sender().send(command)
+    }
+
+    pub fn handshake(
+        &self,
+        command: HandshakeCommand,
+    ) -> impl Future<Item = (CommandSender, HandshakeResponse), Error = ServerError> {
+        self.sender().handshake(command)
     }
 
     pub fn sender(&self) -> CommandSender {
-        CommandSender { tx: self.command_tx.clone(), pending: self.pending.clone() }
+        CommandSender {
+            tx: self.command_tx.clone(),
+            pending: self.pending.clone(),
+            timeout: self.timeout,
+        }
     }
 
-    pub fn sync(&self) -> Receiver<Arc<Vec<SyncMessage>>> {
-        let (tx, rx) = mpsc::channel::<Arc<Vec<SyncMessage>>>(0);
+    pub fn sync(&self) -> Receiver<SyncEvent> {
+        let (tx, rx) = mpsc::channel::<SyncEvent>(0);
 
         let mut mpending = self.pending.lock().unwrap();
         mpending.receivers.push(tx);
@@ -172,48 +637,23 @@ impl WeechatServer {
         rx
     }
 
-    fn start(
-        addr: &SocketAddr,
-        command_rx: Receiver<BoxCommand>,
-        message_rx: Receiver<Message>,
-        message_tx: Sender<Message>,
-        pending: Arc<Mutex<PendingList>>,
-    ) -> Box<Future<Item = (), Error = ()> + Send> {
-        let tcp = TcpStream::connect(addr)
-            .map_err(|e| println!("Connect failed: {:?}", e))
-            .and_then(move |stream| {
-                let (sink, stream) =
-                    Framed::new(stream, WeechatCodec::new()).split();
+    // Round trip time of the latest ping answered on the current connection.
+    // Measured after each login, and then by keepalive pings if enabled.
+    pub fn latency(&self) -> Option<Duration> {
+        self.pending.lock().unwrap().latency
+    }
 
-                command_rx
-                    .fold(sink, move |sink, command: Box<Command + Send>| {
-                        sink.send(command).map_err(|err| {
-                            println!("Send error: {:?}", err);
-                        })
-                    })
-                    .join(
-                        stream
-                            .map_err(|e| println!("Stream read error: {:?}", e))
-                            .fold(message_tx, |tx, msg| {
-                                tx.send(msg).map_err(|e| {
-                                    println!("Message tx error: {:?}", e)
-                                })
-                            })
-                            .map_err(|e| println!("Fold error: {:?}", e)),
-                    )
-                    .join(
-                        message_rx
-                            .fold(pending, |pending, msg| {
-                                WeechatServer::handle_message(msg, pending.clone())
-                                    .map(|_| pending)
-                            })
-                            .map_err(|e| println!("Receive error: {:?}", e)),
-                    )
-                    .map_err(|e| println!("Join error: {:?}", e))
-            })
-            .map_err(|e| println!("Tcp error: {:?}", e));
+    // The latest connection event, then every one after it
+    pub fn events(&self) -> UnboundedReceiver<ConnectionEvent> {
+        let (tx, rx) = mpsc::unbounded::<ConnectionEvent>();
+
+        let mut mpending = self.pending.lock().unwrap();
+        if let Some(state) = &mpending.state {
+            let _ = tx.unbounded_send(state.clone());
+        }
+        mpending.listeners.push(tx);
 
-        Box::new(tcp.map(|_| ()))
+        rx
     }
 
     fn handle_message(
@@ -228,31 +668,921 @@ impl WeechatServer {
         let mut futs = Vec::<Box<Future<Item = (), Error = ()> + Send>>::new();
 
         if is_sync {
-            let items = SyncMessage::parse(&msg).unwrap();
+            // A bad item only loses itself, the rest still reach receivers
+            let items = msg.data.iter().filter_map(|item| {
+                SyncMessage::parse_message_item(&msg.id, item)
+                    .map_err(|e| println!("Dropping {} sync item: {}", msg.id, e))
+                    .ok()
+            });
             for vec in items {
                 let mut inner_futs =
                     Vec::<Box<Future<Item = (), Error = ()> + Send>>::new();
-                let arc = Arc::<Vec<SyncMessage>>::new(vec);
+                let event = SyncEvent::Messages(Arc::new(vec));
 
                 for receiver in &mpending.receivers {
                     inner_futs.push(Box::new(
-                        receiver.clone().send(arc.clone()).then(|_| Ok(())),
+                        receiver.clone().send(event.clone()).then(|_| Ok(())),
                     ));
                 }
 
                 futs.push(Box::new(join_all(inner_futs).then(|_| Ok(()))));
             }
+        } else if let Some(pong) = pong_waiter(&msg, &mut mpending) {
+            let _ = pong.reply.send(msg);
         } else {
-            if !mpending.commands.remove(&msg.id) {
-                println!("Unexpected command response: {:?}", msg);
-            }
-            mpending.messages.insert(msg.id.clone(), msg);
-            for task in mpending.tasks.iter() {
-                task.notify();
+            match mpending.requests.remove(&msg.id) {
+                // The command's future may be gone, then nobody wants it
+                Some(waiter) => {
+                    let _ = waiter.reply.send(msg);
+                }
+                None => println!("Unexpected command response: {:?}", msg),
             }
-            mpending.tasks.clear();
         }
 
         iter_ok(futs).for_each(|fut| fut)
     }
 }
+
+impl Session {
+    // Connect, and connect again as the policy says until told to stop
+    fn run(self) -> impl Future<Item = (), Error = ()> {
+        loop_fn((0, false), move |(failed, was_up): (u32, bool)| {
+            let session = self.clone();
+            self.connection(was_up).then(move |closed| {
+                let up = match closed {
+                    Ok(Closed::Dropped { up }) => up,
+                    _ => {
+                        session.emit(ConnectionEvent::ShuttingDown);
+                        return Either::A(ok(Loop::Break(())));
+                    }
+                };
+                if up {
+                    session.notify(SyncEvent::Disconnected);
+                }
+                let was_up = was_up || up;
+                let failed = if up { 0 } else { failed + 1 };
+
+                match session.reconnect.and_then(|policy| policy.delay(failed)) {
+                    Some(delay) => {
+                        println!("Reconnecting in {:?}", delay);
+                        Either::B(Delay::new(Instant::now() + delay).then(move |_| {
+                            Ok(Loop::Continue((failed, was_up)))
+                        }))
+                    }
+                    None => {
+                        session.emit(ConnectionEvent::ShuttingDown);
+                        Either::A(ok(Loop::Break(())))
+                    }
+                }
+            })
+        })
+    }
+
+    // One connection, from connecting until it's lost. Replies still pending
+    // when it ends fail as Disconnected.
+    fn connection(&self, was_up: bool) -> impl Future<Item = Closed, Error = ()> {
+        let session = self.clone();
+        let ended = self.clone();
+        let progress = Arc::new(Progress::default());
+        let dropped = progress.clone();
+
+        self.emit(ConnectionEvent::Connecting);
+        let tls = self.tls.clone();
+        TcpStream::connect(&self.addr)
+            .map_err(|e| DisconnectReason::ConnectFailed(e.to_string()))
+            .and_then(move |tcp| match tls {
+                None => Either::A(ok(Transport::Plain(tcp))),
+                Some(tls) => Either::B(
+                    result(tls.handshake(tcp))
+                        .map_err(|e| DisconnectReason::TlsFailed(e.to_string()))
+                        .and_then(|handshake| {
+                            handshake
+                                .map(|stream| Transport::Tls(Box::new(stream)))
+                                .map_err(|e| DisconnectReason::TlsFailed(e.to_string()))
+                        }),
+                ),
+            })
+            .and_then(move |stream| {
+                session.emit(ConnectionEvent::Connected);
+                // Nothing to wait for without a login
+                if session.login.is_none() {
+                    progress.up.store(true, Ordering::SeqCst);
+                }
+
+                let (sink, stream) =
+                    Framed::new(stream, WeechatCodec::new(session.limits)).split();
+                let (login_tx, login_rx) = mpsc::channel::<Queued>(0);
+                let (keepalive_tx, keepalive_rx) = mpsc::channel::<Queued>(0);
+
+                // Login and replayed syncs first, then everyone else's, with
+                // keepalive pings in between. Ends when everyone else is gone.
+                let recorded = session.pending.clone();
+                let queued = SharedReceiver(session.commands.clone())
+                    .filter_map(Queued::wanted)
+                    .inspect(move |command| record_sync(&recorded, &**command));
+                let commands = login_rx
+                    .filter_map(Queued::wanted)
+                    .chain(queued)
+                    .map(Some)
+                    .chain(once(Ok(None)))
+                    .select(keepalive_rx.filter_map(Queued::wanted).map(Some))
+                    .take_while(|command| Ok(command.is_some()))
+                    .filter_map(|command| command);
+                // Receivers never fail, only the socket can
+                let writer = commands
+                    .map_err(|_| DisconnectReason::Closed)
+                    .fold(sink, |sink, command| {
+                        sink.send(command)
+                            .map_err(|e| DisconnectReason::Error(e.to_string()))
+                    })
+                    .map(|_| Closed::Shutdown);
+
+                let reader_pending = session.pending.clone();
+                let reader = stream
+                    .map_err(|e| DisconnectReason::Error(e.to_string()))
+                    .for_each(move |msg| {
+                        WeechatServer::handle_message(msg, reader_pending.clone())
+                            .then(|_| Ok(()))
+                    })
+                    .and_then(|_| Err(DisconnectReason::Closed));
+
+                // Only ends if the login fails or keepalive gives up, the
+                // connection is useless then
+                let keepalive = session.clone();
+                let login = session
+                    .login(login_tx, was_up, progress)
+                    .map_err(|e| DisconnectReason::LoginFailed(e.to_string()))
+                    .and_then(move |_| keepalive.keepalive(keepalive_tx));
+
+                reader
+                    .select(writer)
+                    .map(|(closed, _)| closed)
+                    .map_err(|(reason, _)| reason)
+                    .select(login)
+                    .map(|(closed, _)| closed)
+                    .map_err(|(reason, _)| reason)
+            })
+            .then(move |closed| {
+                // No more replies, fail whatever still waits
+                {
+                    let mut mpending = ended.pending.lock().unwrap();
+                    mpending.fail_all();
+                    mpending.latency = None;
+                }
+                let reason = match closed {
+                    Ok(Closed::Shutdown) => return Ok(Closed::Shutdown),
+                    Ok(Closed::Dropped { .. }) => DisconnectReason::Closed,
+                    Err(reason) => reason,
+                };
+
+                let up = dropped.up.load(Ordering::SeqCst);
+                let refused = dropped.logged_in.load(Ordering::SeqCst)
+                    && !up
+                    && matches!(
+                        reason,
+                        DisconnectReason::Closed | DisconnectReason::Error(_)
+                    );
+                ended.emit(if refused {
+                    ConnectionEvent::AuthFailed
+                } else {
+                    ConnectionEvent::Disconnected(reason)
+                });
+                Ok(Closed::Dropped { up })
+            })
+    }
+
+    // Log in and make sure the relay took it, then send the syncs the last
+    // connection had. Commands go to this connection only.
+    fn login(
+        &self,
+        tx: Sender<Queued>,
+        was_up: bool,
+        progress: Arc<Progress>,
+    ) -> impl Future<Item = (), Error = ServerError> {
+        let sender = CommandSender {
+            tx,
+            pending: self.pending.clone(),
+            timeout: self.timeout,
+        };
+        let session = self.clone();
+        let logged_in = match &self.login {
+            Some(login) => Either::A(login(sender).and_then(move |sender| {
+                progress.logged_in.store(true, Ordering::SeqCst);
+                session.ping(sender.tx.clone(), session.timeout).map(move |_| {
+                    let tx = sender.tx;
+                    progress.up.store(true, Ordering::SeqCst);
+                    session.emit(ConnectionEvent::Authenticated);
+                    tx
+                })
+            })),
+            None => Either::B(ok(sender.tx)),
+        };
+
+        let session = self.clone();
+        logged_in.and_then(move |tx| {
+            let syncs = session.pending.lock().unwrap().syncs.clone();
+            iter_ok::<_, SendError<Queued>>(syncs)
+                .fold(tx, |tx, line| tx.send(Queued::new(Box::new(Replay(line)))))
+                .map_err(|_| {
+                    ServerError::new(
+                        ServerErrorType::Disconnected,
+                        "Connection closed while restoring syncs".to_owned(),
+                        Backtrace::new(),
+                    )
+                })
+                .map(move |_| {
+                    if was_up {
+                        session.notify(SyncEvent::Reconnected);
+                    }
+                })
+        })
+    }
+
+    // Ping until too many pongs are missed in a row. Never ends without
+    // keepalive.
+    fn keepalive(
+        &self,
+        tx: Sender<Queued>,
+    ) -> impl Future<Item = Closed, Error = DisconnectReason> {
+        let keepalive = match self.keepalive {
+            Some(keepalive) => keepalive,
+            None => return Either::A(empty()),
+        };
+        let interval = keepalive.interval;
+
+        let session = self.clone();
+        let pings = loop_fn(0, move |missed: u32| {
+            let session = session.clone();
+            let sent = Instant::now();
+            session.ping(tx.clone(), interval).then(move |result| {
+                let missed = match result {
+                    Ok(_) => 0,
+                    Err(ServerError { error: ServerErrorType::Timeout, .. }) => {
+                        missed + 1
+                    }
+                    Err(e) => {
+                        return Either::A(err(DisconnectReason::Error(e.message)))
+                    }
+                };
+                if missed >= keepalive.max_missed {
+                    return Either::A(err(DisconnectReason::PingTimeout));
+                }
+                // Right away after a miss, the interval is already up then
+                Either::B(
+                    Delay::new(sent + interval)
+                        .map(move |_| Loop::<Closed, u32>::Continue(missed))
+                        .map_err(|e| DisconnectReason::Error(e.to_string())),
+                )
+            })
+        });
+
+        // The login just measured the latency, start after an interval
+        Either::B(
+            Delay::new(Instant::now() + interval)
+                .map_err(|e| DisconnectReason::Error(e.to_string()))
+                .and_then(|_| pings),
+        )
+    }
+
+    // Ping with a token of our own and wait up to timeout for the pong,
+    // resolving to the round trip time which is also kept for latency()
+    fn ping(
+        &self,
+        tx: Sender<Queued>,
+        timeout: Duration,
+    ) -> impl Future<Item = Duration, Error = ServerError> {
+        let token: String =
+            thread_rng().sample_iter(&Alphanumeric).take(10).collect();
+        let ping = PingCommand::new(None, Some(vec![token.clone()]));
+        let queued = Queued::new(Box::new(ping));
+        let (reply, pong_rx) = oneshot::channel::<Message>();
+        let cancelled = queued.cancelled.clone();
+        self.pending.lock().unwrap().pongs.insert(token.clone(), Waiter { reply, cancelled });
+
+        let pending = self.pending.clone();
+        let sent = Instant::now();
+        tx.send(queued)
+            .map_err(|_| {
+                ServerError::new(
+                    ServerErrorType::Disconnected,
+                    "Connection closed before the ping was sent".to_owned(),
+                    Backtrace::new(),
+                )
+            })
+            .and_then(move |_| {
+                Timeout::new(pong_rx, timeout).then(move |pong| {
+                    let mut mpending = pending.lock().unwrap();
+                    match pong {
+                        Ok(_) => {
+                            let latency = sent.elapsed();
+                            mpending.latency = Some(latency);
+                            Ok(latency)
+                        }
+                        Err(e) => {
+                            // A late pong is as good as none
+                            mpending.pongs.remove(&token);
+                            Err(pong_error(&e, &token, timeout))
+                        }
+                    }
+                })
+            })
+    }
+
+    // Tell every sync subscriber, without waiting for them to read it
+    fn notify(&self, event: SyncEvent) {
+        let mpending = self.pending.lock().unwrap();
+        for receiver in &mpending.receivers {
+            let send = receiver.clone().send(event.clone()).then(|_| Ok(()));
+            tokio::spawn(send);
+        }
+    }
+
+    // Tell every events() listener, forgetting the ones that are gone
+    fn emit(&self, event: ConnectionEvent) {
+        let mut mpending = self.pending.lock().unwrap();
+        mpending
+            .listeners
+            .retain(|listener| listener.unbounded_send(event.clone()).is_ok());
+        mpending.state = Some(event);
+    }
+}
+
+impl Stream for SharedReceiver {
+    type Item = Queued;
+    type Error = ();
+
+    fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
+        self.0.lock().unwrap().poll()
+    }
+}
+
+impl Queued {
+    fn new(command: BoxCommand) -> Queued {
+        Queued { command, cancelled: Arc::new(AtomicBool::new(false)) }
+    }
+
+    // The command, unless it was cancelled
+    fn wanted(self) -> Option<BoxCommand> {
+        if self.cancelled.load(Ordering::SeqCst) {
+            None
+        } else {
+            Some(self.command)
+        }
+    }
+}
+
+impl Command for Replay {
+    fn get_id(&self) -> Option<String> {
+        None
+    }
+
+    fn set_id(&mut self, _id: Option<String>) {}
+
+    fn encode(&self, out: &mut dyn Write) -> Result<usize, std::io::Error> {
+        out.write(&self.0)
+    }
+
+    fn has_response(&self) -> bool {
+        false
+    }
+}
+
+//
+// Helper functions
+//
+
+// Remember sync and desync commands to send them again after reconnecting.
+// A desync of everything makes the ones before it moot.
+fn record_sync(pending: &Mutex<PendingList>, command: &(dyn Command + Send)) {
+    let mut line = Vec::<u8>::new();
+    if command.encode(&mut line).is_err() {
+        return;
+    }
+    let text = without_id(&line);
+    let is_sync = [&b"sync"[..], &b"desync"[..]].iter().any(|name| {
+        text.starts_with(name)
+            && matches!(text.get(name.len()), Some(b' ') | Some(b'\n'))
+    });
+    if !is_sync {
+        return;
+    }
+
+    let mut mpending = pending.lock().unwrap();
+    if text == b"desync\n" {
+        mpending.syncs.clear();
+    } else {
+        mpending.syncs.push(line);
+    }
+}
+
+// The waiter for a pong, if it is one. Pings without arguments get an empty
+// or null string back.
+fn pong_waiter(
+    msg: &Message,
+    mpending: &mut PendingList,
+) -> Option<Waiter> {
+    if msg.id != "_pong" {
+        return None;
+    }
+    let token = msg.data.first()?.unwrap::<WeechatString>()?;
+    mpending.pongs.remove(token.as_str().ok()?.unwrap_or(""))
+}
+
+// Why a pong didn't come
+fn pong_error<T>(
+    e: &timeout::Error<T>,
+    token: &str,
+    timeout: Duration,
+) -> ServerError {
+    if e.is_elapsed() {
+        ServerError::new(
+            ServerErrorType::Timeout,
+            format!("No pong to {} after {:?}", token, timeout),
+            Backtrace::new(),
+        )
+    } else {
+        ServerError::new(
+            ServerErrorType::Disconnected,
+            format!("Connection closed before pong to {}", token),
+            Backtrace::new(),
+        )
+    }
+}
+
+// A command line without its "(id) " prefix
+fn without_id(line: &[u8]) -> &[u8] {
+    if line.first() != Some(&b'(') {
+        return line;
+    }
+    match line.iter().position(|b| *b == b')') {
+        Some(end) => {
+            let rest = &line[end + 1..];
+            rest.strip_prefix(b" ").unwrap_or(rest)
+        }
+        None => line,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use dingy_mock::relay::MockRelay;
+    use dingy_mock::scenario::Scenario;
+    use dingy_mock::sim::SimRelay;
+    use dingy_mock::weechat::Core;
+    use libdingy::command::{HdataPath, InputCommand, SyncCommand, SyncOption};
+    use libdingy::message::{Pointer, WeechatType};
+    use std::fmt::Debug;
+    use std::path::Path;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::runtime::Runtime;
+
+    // Long enough for a slow CI box, short enough to notice a hang
+    const WAIT: Duration = Duration::from_secs(10);
+
+    // Run a future on a runtime of its own, failing if it takes over WAIT
+    pub(crate) fn wait<F>(future: F) -> F::Item
+    where
+        F: Future + Send + 'static,
+        F::Item: Send + 'static,
+        F::Error: Debug + Send + 'static,
+    {
+        let mut runtime = Runtime::new().unwrap();
+        runtime.block_on(Timeout::new(future, WAIT)).expect("future failed or timed out")
+    }
+
+    // Logs in with password on every connection, like the demo client
+    pub(crate) fn builder(addr: &SocketAddr, password: &str) -> ServerBuilder {
+        let password = password.to_owned();
+        WeechatServer::builder(addr)
+            .timeout(WAIT)
+            .login(move |tx| crate::login(tx, password.clone(), None))
+    }
+
+    // The first event that matches, skipping the others
+    pub(crate) fn wait_for_event<F>(server: &WeechatServer, matches: F) -> ConnectionEvent
+    where
+        F: Fn(&ConnectionEvent) -> bool + Send + 'static,
+    {
+        let events = server.events().filter(move |event| matches(event));
+        wait(events.into_future().map_err(|(e, _)| e)).0.unwrap()
+    }
+
+    // The next sync event, whatever it is
+    fn next_event(sync: Receiver<SyncEvent>) -> (Option<SyncEvent>, Receiver<SyncEvent>) {
+        wait(sync.into_future().map_err(|(e, _)| e))
+    }
+
+    // Messages of the next sync event, which has to be one
+    fn next_sync(sync: Receiver<SyncEvent>) -> (Arc<Vec<SyncMessage>>, Receiver<SyncEvent>) {
+        match wait(sync.into_future().map_err(|(e, _)| e)) {
+            (Some(SyncEvent::Messages(messages)), sync) => (messages, sync),
+            (event, _) => panic!("Expected sync messages, got {:?}", event),
+        }
+    }
+
+    // Reconnects right away, for as long as it takes
+    fn reconnect_policy() -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(100),
+            ..ReconnectPolicy::default()
+        }
+    }
+
+    // Drop the relay's clients, once there is one
+    fn drop_clients(relay: &SimRelay) {
+        while relay.drop_clients() == 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // Title of the buffer at index in the buffer list
+    fn title(server: &WeechatServer, index: usize) -> String {
+        let hdata = HdataPath::buffer().list("gui_buffers").all().keys(["title"]).build().unwrap();
+        let (_, msg) = wait(server.send(hdata));
+        match msg.as_ref().and_then(|msg| msg.data.first()) {
+            Some(WeechatType::Hdata(hdata)) => {
+                hdata.get::<WeechatString>(index, "title").unwrap().to_str()
+            }
+            data => panic!("Expected hdata, got {:?}", data),
+        }
+    }
+
+    // An input that waits for a reply it never gets, but running it shows
+    struct WaitingInput(InputCommand);
+
+    impl Command for WaitingInput {
+        fn get_id(&self) -> Option<String> {
+            self.0.get_id()
+        }
+
+        fn set_id(&mut self, id: Option<String>) {
+            self.0.set_id(id)
+        }
+
+        fn encode(&self, out: &mut dyn Write) -> Result<usize, std::io::Error> {
+            Command::encode(&self.0, out)
+        }
+
+        fn has_response(&self) -> bool {
+            true
+        }
+    }
+
+    fn basic_scenario() -> MockRelay {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("dingy-mock/scenarios/basic.yaml");
+        MockRelay::start("127.0.0.1:0", Scenario::load(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn basic_scenario_through_mock_relay() {
+        let relay = basic_scenario();
+        let server = builder(&relay.addr(), "hunter2").connect();
+        let sync = server.sync();
+
+        let hdata = HdataPath::buffer()
+            .list("gui_buffers")
+            .all()
+            .keys(["number", "full_name"])
+            .build()
+            .unwrap();
+        let (tx, msg) = wait(server.send(hdata));
+        let hdata = match msg.map(|msg| msg.data) {
+            Some(mut data) if data.len() == 1 => match data.remove(0) {
+                WeechatType::Hdata(hdata) => hdata,
+                item => panic!("Expected hdata, got {:?}", item),
+            },
+            data => panic!("Expected one hdata, got {:?}", data),
+        };
+        assert_eq!(hdata.len(), 2);
+        assert_eq!(hdata.get::<i32>(1, "number"), Some(2));
+        assert_eq!(
+            hdata.get::<WeechatString>(0, "full_name").map(|s| s.to_str()),
+            Some("core.weechat".to_owned())
+        );
+
+        wait(tx.send(SyncCommand::new(None, vec![])));
+        let (messages, _) = next_sync(sync);
+        match messages.as_slice() {
+            [SyncMessage::BufferLineAdded(line)] => {
+                assert_eq!(line.buffer, Pointer::new(0x55d0_a8e8_c5f0));
+                assert_eq!(line.message.to_str(), "Hello from the mock relay");
+            }
+            messages => panic!("Expected one line, got {:?}", messages),
+        }
+    }
+
+    #[test]
+    fn wrong_password_fails_login() {
+        let relay = basic_scenario();
+        let server = builder(&relay.addr(), "hunter3").connect();
+        let event = wait_for_event(&server, |event| {
+            matches!(event, ConnectionEvent::Authenticated | ConnectionEvent::AuthFailed)
+        });
+        assert!(matches!(event, ConnectionEvent::AuthFailed));
+    }
+
+    #[test]
+    fn no_agreed_password_hash_fails_without_sending_the_password() {
+        // The relay takes the password if it's sent, so only not sending it
+        // fails the login
+        let scenario = Scenario::from_yaml(
+            r#"
+password: hunter2
+steps:
+  - expect:
+      command: handshake
+      reply:
+        - data:
+            - htb:
+                - [{str: password_hash_algo}, {str: ""}]
+                - [{str: compression}, {str: "off"}]
+  - expect:
+      command: init
+"#,
+        )
+        .unwrap();
+        let relay = MockRelay::start("127.0.0.1:0", scenario).unwrap();
+        let server = builder(&relay.addr(), "hunter2").connect();
+        let event = wait_for_event(&server, |event| {
+            matches!(event, ConnectionEvent::Authenticated | ConnectionEvent::Disconnected(_))
+        });
+        match event {
+            ConnectionEvent::Disconnected(DisconnectReason::LoginFailed(message)) => {
+                assert!(message.contains("No password hash"), "{}", message)
+            }
+            event => panic!("Expected a failed login, got {:?}", event),
+        }
+    }
+
+    #[test]
+    fn simulated_core_through_sim_relay() {
+        let mut core = Core::new();
+        core.add_buffer("irc.libera.#weechat", "#weechat", "WeeChat support channel");
+        core.set_local_variable("irc.libera.#weechat", "nick", "dingy");
+        let relay = SimRelay::start("127.0.0.1:0", core, Some("secret".to_owned())).unwrap();
+        let server = builder(&relay.addr(), "secret").connect();
+        let sync = server.sync();
+
+        let hdata = HdataPath::buffer()
+            .list("gui_buffers")
+            .all()
+            .keys(["number", "full_name", "title"])
+            .build()
+            .unwrap();
+        let (tx, msg) = wait(server.send(hdata));
+        let hdata = match msg.as_ref().and_then(|msg| msg.data.first()) {
+            Some(WeechatType::Hdata(hdata)) => hdata.clone(),
+            data => panic!("Expected hdata, got {:?}", data),
+        };
+        let names: Vec<String> = (0..hdata.len())
+            .filter_map(|i| hdata.get::<WeechatString>(i, "full_name"))
+            .map(|name| name.to_str())
+            .collect();
+        assert_eq!(names, vec!["core.weechat", "irc.libera.#weechat"]);
+        assert_eq!(
+            hdata.get::<WeechatString>(1, "title").map(|s| s.to_str()),
+            Some("WeeChat support channel".to_owned())
+        );
+
+        // Title changes only go to clients synced with buffers, so the line
+        // is the first thing this sync sees
+        let channel = "irc.libera.#weechat".to_owned();
+        let sync_buffer = SyncCommand::new(None, vec![(channel.clone(), SyncOption::Buffer)]);
+        let (tx, _) = wait(tx.send(sync_buffer));
+        let title = InputCommand::new(None, channel.clone(), "/title New title".into());
+        let (tx, _) = wait(tx.send(title));
+        let (tx, _) = wait(tx.send(InputCommand::new(None, channel.clone(), "hello".into())));
+        let (messages, sync) = next_sync(sync);
+        match messages.as_slice() {
+            [SyncMessage::BufferLineAdded(line)] => {
+                assert_eq!(line.prefix.to_str(), "dingy");
+                assert_eq!(line.message.to_str(), "hello");
+            }
+            messages => panic!("Expected one line, got {:?}", messages),
+        }
+
+        let sync_buffers = SyncCommand::new(None, vec![("*".to_owned(), SyncOption::Buffers)]);
+        let (tx, _) = wait(tx.send(sync_buffers));
+        wait(tx.send(InputCommand::new(None, channel, "/title Newer title".into())));
+        let (messages, _) = next_sync(sync);
+        match messages.as_slice() {
+            [SyncMessage::BufferTitleChanged(changed)] => {
+                assert_eq!(changed.full_name.to_str(), "irc.libera.#weechat");
+                assert_eq!(changed.title.to_str(), "Newer title");
+            }
+            messages => panic!("Expected a title change, got {:?}", messages),
+        }
+    }
+
+    #[test]
+    fn concurrent_pings_get_their_own_pongs() {
+        let password = Some("secret".to_owned());
+        let relay = SimRelay::start("127.0.0.1:0", Core::new(), password).unwrap();
+        let server = builder(&relay.addr(), "secret").connect();
+        let ping = |token: &str| PingCommand::new(None, Some(vec![token.to_owned()]));
+        let token = |msg: Option<Message>| match msg.as_ref().and_then(|msg| msg.data.first()) {
+            Some(WeechatType::String(token)) => token.to_string_lossy().unwrap_or_default(),
+            data => panic!("Expected a pong, got {:?}", data),
+        }
+        .into_owned();
+
+        let pongs = server.send(ping("first")).join3(
+            server.send(ping("second")),
+            server.send(PingCommand::new(None, None)),
+        );
+        let ((_, first), (_, second), (_, empty)) = wait(pongs);
+        assert_eq!(token(first), "first");
+        assert_eq!(token(second), "second");
+        assert_eq!(token(empty), "");
+
+        // Their pongs couldn't be told apart
+        let first = server.send(ping("same"));
+        match wait(server.send(ping("same")).then(Ok::<_, ()>)) {
+            Err(ServerError { error: ServerErrorType::DuplicateId, .. }) => {}
+            result => panic!("Expected a duplicate, got {:?}", result.map(|(_, msg)| msg)),
+        }
+        assert_eq!(token(wait(first).1), "same");
+    }
+
+    #[test]
+    fn timed_out_commands_are_never_sent() {
+        let mut core = Core::new();
+        core.add_buffer("irc.libera.#weechat", "#weechat", "WeeChat support channel");
+        let relay = SimRelay::start("127.0.0.1:0", core, Some("secret".to_owned())).unwrap();
+        // Nothing else is sent until the login is done
+        let server = WeechatServer::builder(&relay.addr())
+            .timeout(WAIT)
+            .login(|tx| {
+                Delay::new(Instant::now() + Duration::from_millis(500))
+                    .map_err(|e| {
+                        ServerError::new(ServerErrorType::Other, e.to_string(), Backtrace::new())
+                    })
+                    .and_then(move |_| crate::login(tx, "secret".to_owned(), None))
+            })
+            .connect();
+
+        let channel = "irc.libera.#weechat".to_owned();
+        let late = InputCommand::new(None, channel, "/title Too late".into());
+        let sent = server.sender().with_timeout(Duration::from_millis(100)).send(late);
+        match wait(sent.then(Ok::<_, ()>)) {
+            Err(ServerError { error: ServerErrorType::Timeout, .. }) => {}
+            result => panic!("Expected a timeout, got {:?}", result.map(|(_, msg)| msg)),
+        }
+
+        wait_for_event(&server, |event| matches!(event, ConnectionEvent::Authenticated));
+        let hdata = HdataPath::buffer().list("gui_buffers").all().keys(["title"]).build().unwrap();
+        let (_, msg) = wait(server.send(hdata));
+        match msg.as_ref().and_then(|msg| msg.data.first()) {
+            Some(WeechatType::Hdata(hdata)) => assert_eq!(
+                hdata.get::<WeechatString>(1, "title").map(|s| s.to_str()),
+                Some("WeeChat support channel".to_owned())
+            ),
+            data => panic!("Expected hdata, got {:?}", data),
+        }
+    }
+
+    #[test]
+    fn commands_queued_on_a_lost_connection_are_never_sent() {
+        let password = Some("secret".to_owned());
+        let relay = SimRelay::start("127.0.0.1:0", Core::new(), password).unwrap();
+        // The first login hangs until the connection is lost
+        let logins = Arc::new(AtomicUsize::new(0));
+        let server = WeechatServer::builder(&relay.addr())
+            .timeout(WAIT)
+            .reconnect(reconnect_policy())
+            .login(move |tx| {
+                let delay = match logins.fetch_add(1, Ordering::SeqCst) {
+                    0 => WAIT,
+                    _ => Duration::from_millis(0),
+                };
+                Delay::new(Instant::now() + delay)
+                    .map_err(|e| {
+                        ServerError::new(ServerErrorType::Other, e.to_string(), Backtrace::new())
+                    })
+                    .and_then(move |_| crate::login(tx, "secret".to_owned(), None))
+            })
+            .connect();
+
+        let input = InputCommand::new(None, "core.weechat".into(), "/title Queued".into());
+        let queued = server.send(WaitingInput(input));
+        drop_clients(&relay);
+        match wait(queued.then(Ok::<_, ()>)) {
+            Err(ServerError { error: ServerErrorType::Disconnected, .. }) => {}
+            result => panic!("Expected a disconnect, got {:?}", result.map(|(_, msg)| msg)),
+        }
+
+        wait_for_event(&server, |event| matches!(event, ConnectionEvent::Authenticated));
+        assert_eq!(title(&server, 0), "WeeChat (dingy-mock)");
+    }
+
+    #[test]
+    fn reconnects_logs_in_again_and_replays_syncs() {
+        let password = Some("secret".to_owned());
+        let relay = SimRelay::start("127.0.0.1:0", Core::new(), password).unwrap();
+        let logins = Arc::new(AtomicUsize::new(0));
+        let counted = logins.clone();
+        let server = WeechatServer::builder(&relay.addr())
+            .timeout(WAIT)
+            .reconnect(reconnect_policy())
+            .login(move |tx| {
+                counted.fetch_add(1, Ordering::SeqCst);
+                crate::login(tx, "secret".to_owned(), None)
+            })
+            .connect();
+        let sync = server.sync();
+
+        let core = vec![("core.weechat".to_owned(), SyncOption::Buffer)];
+        wait(server.send(SyncCommand::new(None, core)));
+        drop_clients(&relay);
+        let (event, sync) = next_event(sync);
+        assert!(matches!(event, Some(SyncEvent::Disconnected)), "{:?}", event);
+        let (event, sync) = next_event(sync);
+        assert!(matches!(event, Some(SyncEvent::Reconnected)), "{:?}", event);
+        assert_eq!(logins.load(Ordering::SeqCst), 2);
+
+        // The relay has run the replayed sync once it answers after it, and
+        // only a synced client gets the line
+        title(&server, 0);
+        relay.core().lock().unwrap().print("core.weechat", "--", "Back again", &[]);
+        let (messages, _) = next_sync(sync);
+        match messages.as_slice() {
+            [SyncMessage::BufferLineAdded(line)] => {
+                assert_eq!(line.message.to_str(), "Back again")
+            }
+            messages => panic!("Expected one line, got {:?}", messages),
+        }
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        // Nothing listens there anymore
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let policy = ReconnectPolicy { max_attempts: Some(2), ..reconnect_policy() };
+        let server = WeechatServer::builder(&addr).reconnect(policy).connect();
+        let failed = |event: &ConnectionEvent| {
+            matches!(event, ConnectionEvent::Disconnected(DisconnectReason::ConnectFailed(_)))
+        };
+
+        let events = server
+            .events()
+            .take_while(|event| Ok(!matches!(event, ConnectionEvent::ShuttingDown)))
+            .collect();
+        let events = wait(events);
+        assert!(events.last().is_some_and(failed), "{:?}", events);
+        wait_for_event(&server, |event| matches!(event, ConnectionEvent::ShuttingDown));
+    }
+
+    #[test]
+    fn reconnect_delays_stay_in_range() {
+        let policy = ReconnectPolicy { jitter: 0.0, ..ReconnectPolicy::default() };
+        assert_eq!(policy.delay(0), Some(Duration::from_secs(1)));
+        assert_eq!(policy.delay(3), Some(Duration::from_secs(8)));
+        assert_eq!(policy.delay(1000), Some(Duration::from_secs(60)));
+        let limited = ReconnectPolicy { max_attempts: Some(3), ..policy };
+        assert_eq!(limited.delay(2), Some(Duration::from_secs(4)));
+        assert_eq!(limited.delay(3), None);
+
+        let max_delay = Duration::from_secs(60);
+        let negative = ReconnectPolicy { multiplier: -2.0, ..policy };
+        assert_eq!(negative.delay(1), Some(max_delay));
+        let nan = ReconnectPolicy { jitter: f64::NAN, ..policy };
+        assert_eq!(nan.delay(1), Some(max_delay));
+        let forever = ReconnectPolicy { max_delay: Duration::MAX, ..policy };
+        assert_eq!(forever.delay(2000), Some(Duration::MAX));
+    }
+
+    #[test]
+    fn keepalive_measures_latency() {
+        // Answers pings without a login, so only keepalive pings
+        let relay = MockRelay::start("127.0.0.1:0", Scenario::from_yaml("steps: []").unwrap());
+        let keepalive = Keepalive { interval: Duration::from_millis(50), max_missed: 2 };
+        let server = WeechatServer::builder(&relay.unwrap().addr()).keepalive(keepalive).connect();
+
+        let deadline = Instant::now() + WAIT;
+        while server.latency().is_none() {
+            assert!(Instant::now() < deadline, "No latency after {:?}", WAIT);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn keepalive_drops_relays_that_stop_answering() {
+        // Takes the connection and never says anything
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || listener.accept().map(|(stream, _)| thread::park()));
+
+        let keepalive = Keepalive { interval: Duration::from_millis(50), max_missed: 2 };
+        let server = WeechatServer::builder(&addr).keepalive(keepalive).connect();
+        let event = wait_for_event(&server, |event| {
+            matches!(event, ConnectionEvent::Disconnected(_))
+        });
+        assert!(
+            matches!(event, ConnectionEvent::Disconnected(DisconnectReason::PingTimeout)),
+            "{:?}",
+            event
+        );
+    }
+}
@@ -0,0 +1,534 @@
+// TLS for relays listening on ssl. ports. rustls does the work, this only
+// drives its ClientConnection over a tokio TcpStream, which works since
+// tokio 0.1 sockets are plain non-blocking io::Read and io::Write.
+
+use backtrace::Backtrace;
+use futures::{Async, Future, Poll};
+use rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use rustls::crypto::{ring, WebPkiSupportedAlgorithms};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{
+    CertificateError, ClientConfig, ClientConnection, DigitallySignedStruct,
+    OtherError, RootCertStore, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+// ? for socket calls in poll functions, where WouldBlock means NotReady
+macro_rules! try_io {
+    ($e:expr) => {
+        match $e {
+            Ok(value) => value,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                return Ok(Async::NotReady)
+            }
+            Err(e) => return Err(e),
+        }
+    };
+}
+
+#[derive(Debug)]
+pub enum TlsErrorType {
+    InvalidServerName,
+    InvalidFingerprint,
+    InvalidCertificate,
+    NoCertificates,
+    FingerprintMismatch,
+    Other,
+}
+
+#[derive(Constructor, Debug)]
+pub struct TlsError {
+    pub error: TlsErrorType,
+    pub message: String,
+    pub trace: Backtrace,
+}
+
+impl std::fmt::Display for TlsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for TlsError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+impl From<TlsError> for std::io::Error {
+    fn from(terr: TlsError) -> Self {
+        std::io::Error::other(terr)
+    }
+}
+
+// How to connect: the name sent as SNI and checked against the certificate,
+// and which certificates to trust. Trusts the system's CAs unless told
+// otherwise.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    server_name: ServerName<'static>,
+    trust: Trust,
+}
+
+#[derive(Clone, Debug)]
+enum Trust {
+    System,
+    Roots(Vec<CertificateDer<'static>>),
+    // Certificates with these SHA-256 fingerprints, whoever signed them
+    Pinned(Vec<[u8; 32]>),
+    Anything,
+}
+
+impl TlsConfig {
+    // An IP address works too, but isn't sent as SNI
+    pub fn new(server_name: &str) -> Result<TlsConfig, TlsError> {
+        let server_name = ServerName::try_from(server_name.to_owned()).map_err(|e| {
+            TlsError::new(
+                TlsErrorType::InvalidServerName,
+                format!("Invalid TLS server name {}: {}", server_name, e),
+                Backtrace::new(),
+            )
+        })?;
+        Ok(TlsConfig { server_name, trust: Trust::System })
+    }
+
+    // Trust the CA certificates in a PEM file instead of the system's
+    pub fn ca_file<P: AsRef<Path>>(mut self, path: P) -> Result<TlsConfig, TlsError> {
+        let path = path.as_ref();
+        let invalid = |e: rustls::pki_types::pem::Error| {
+            TlsError::new(
+                TlsErrorType::InvalidCertificate,
+                format!("Can't read CA file {}: {}", path.display(), e),
+                Backtrace::new(),
+            )
+        };
+        let certs = CertificateDer::pem_file_iter(path)
+            .map_err(invalid)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(invalid)?;
+        if certs.is_empty() {
+            return Err(TlsError::new(
+                TlsErrorType::NoCertificates,
+                format!("No certificates in CA file {}", path.display()),
+                Backtrace::new(),
+            ));
+        }
+        self.trust = Trust::Roots(certs);
+        Ok(self)
+    }
+
+    // Trust certificates by SHA-256 fingerprint instead of CAs, the way
+    // WeeChat's tls_fingerprint option does. Takes hex digits, with or
+    // without colons, and several fingerprints separated by commas.
+    pub fn fingerprint(mut self, fingerprints: &str) -> Result<TlsConfig, TlsError> {
+        let mut pinned = match self.trust {
+            Trust::Pinned(pinned) => pinned,
+            _ => vec![],
+        };
+        for fingerprint in fingerprints.split(',') {
+            pinned.push(parse_fingerprint(fingerprint.trim())?);
+        }
+        self.trust = Trust::Pinned(pinned);
+        Ok(self)
+    }
+
+    // Accept any certificate. Only for self-signed test setups, anyone in
+    // the middle can read everything, password included.
+    pub fn insecure(mut self) -> TlsConfig {
+        self.trust = Trust::Anything;
+        self
+    }
+
+    // Start a handshake on a connected socket
+    pub fn handshake(&self, tcp: TcpStream) -> Result<Handshake, TlsError> {
+        let conn = ClientConnection::new(self.client_config()?, self.server_name.clone())
+            .map_err(|e| {
+                TlsError::new(
+                    TlsErrorType::Other,
+                    format!("Can't start TLS: {}", e),
+                    Backtrace::new(),
+                )
+            })?;
+        Ok(Handshake { stream: Some(TlsStream { tcp, conn }) })
+    }
+
+    fn client_config(&self) -> Result<Arc<ClientConfig>, TlsError> {
+        let provider = Arc::new(ring::default_provider());
+        let algorithms = provider.signature_verification_algorithms;
+        let builder = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(|e| {
+                TlsError::new(TlsErrorType::Other, e.to_string(), Backtrace::new())
+            })?;
+
+        let config = match &self.trust {
+            Trust::System => builder
+                .with_root_certificates(root_store(system_roots()?)?)
+                .with_no_client_auth(),
+            Trust::Roots(roots) => builder
+                .with_root_certificates(root_store(roots.clone())?)
+                .with_no_client_auth(),
+            Trust::Pinned(pinned) => builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+                    pinned: Some(pinned.clone()),
+                    algorithms,
+                }))
+                .with_no_client_auth(),
+            Trust::Anything => builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+                    pinned: None,
+                    algorithms,
+                }))
+                .with_no_client_auth(),
+        };
+        Ok(Arc::new(config))
+    }
+}
+
+// Future for the TLS handshake, returned by TlsConfig::handshake
+pub struct Handshake {
+    stream: Option<TlsStream>,
+}
+
+impl Future for Handshake {
+    type Item = TlsStream;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<TlsStream, io::Error> {
+        {
+            let stream = self.stream.as_mut().expect("Handshake polled after completion");
+            while stream.conn.is_handshaking() {
+                if stream.conn.wants_write() {
+                    try_io!(stream.flush_tls());
+                    continue;
+                }
+                if try_io!(stream.conn.read_tls(&mut stream.tcp)) == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Connection closed during the TLS handshake",
+                    ));
+                }
+                stream.process_packets()?;
+            }
+            try_io!(stream.flush_tls());
+        }
+        Ok(Async::Ready(self.stream.take().unwrap()))
+    }
+}
+
+// A socket after the handshake
+pub struct TlsStream {
+    tcp: TcpStream,
+    conn: ClientConnection,
+}
+
+impl TlsStream {
+    // Send whatever rustls has ready, until the socket would block
+    fn flush_tls(&mut self) -> io::Result<()> {
+        while self.conn.wants_write() {
+            self.conn.write_tls(&mut self.tcp)?;
+        }
+        Ok(())
+    }
+
+    fn process_packets(&mut self) -> io::Result<()> {
+        if let Err(e) = self.conn.process_new_packets() {
+            // Try to tell the relay why before giving up
+            let _ = self.flush_tls();
+            // rustls shows errors from our verifier with Debug, backtrace and all
+            let message = match e {
+                rustls::Error::InvalidCertificate(CertificateError::Other(other)) => {
+                    other.to_string()
+                }
+                e => e.to_string(),
+            };
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.conn.reader().read(buf) {
+                Ok(n) => return Ok(n),
+                // Relays close without a close_notify, frames have their
+                // length so nothing can be cut off unnoticed
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+            if self.conn.read_tls(&mut self.tcp)? == 0 {
+                return Ok(0);
+            }
+            self.process_packets()?;
+            // Answers to what was just read, and writes held back earlier
+            match self.flush_tls() {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => result?,
+            }
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Keep rustls' buffer from growing while the socket can't keep up
+        self.flush_tls()?;
+        let n = self.conn.writer().write(buf)?;
+        match self.flush_tls() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(n),
+            result => result.map(|_| n),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.conn.writer().flush()?;
+        self.flush_tls()?;
+        self.tcp.flush()
+    }
+}
+
+impl AsyncRead for TlsStream {}
+
+impl AsyncWrite for TlsStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.conn.send_close_notify();
+        try_io!(self.flush_tls());
+        AsyncWrite::shutdown(&mut self.tcp)
+    }
+}
+
+// What a relay connection runs over
+pub enum Transport {
+    Plain(TcpStream),
+    Tls(Box<TlsStream>),
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Transport::Plain(tcp) => tcp.read(buf),
+            Transport::Tls(tls) => tls.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Transport::Plain(tcp) => tcp.write(buf),
+            Transport::Tls(tls) => tls.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Transport::Plain(tcp) => tcp.flush(),
+            Transport::Tls(tls) => tls.flush(),
+        }
+    }
+}
+
+impl AsyncRead for Transport {}
+
+impl AsyncWrite for Transport {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self {
+            Transport::Plain(tcp) => AsyncWrite::shutdown(tcp),
+            Transport::Tls(tls) => tls.shutdown(),
+        }
+    }
+}
+
+// Checks certificates by fingerprint only, or not at all without any.
+// Handshake signatures are still checked, so the relay has to hold the key.
+#[derive(Debug)]
+struct PinnedVerifier {
+    pinned: Option<Vec<[u8; 32]>>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let pinned = match &self.pinned {
+            Some(pinned) => pinned,
+            None => return Ok(ServerCertVerified::assertion()),
+        };
+        let fingerprint: [u8; 32] = Sha256::digest(end_entity).into();
+        if pinned.contains(&fingerprint) {
+            return Ok(ServerCertVerified::assertion());
+        }
+        let mismatch = TlsError::new(
+            TlsErrorType::FingerprintMismatch,
+            format!("Certificate SHA-256 fingerprint {} isn't pinned", to_hex(&fingerprint)),
+            Backtrace::new(),
+        );
+        Err(rustls::Error::InvalidCertificate(CertificateError::Other(OtherError(
+            Arc::new(mismatch),
+        ))))
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+//
+// Helper functions
+//
+
+fn system_roots() -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let native = rustls_native_certs::load_native_certs();
+    if native.certs.is_empty() {
+        let errors: Vec<String> = native.errors.iter().map(|e| e.to_string()).collect();
+        return Err(TlsError::new(
+            TlsErrorType::NoCertificates,
+            format!("No system CA certificates found: {}", errors.join(", ")),
+            Backtrace::new(),
+        ));
+    }
+    Ok(native.certs)
+}
+
+fn root_store(certs: Vec<CertificateDer<'static>>) -> Result<RootCertStore, TlsError> {
+    let mut store = RootCertStore::empty();
+    let (_, ignored) = store.add_parsable_certificates(certs);
+    if store.is_empty() {
+        return Err(TlsError::new(
+            TlsErrorType::InvalidCertificate,
+            format!("None of the {} CA certificates are usable", ignored),
+            Backtrace::new(),
+        ));
+    }
+    Ok(store)
+}
+
+fn parse_fingerprint(fingerprint: &str) -> Result<[u8; 32], TlsError> {
+    let invalid = || {
+        TlsError::new(
+            TlsErrorType::InvalidFingerprint,
+            format!("Invalid SHA-256 fingerprint: {}", fingerprint),
+            Backtrace::new(),
+        )
+    };
+    let digits: Vec<u8> = fingerprint.bytes().filter(|b| *b != b':').collect();
+    if digits.len() != 64 || !digits.iter().all(u8::is_ascii_hexdigit) {
+        return Err(invalid());
+    }
+    let mut bytes = [0u8; 32];
+    for (byte, pair) in bytes.iter_mut().zip(digits.chunks(2)) {
+        *byte = (hex_value(pair[0]) << 4) | hex_value(pair[1]);
+    }
+    Ok(bytes)
+}
+
+fn hex_value(digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        _ => (digit | 0x20) - b'a' + 10,
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::tests::{builder, wait, wait_for_event};
+    use crate::server::{ConnectionEvent, DisconnectReason};
+    use dingy_mock::sim::SimRelay;
+    use dingy_mock::tls::TlsFront;
+    use dingy_mock::weechat::Core;
+    use libdingy::command::InfoCommand;
+    use std::fs;
+
+    // TLS in front of a simulated relay, which has to be kept alive too
+    fn front() -> (SimRelay, TlsFront) {
+        let password = Some("secret".to_owned());
+        let relay = SimRelay::start("127.0.0.1:0", Core::new(), password).unwrap();
+        let front = TlsFront::start("127.0.0.1:0", relay.addr()).unwrap();
+        (relay, front)
+    }
+
+    // Log in through the front and get a reply
+    fn connects(front: &TlsFront, tls: TlsConfig) {
+        let server = builder(&front.addr(), "secret").tls(tls).connect();
+        wait_for_event(&server, |event| matches!(event, ConnectionEvent::Authenticated));
+        let (_, msg) = wait(server.send(InfoCommand::new(None, "version".to_owned())));
+        assert!(msg.is_some());
+    }
+
+    #[test]
+    fn pinned_fingerprint_connects() {
+        let (_relay, front) = front();
+        let tls = TlsConfig::new("localhost").unwrap().fingerprint(front.fingerprint()).unwrap();
+        connects(&front, tls);
+    }
+
+    #[test]
+    fn ca_file_connects() {
+        let (_relay, front) = front();
+        let path = std::env::temp_dir().join(format!("dingy-front-{}.pem", front.addr().port()));
+        fs::write(&path, front.cert_pem()).unwrap();
+        let tls = TlsConfig::new("localhost").unwrap().ca_file(&path);
+        fs::remove_file(&path).unwrap();
+        connects(&front, tls.unwrap());
+    }
+
+    #[test]
+    fn insecure_connects() {
+        let (_relay, front) = front();
+        connects(&front, TlsConfig::new("localhost").unwrap().insecure());
+    }
+
+    #[test]
+    fn wrong_fingerprint_fails() {
+        let (_relay, front) = front();
+        let tls = TlsConfig::new("localhost").unwrap().fingerprint(&"00".repeat(32)).unwrap();
+        let server = builder(&front.addr(), "secret").tls(tls).connect();
+        match wait_for_event(&server, |event| matches!(event, ConnectionEvent::Disconnected(_))) {
+            ConnectionEvent::Disconnected(DisconnectReason::TlsFailed(_)) => {}
+            event => panic!("Expected a TLS failure, got {:?}", event),
+        }
+    }
+}